@@ -0,0 +1,18 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use machine::ArmVcpuInit;
+use std::os::unix::io::AsRawFd;
+
+impl Core {
+    /// Initializes the core with the given target and features.  This
+    /// must be done before the core is run, or before any of its
+    /// registers are accessed; it can be done again later to reset the
+    /// core.  Every core of a machine must use the same target.
+    pub fn arm_vcpu_init(&mut self, init: &ArmVcpuInit) -> Result<()> {
+        let init = init.raw();
+        unsafe { kvm::kvm_arm_vcpu_init(self.as_raw_fd(), &init as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_arm_vcpu_init"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,156 @@
+use super::{Core, IoMut, Pause};
+use error::*;
+use kvm_sys as kvm;
+use nix::errno::Errno;
+use std::cmp;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc;
+use std::thread::{self, JoinHandle};
+use tokio::prelude::*;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The response to a pause, given through [`AsyncCore::respond`].  The
+/// core does not run again until a response is given.
+pub enum Response {
+    /// Resumes the core, without modifying the exit information.
+    Resume,
+    /// Resumes the core, providing the data for an MMIO read.  Only the
+    /// first `len` bytes of the data are used by the guest.
+    Mmio([u8; 8]),
+    /// Resumes the core, providing the data for a port IO read.  The
+    /// data is copied into the data area of the exit (see [`IoMut`]),
+    /// as `count` values of `size` bytes each; anything past the end of
+    /// the area is ignored.  If the pause was not for port IO, this is
+    /// the same as [`Response::Resume`].
+    Io(Vec<u8>),
+    /// Stops the core.  The stream ends, and the core can be retrieved
+    /// through [`AsyncCore::into_inner`].
+    Stop,
+}
+
+/// A core that is running on its own thread.  This is a stream of the
+/// pauses of the core; every time the core stops running, the pause is
+/// yielded through the stream.
+///
+/// The core thread then blocks until [`AsyncCore::respond`] is called,
+/// so that any data the guest requested (e.g. for an MMIO read) can be
+/// filled in before the core resumes.  This means that at most one pause
+/// is ever waiting to be handled, and that the guest makes no progress
+/// while its pause is being handled.  The thread parks the core while
+/// its machine is paused, through [`Machine::pause_all`]; a pause that
+/// is waiting for a response keeps the machine from pausing until it
+/// is responded to.
+pub struct AsyncCore {
+    pauses: UnboundedReceiver<Result<Pause>>,
+    responses: mpsc::Sender<Response>,
+    thread: Option<JoinHandle<Core>>,
+}
+
+impl AsyncCore {
+    pub(super) fn new(core: Core) -> AsyncCore {
+        let (pause_tx, pause_rx) = unbounded_channel();
+        let (response_tx, response_rx) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut core = core;
+            drive(&mut core, pause_tx, response_rx);
+            core
+        });
+
+        AsyncCore {
+            pauses: pause_rx,
+            responses: response_tx,
+            thread: Some(thread),
+        }
+    }
+
+    /// Responds to the last pause, allowing the core to run again.
+    pub fn respond(&mut self, response: Response) -> Result<()> {
+        self.responses
+            .send(response)
+            .map_err(|_| ErrorKind::CoreThreadError.into())
+    }
+
+    /// Stops the core, and waits for its thread to finish, returning the
+    /// core.  If the core is currently running, this waits until it
+    /// pauses.
+    pub fn into_inner(mut self) -> Result<Core> {
+        let _ = self.responses.send(Response::Stop);
+        match self.thread.take().map(|thread| thread.join()) {
+            Some(Ok(core)) => Ok(core),
+            _ => Err(ErrorKind::CoreThreadError.into()),
+        }
+    }
+}
+
+impl Stream for AsyncCore {
+    type Item = Pause;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>> {
+        match self
+            .pauses
+            .poll()
+            .chain_err(|| ErrorKind::CoreThreadError)?
+        {
+            Async::Ready(Some(pause)) => pause.map(|pause| Async::Ready(Some(pause))),
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl Core {
+    /// Moves the core onto its own thread, returning a stream of its
+    /// pauses.  See [`AsyncCore`] for more information.
+    pub fn into_async(self) -> AsyncCore {
+        AsyncCore::new(self)
+    }
+}
+
+/// Runs the core until it is told to stop, or until the other end of
+/// either channel is dropped.
+fn drive(
+    core: &mut Core,
+    mut pauses: UnboundedSender<Result<Pause>>,
+    responses: mpsc::Receiver<Response>,
+) {
+    loop {
+        core.park_if_requested();
+        let result = match core.7.enter(core.as_raw_fd()) {
+            Ok(_section) => unsafe { kvm::kvm_run(core.as_raw_fd()) },
+            Err(err) => {
+                let _ = pauses.try_send(Err(err));
+                return;
+            }
+        };
+        let pause = match result {
+            Ok(_) => {
+                let run = unsafe { &*core.1 };
+                Ok(Pause::from(run.exit_reason, &run.exit))
+            }
+            // The machine is being paused, and so the core parks instead
+            // of reporting the interruption.
+            Err(::nix::Error::Sys(Errno::EINTR)) if core.pause_requested() => continue,
+            Err(::nix::Error::Sys(Errno::EINTR)) => Ok(Pause::Interrupted),
+            Err(err) => Err(err).chain_err(|| ErrorKind::CoreApiError("kvm_run")),
+        };
+        let failed = pause.is_err();
+
+        if pauses.try_send(pause).is_err() || failed {
+            return;
+        }
+
+        match responses.recv() {
+            Ok(Response::Resume) => {}
+            Ok(Response::Mmio(data)) => unsafe { (*core.1).exit.mmio.data = data },
+            Ok(Response::Io(data)) => {
+                if let Some(mut io) = IoMut::from(unsafe { &mut *core.1 }, core.2) {
+                    let len = cmp::min(data.len(), io.data_mut().len());
+                    io.data_mut()[..len].copy_from_slice(&data[..len]);
+                }
+            }
+            Ok(Response::Stop) | Err(_) => return,
+        }
+    }
+}
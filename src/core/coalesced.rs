@@ -0,0 +1,112 @@
+use super::{Core, IoAddress};
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+use std::cmp;
+use std::mem::size_of;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+
+const PAGE_SIZE: usize = 4096;
+
+/// The number of entries in the coalesced ring of a core.  The ring is
+/// a single page of the mapping of the core, after its two indices, and
+/// its size cannot be changed.  One entry is always left empty, so that
+/// a full ring can be told apart from an empty one; the ring holds at
+/// most one less than this.
+pub const COALESCED_RING_ENTRIES: usize =
+    (PAGE_SIZE - size_of::<kvm::CoalescedMmioRing>()) / size_of::<kvm::CoalescedMmio>();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// A write by the guest that was queued in the coalesced ring, instead
+/// of causing an exit.  See [`Core::drain_coalesced`].
+pub struct CoalescedWrite {
+    /// The address written to.
+    pub address: IoAddress,
+    len: usize,
+    value: [u8; 8],
+}
+
+impl CoalescedWrite {
+    /// The data written, in the byte order the guest wrote it in.
+    pub fn data(&self) -> &[u8] {
+        &self.value[..self.len]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The writes drained from the coalesced ring of a core, in the order
+/// the guest made them.  See [`Core::drain_coalesced`].
+pub struct CoalescedDrain {
+    /// The writes that were in the ring.
+    pub writes: Vec<CoalescedWrite>,
+    /// Whether or not the ring was full.  The kernel does not drop
+    /// writes once the ring is full; it stops queueing them, and lets
+    /// them cause an ordinary exit instead.  If the exit that was just
+    /// returned is such a write, it came after every write drained
+    /// here, and must be handled after them.
+    pub full: bool,
+}
+
+impl Core {
+    /// Drains the writes queued in the coalesced ring of the core.
+    /// Writes to the ranges registered through
+    /// [`Machine::register_coalesced_pio`] do not cause an exit;
+    /// instead, the kernel appends them to a ring shared with
+    /// userspace, and they are only seen once the ring is drained.
+    /// This advances the read index of the ring past the drained
+    /// writes, so that the kernel can reuse their entries.
+    ///
+    /// The ring must be drained on every exit, before the exit itself
+    /// is handled.  Otherwise, a read of a coalesced device sees its
+    /// state from before the writes that preceded it, and once the
+    /// ring fills up, further writes cause ordinary exits that are
+    /// handled out of order with the ones still queued; see
+    /// [`CoalescedDrain::full`].  As the ring has a fixed size of
+    /// [`COALESCED_RING_ENTRIES`], the only way to size it is to limit
+    /// how much the guest can write between exits: e.g. by coalescing
+    /// only the ports it writes in bursts, and leaving out those it
+    /// polls.
+    ///
+    /// All of the cores of a machine share a single ring, and so this
+    /// must only be called for one of them at a time.  This requires
+    /// the [`Capability::CoalescedMmio`] capability, which reports the
+    /// page of the mapping of the core the ring is in.
+    pub fn drain_coalesced(&mut self) -> Result<CoalescedDrain> {
+        let offset = self.assert_extension(Capability::CoalescedMmio)?.get() as usize * PAGE_SIZE;
+        if offset + PAGE_SIZE > self.2 {
+            return Err(ErrorKind::MapCoreError.into());
+        }
+
+        let ring = unsafe { (self.1 as *mut u8).add(offset) as *mut kvm::CoalescedMmioRing };
+        let mut first = unsafe { ptr::read_volatile(&(*ring).first) } as usize;
+        let last = unsafe { ptr::read_volatile(&(*ring).last) } as usize;
+        // The entries are written by the kernel before it moves the
+        // write index past them.
+        fence(Ordering::Acquire);
+        let full = (last + 1) % COALESCED_RING_ENTRIES == first;
+
+        let entries = unsafe { (*ring).coalesced_mmio.as_ptr() };
+        let mut writes = Vec::new();
+        while first != last {
+            let entry = unsafe { ptr::read_volatile(entries.add(first)) };
+            let address = if entry.pio != 0 {
+                IoAddress::Port(entry.phys_addr as u16)
+            } else {
+                IoAddress::Memory(entry.phys_addr)
+            };
+            writes.push(CoalescedWrite {
+                address,
+                len: cmp::min(entry.len as usize, entry.data.len()),
+                value: entry.data,
+            });
+            first = (first + 1) % COALESCED_RING_ENTRIES;
+        }
+
+        // The entries must be read before the kernel is allowed to
+        // reuse them.
+        fence(Ordering::Release);
+        unsafe { ptr::write_volatile(&mut (*ring).first, first as u32) };
+        Ok(CoalescedDrain { writes, full })
+    }
+}
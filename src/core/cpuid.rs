@@ -0,0 +1,71 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+
+impl Core {
+    /// Sets the CPUID information the guest sees on this core.  This
+    /// replaces the entire table; any leaf that is not given is treated
+    /// as unsupported by the guest.  This is only supported on x86.
+    pub fn set_cpuid(&mut self, entries: &[kvm::CpuidEntry2]) -> Result<()> {
+        // The structure is variable-length, so we allocate enough words
+        // for the header and all of the entries.
+        let size = size_of::<kvm::Cpuid2>() + entries.len() * size_of::<kvm::CpuidEntry2>();
+        let mut buf = vec![0u32; (size + 3) / 4];
+        let cpuid = buf.as_mut_ptr() as *mut kvm::Cpuid2;
+        unsafe {
+            (*cpuid).nent = entries.len() as u32;
+            ptr::copy_nonoverlapping(
+                entries.as_ptr(),
+                (*cpuid).entries.as_mut_ptr(),
+                entries.len(),
+            );
+        }
+
+        unsafe { kvm::kvm_set_cpuid2(self.as_raw_fd(), cpuid) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_cpuid2"))?;
+        Ok(())
+    }
+
+    /// Retrieves the CPUID information the guest sees on this core, as
+    /// last set through [`Core::set_cpuid`].  This is empty if none was
+    /// set.  This is only supported on x86.
+    pub fn cpuid(&self) -> Result<Vec<kvm::CpuidEntry2>> {
+        // As with the supported leaves of the system, the kernel returns
+        // E2BIG if there isn't enough room, so we keep growing the
+        // buffer until it fits.
+        let mut count = 64;
+        loop {
+            let size = size_of::<kvm::Cpuid2>() + count * size_of::<kvm::CpuidEntry2>();
+            let mut buf = vec![0u32; (size + 3) / 4];
+            let cpuid = buf.as_mut_ptr() as *mut kvm::Cpuid2;
+            unsafe { (*cpuid).nent = count as u32 };
+
+            match unsafe { kvm::kvm_get_cpuid2(self.as_raw_fd(), cpuid) } {
+                Ok(_) => unsafe {
+                    let entries = (*cpuid).entries.as_ptr();
+                    return Ok(slice::from_raw_parts(entries, (*cpuid).nent as usize).to_vec());
+                },
+                Err(::nix::Error::Sys(::nix::errno::Errno::E2BIG)) if count < 4096 => {
+                    count *= 2
+                }
+                Err(err) => return Err(err).chain_err(|| ErrorKind::CoreApiError("kvm_get_cpuid2")),
+            }
+        }
+    }
+}
+
+/// Creates a CPUID entry for the given leaf, with a subleaf index of
+/// zero.
+pub fn cpuid_entry(function: u32, eax: u32, ebx: u32, ecx: u32, edx: u32) -> kvm::CpuidEntry2 {
+    let mut entry: kvm::CpuidEntry2 = unsafe { ::std::mem::zeroed() };
+    entry.function = function;
+    entry.eax = eax;
+    entry.ebx = ebx;
+    entry.ecx = ecx;
+    entry.edx = edx;
+    entry
+}
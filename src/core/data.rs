@@ -1,10 +1,31 @@
-use super::{Exit, ExitMut};
+use super::{EoiExit, Exit, ExitMut, Io, IoMut, Pause};
 use kvm_sys as kvm;
+use std::slice;
+
+bitflags! {
+    /// The register sets that can be synchronized through the run
+    /// structure, instead of through separate API calls.  This is only
+    /// supported on x86.
+    pub struct SyncRegsFlag: u64 {
+        /// The general purpose registers.
+        const REGS = kvm::KVM_SYNC_X86_REGS;
+        /// The special registers.
+        const SREGS = kvm::KVM_SYNC_X86_SREGS;
+        /// The vCPU events.
+        const EVENTS = kvm::KVM_SYNC_X86_EVENTS;
+    }
+}
 
 #[derive(Copy, Clone)]
-pub struct Data<'c>(pub(super) &'c kvm::Run);
+pub struct Data<'c>(pub(super) &'c kvm::Run, pub(super) usize);
 
 impl<'c> Data<'c> {
+    /// The entire run structure of the core, for fields that have no
+    /// accessor of their own.
+    pub fn run(&self) -> &'c kvm::Run {
+        self.0
+    }
+
     pub fn exit_reason(&self) -> u32 {
         self.0.exit_reason
     }
@@ -13,13 +34,94 @@ impl<'c> Data<'c> {
         self.0.cr8
     }
 
+    /// The value of the APIC base MSR of the core.  This is only kept
+    /// up to date without an in-kernel irqchip.
+    pub fn apic_base(&self) -> u64 {
+        self.0.apic_base
+    }
+
     pub fn raw_exit(&self) -> &kvm::Exit {
         &self.0.exit
     }
 
+    /// Whether or not the core can accept an interrupt through
+    /// [`Core::interrupt`] right now.  This is only meaningful without
+    /// an in-kernel irqchip.
+    pub fn ready_for_interrupt_injection(&self) -> bool {
+        self.0.ready_for_interrupt_injection != 0
+    }
+
+    /// The value of the interrupt flag (`RFLAGS.IF`) of the core.  This
+    /// is only meaningful without an in-kernel irqchip.
+    pub fn if_flag(&self) -> bool {
+        self.0.if_flag != 0
+    }
+
     pub fn exit(&self) -> Option<Exit<'c>> {
         Exit::from(self.exit_reason(), &self.0.exit)
     }
+
+    /// The port IO exit, along with the data being transferred.  This
+    /// is `None` if the core did not exit for port IO.
+    pub fn io(&self) -> Option<Io<'c>> {
+        Io::from(self.0, self.1)
+    }
+
+    /// The end-of-interrupt exit.  This is `None` if the core did not
+    /// exit for an end-of-interrupt; see [`EoiExit`].
+    pub fn eoi(&self) -> Option<EoiExit> {
+        match self.exit() {
+            Some(Exit::Eoi(eoi)) => Some(eoi.into()),
+            _ => None,
+        }
+    }
+
+    /// The bytes of the instruction the kernel was emulating when the
+    /// core exited.  The kernel only reports these for internal errors
+    /// caused by an emulation failure (`KVM_INTERNAL_ERROR_EMULATION`),
+    /// and only if it sets the instruction bytes flag; for every other
+    /// exit, including MMIO and port IO, this is `None`, and the
+    /// instruction must be decoded from guest memory instead.
+    pub fn insn_bytes(&self) -> Option<&'c [u8]> {
+        if self.0.exit_reason != kvm::KVM_EXIT_INTERNAL_ERROR {
+            return None;
+        }
+
+        let internal = unsafe { &self.0.exit.internal };
+        if internal.suberror != kvm::KVM_INTERNAL_ERROR_EMULATION
+            || internal.ndata < 3
+            || internal.data[0] & kvm::KVM_INTERNAL_ERROR_EMULATION_FLAG_INSTRUCTION_BYTES == 0
+        {
+            return None;
+        }
+
+        // After the flags, the data contains the length of the
+        // instruction as a single byte, followed by up to 15 bytes of
+        // the instruction itself.
+        let bytes = unsafe { slice::from_raw_parts(internal.data[1..3].as_ptr() as *const u8, 16) };
+        let len = (bytes[0] as usize).min(15);
+        Some(&bytes[1..=len])
+    }
+
+    /// The length of the instruction the kernel was emulating when the
+    /// core exited.  See [`Data::insn_bytes`] for when this is
+    /// available.
+    pub fn insn_len(&self) -> Option<usize> {
+        self.insn_bytes().map(|bytes| bytes.len())
+    }
+
+    /// The register sets that the kernel fills into the run structure
+    /// on every exit.  See [`Core::sync_regs`].
+    pub fn valid_regs(&self) -> SyncRegsFlag {
+        SyncRegsFlag::from_bits_truncate(self.0.kvm_valid_regs)
+    }
+
+    /// The registers synchronized through the run structure.  Only the
+    /// register sets in [`Data::valid_regs`] are filled in by the
+    /// kernel; the rest should be ignored.
+    pub fn sync_regs(&self) -> &'c kvm::SyncRegs {
+        unsafe { &self.0.s.regs }
+    }
 }
 
 impl<'c> AsRef<kvm::Run> for Data<'c> {
@@ -28,9 +130,24 @@ impl<'c> AsRef<kvm::Run> for Data<'c> {
     }
 }
 
-pub struct DataMut<'c>(pub(super) &'c mut kvm::Run);
+pub struct DataMut<'c>(pub(super) &'c mut kvm::Run, pub(super) usize);
 
 impl<'c> DataMut<'c> {
+    /// The entire run structure of the core.  See [`Data::run`].
+    pub fn run(&self) -> &kvm::Run {
+        self.0
+    }
+
+    /// The entire run structure of the core, mutably, for fields that
+    /// have no accessor of their own.  The exit reason and the exit
+    /// information must be kept consistent: the accessors of this
+    /// library read the exit information based on the exit reason, and
+    /// the kernel reads the data for MMIO and port IO exits back from
+    /// the exit information on the next run.
+    pub fn run_mut(&mut self) -> &mut kvm::Run {
+        self.0
+    }
+
     pub fn exit_reason(&self) -> u32 {
         self.0.exit_reason
     }
@@ -47,6 +164,20 @@ impl<'c> DataMut<'c> {
         self.0.cr8 = value;
     }
 
+    /// The value of the APIC base MSR of the core.  See
+    /// [`Data::apic_base`].
+    pub fn apic_base(&self) -> u64 {
+        self.0.apic_base
+    }
+
+    /// Sets the APIC base value in the run structure.  Unlike `cr8`,
+    /// the kernel does not load this on the next run; it only reports
+    /// the MSR here on every exit.  To change the MSR itself, it must be
+    /// set through the MSR interface.
+    pub fn set_apic_base(&mut self, value: u64) {
+        self.0.apic_base = value;
+    }
+
     pub fn raw_exit(&self) -> &kvm::Exit {
         &self.0.exit
     }
@@ -55,16 +186,111 @@ impl<'c> DataMut<'c> {
         self.0.exit = exit;
     }
 
+    /// Whether or not the core can accept an interrupt through
+    /// [`Core::interrupt`] right now.  This is only meaningful without
+    /// an in-kernel irqchip.
+    pub fn ready_for_interrupt_injection(&self) -> bool {
+        self.0.ready_for_interrupt_injection != 0
+    }
+
+    /// The value of the interrupt flag (`RFLAGS.IF`) of the core.  This
+    /// is only meaningful without an in-kernel irqchip.
+    pub fn if_flag(&self) -> bool {
+        self.0.if_flag != 0
+    }
+
+    /// Whether or not the core exits as soon as it is able to accept an
+    /// interrupt.
+    pub fn request_interrupt_window(&self) -> bool {
+        self.0.request_interrupt_window != 0
+    }
+
+    /// Sets whether or not the core should exit as soon as it is able
+    /// to accept an interrupt, with [`Pause::IrqWindowOpen`].  This is
+    /// only meaningful without an in-kernel irqchip.
+    pub fn set_request_interrupt_window(&mut self, request: bool) {
+        self.0.request_interrupt_window = request as u8;
+    }
+
     pub fn exit(&'c mut self) -> Option<ExitMut<'c>> {
         ExitMut::from(self.exit_reason(), &mut self.0.exit)
     }
 
+    /// The register sets that the kernel fills into the run structure
+    /// on every exit.  See [`Core::sync_regs`].
+    pub fn valid_regs(&self) -> SyncRegsFlag {
+        SyncRegsFlag::from_bits_truncate(self.0.kvm_valid_regs)
+    }
+
+    /// The registers synchronized through the run structure.  See
+    /// [`Data::sync_regs`].
+    pub fn sync_regs(&self) -> &kvm::SyncRegs {
+        unsafe { &self.0.s.regs }
+    }
+
+    /// The registers synchronized through the run structure, mutably.
+    /// Changes made here are only loaded by the kernel for the register
+    /// sets marked with [`DataMut::set_sync_regs_dirty`].
+    pub fn sync_regs_mut(&mut self) -> &mut kvm::SyncRegs {
+        unsafe { &mut self.0.s.regs }
+    }
+
+    /// Marks the given register sets as modified, so that the kernel
+    /// loads them from the run structure on the next run.  The kernel
+    /// clears these once it has loaded them.
+    pub fn set_sync_regs_dirty(&mut self, dirty: SyncRegsFlag) {
+        self.0.kvm_dirty_regs |= dirty.bits();
+    }
+
+    /// The port IO exit, along with the data being transferred.  This
+    /// is `None` if the core did not exit for port IO.  Data for an `In`
+    /// access should be written here before running the core again.
+    pub fn io_mut(&mut self) -> Option<IoMut> {
+        IoMut::from(self.0, self.1)
+    }
+
     pub fn set_exit<'m>(&mut self, exit: impl Into<Exit<'m>>) {
         let exit = exit.into();
         let (reason, raw) = exit.split();
         self.set_exit_reason(reason);
         self.set_raw_exit(raw);
     }
+
+    /// Writes the given pause into the run structure, as its exit reason
+    /// and exit information.  This is mostly useful for testing exit
+    /// handlers and device models against synthetic exits, without a
+    /// guest.  The kernel overwrites the exit on the next run, so it has
+    /// no effect on the guest itself; in particular, it does not carry
+    /// the data of a port IO access, which lives outside of the exit
+    /// information.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::core::Pause;
+    /// # use kvm::machine::MachineKind;
+    /// # use kvm::system::System;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// # let system = match System::new() {
+    /// #     Ok(system) => system,
+    /// #     Err(_) => return Ok(()),
+    /// # };
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// let mut core = machine.create_core(0)?;
+    /// core.data_mut().set_pause(Pause::Shutdown);
+    ///
+    /// let data = core.data();
+    /// match Pause::from(data.exit_reason(), data.raw_exit()) {
+    ///     Pause::Shutdown => {}
+    ///     _ => panic!("expected a shutdown"),
+    /// }
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn set_pause(&mut self, pause: Pause) {
+        let (reason, raw) = pause.into();
+        self.set_exit_reason(reason);
+        self.set_raw_exit(raw);
+    }
 }
 
 impl<'c> AsRef<kvm::Run> for DataMut<'c> {
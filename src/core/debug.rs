@@ -0,0 +1,310 @@
+use super::{Core, Pause};
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+use std::os::unix::io::AsRawFd;
+
+bitflags! {
+    /// The flags controlling how a core is debugged.  These are set
+    /// through a [`GuestDebugBuilder`]; see
+    /// [`Core::supported_guest_debug_flags`] for the flags the host
+    /// accepts.
+    pub struct GuestDebugFlag: u32 {
+        /// Debugging is enabled.  This is set whenever any other flag
+        /// is.
+        const ENABLE = kvm::KVM_GUESTDBG_ENABLE;
+        /// The core exits after every instruction.
+        const SINGLESTEP = kvm::KVM_GUESTDBG_SINGLESTEP;
+        /// The core exits on software breakpoints.
+        const USE_SW_BP = kvm::KVM_GUESTDBG_USE_SW_BP;
+        /// The core exits on hardware breakpoints.
+        const USE_HW_BP = kvm::KVM_GUESTDBG_USE_HW_BP;
+        /// A `#DB` exception is injected into the guest on the next
+        /// run.
+        const INJECT_DB = kvm::KVM_GUESTDBG_INJECT_DB;
+        /// A `#BP` exception is injected into the guest on the next
+        /// run.
+        const INJECT_BP = kvm::KVM_GUESTDBG_INJECT_BP;
+        /// Interrupts are not delivered while single-stepping, so that
+        /// a step does not land in an interrupt handler.
+        const BLOCKIRQ = kvm::KVM_GUESTDBG_BLOCKIRQ;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+/// The kind of access that triggers a hardware breakpoint.  These are
+/// the `R/W` bits of the breakpoint in `DR7`.
+pub enum BreakpointKind {
+    /// The breakpoint triggers when the instruction at the address is
+    /// executed.  The length must be one.
+    Execute = 0b00,
+    /// The breakpoint triggers when the address is written to.
+    Write = 0b01,
+    /// The breakpoint triggers when the address is read from or written
+    /// to.
+    ReadWrite = 0b11,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+/// The debug registers of an x86 core, as the guest sees them.  See
+/// [`Core::debug_registers`].
+pub struct DebugRegs {
+    /// The breakpoint addresses, `DR0` through `DR3`.
+    pub db: [u64; 4],
+    /// The debug status register.
+    pub dr6: u64,
+    /// The debug control register.
+    pub dr7: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+struct Breakpoint {
+    address: u64,
+    len: u8,
+    kind: BreakpointKind,
+}
+
+#[derive(Debug, Clone, Default)]
+/// A builder for the debug state of a core.  This is passed to
+/// [`Core::set_guest_debug`].  On x86, up to four hardware breakpoints
+/// can be set, through the debug registers of the core.
+///
+/// # Example
+/// ```rust
+/// # use kvm::core::{BreakpointKind, GuestDebugBuilder};
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let mut debug = GuestDebugBuilder::new();
+/// debug.breakpoint(0, 0x1000, 1, BreakpointKind::Execute)?;
+/// assert_eq!(debug.dr7(), 0x601);
+///
+/// debug.breakpoint(1, 0x2000, 4, BreakpointKind::Write)?;
+/// assert_eq!(debug.dr7(), 0xd0_0605);
+///
+/// debug.breakpoint(3, 0x3000, 8, BreakpointKind::ReadWrite)?;
+/// assert_eq!(debug.dr7(), 0xb0d0_0645);
+/// #     Ok(())
+/// # }
+/// ```
+pub struct GuestDebugBuilder {
+    single_step: bool,
+    software_breakpoints: bool,
+    block_irq: bool,
+    breakpoints: [Option<Breakpoint>; 4],
+}
+
+impl GuestDebugBuilder {
+    /// Creates a builder with debugging disabled.
+    pub fn new() -> GuestDebugBuilder {
+        GuestDebugBuilder::default()
+    }
+
+    /// Makes the core exit after every instruction.
+    pub fn single_step(&mut self) -> &mut Self {
+        self.single_step = true;
+        self
+    }
+
+    /// Blocks interrupts while single-stepping, so that each step
+    /// executes an instruction of the guest instead of landing in an
+    /// interrupt handler.  This is not supported by every host; see
+    /// [`Core::supported_guest_debug_flags`].
+    pub fn block_irq(&mut self) -> &mut Self {
+        self.block_irq = true;
+        self
+    }
+
+    /// Makes the core exit on software breakpoints (`INT3` on x86),
+    /// instead of delivering them to the guest.
+    pub fn software_breakpoints(&mut self) -> &mut Self {
+        self.software_breakpoints = true;
+        self
+    }
+
+    /// Sets the hardware breakpoint with the given index, from zero to
+    /// three.  The length must be 1, 2, 4, or 8 bytes, and the address
+    /// must be aligned to the length; execution breakpoints must have a
+    /// length of one.
+    pub fn breakpoint(
+        &mut self,
+        index: usize,
+        address: u64,
+        len: u8,
+        kind: BreakpointKind,
+    ) -> Result<&mut Self> {
+        if index >= self.breakpoints.len() {
+            return Err(ErrorKind::InvalidBreakpointError("index must be at most three").into());
+        }
+
+        match len {
+            1 | 2 | 4 | 8 => {}
+            _ => {
+                return Err(ErrorKind::InvalidBreakpointError("length must be 1, 2, 4, or 8").into())
+            }
+        }
+
+        if kind == BreakpointKind::Execute && len != 1 {
+            return Err(
+                ErrorKind::InvalidBreakpointError("execution breakpoints must be one byte").into(),
+            );
+        }
+
+        if address % len as u64 != 0 {
+            return Err(ErrorKind::InvalidBreakpointError("address must be aligned").into());
+        }
+
+        self.breakpoints[index] = Some(Breakpoint { address, len, kind });
+        Ok(self)
+    }
+
+    /// Removes the hardware breakpoint with the given index.
+    pub fn clear_breakpoint(&mut self, index: usize) -> &mut Self {
+        if let Some(breakpoint) = self.breakpoints.get_mut(index) {
+            *breakpoint = None;
+        }
+        self
+    }
+
+    /// The value of `DR7` for the hardware breakpoints.  Each breakpoint
+    /// sets its local enable bit, and its access kind and length in the
+    /// upper half; bits 9 and 10 are always set, as the processor
+    /// expects.
+    pub fn dr7(&self) -> u64 {
+        let mut dr7 = 0x600;
+        for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+            if let Some(breakpoint) = breakpoint {
+                let len: u64 = match breakpoint.len {
+                    1 => 0b00,
+                    2 => 0b01,
+                    8 => 0b10,
+                    _ => 0b11,
+                };
+                dr7 |= 1 << (index * 2);
+                dr7 |= (breakpoint.kind as u64) << (16 + index * 4);
+                dr7 |= len << (18 + index * 4);
+            }
+        }
+        dr7
+    }
+
+    /// The flags for the debug state.
+    pub fn flags(&self) -> GuestDebugFlag {
+        let mut flags = GuestDebugFlag::empty();
+        if self.single_step {
+            flags |= GuestDebugFlag::SINGLESTEP;
+        }
+        if self.software_breakpoints {
+            flags |= GuestDebugFlag::USE_SW_BP;
+        }
+        if self.block_irq {
+            flags |= GuestDebugFlag::BLOCKIRQ;
+        }
+        if self.breakpoints.iter().any(Option::is_some) {
+            flags |= GuestDebugFlag::USE_HW_BP;
+        }
+        if !flags.is_empty() {
+            flags |= GuestDebugFlag::ENABLE;
+        }
+        flags
+    }
+
+    /// Builds the debug state.
+    pub fn build(&self) -> kvm::GuestDebug {
+        let mut debug: kvm::GuestDebug = unsafe { ::std::mem::zeroed() };
+        let flags = self.flags();
+        debug.control = flags.bits();
+        if flags.contains(GuestDebugFlag::USE_HW_BP) {
+            for (index, breakpoint) in self.breakpoints.iter().enumerate() {
+                if let Some(breakpoint) = breakpoint {
+                    debug.arch.debugreg[index] = breakpoint.address;
+                }
+            }
+            debug.arch.debugreg[7] = self.dr7();
+        }
+        debug
+    }
+}
+
+impl Core {
+    /// Retrieves the debug registers of the core.  These are the
+    /// registers the guest itself uses for hardware breakpoints, and
+    /// must be saved when migrating or snapshotting the guest; this is
+    /// separate from [`Core::set_guest_debug`], which debugs the guest
+    /// from the host.  This requires the [`Capability::DebugRegs`]
+    /// capability.
+    pub fn debug_registers(&self) -> Result<DebugRegs> {
+        self.assert_extension(Capability::DebugRegs)?;
+        let mut regs: kvm::DebugRegs = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_debugregs(self.as_raw_fd(), &mut regs as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_debugregs"))?;
+        Ok(DebugRegs {
+            db: regs.db,
+            dr6: regs.dr6,
+            dr7: regs.dr7,
+        })
+    }
+
+    /// Sets the debug registers of the core.  See
+    /// [`Core::debug_registers`].
+    pub fn set_debug_registers(&mut self, regs: &DebugRegs) -> Result<()> {
+        self.assert_extension(Capability::DebugRegs)?;
+        let mut raw: kvm::DebugRegs = unsafe { ::std::mem::zeroed() };
+        raw.db = regs.db;
+        raw.dr6 = regs.dr6;
+        raw.dr7 = regs.dr7;
+        unsafe { kvm::kvm_set_debugregs(self.as_raw_fd(), &raw as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_debugregs"))?;
+        Ok(())
+    }
+
+    /// The guest debug flags the host accepts.  This requires the
+    /// [`Capability::SetGuestDebug2`] capability, which reports them;
+    /// older hosts only support the flags needed for single-stepping
+    /// and breakpoints, without reporting them.
+    pub fn supported_guest_debug_flags(&self) -> Result<GuestDebugFlag> {
+        self.assert_extension(Capability::SetGuestDebug2)
+            .map(|mask| GuestDebugFlag::from_bits_truncate(mask.get()))
+    }
+
+    /// Sets the debug state of the core.  See [`GuestDebugBuilder`].
+    /// Passing an empty builder disables debugging.  This requires the
+    /// [`Capability::SetGuestDebug`] capability.  If the host reports
+    /// the flags it supports, through [`Capability::SetGuestDebug2`],
+    /// flags outside of those error with
+    /// [`ErrorKind::UnsupportedGuestDebugError`].
+    pub fn set_guest_debug(&mut self, debug: &GuestDebugBuilder) -> Result<()> {
+        self.assert_extension(Capability::SetGuestDebug)?;
+        if self.extension(Capability::SetGuestDebug2)? > 0 {
+            let unsupported = debug.flags() - self.supported_guest_debug_flags()?;
+            if !unsupported.is_empty() {
+                return Err(ErrorKind::UnsupportedGuestDebugError(unsupported.bits()).into());
+            }
+        }
+        let debug = debug.build();
+        unsafe { kvm::kvm_set_guest_debug(self.as_raw_fd(), &debug as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_guest_debug"))?;
+        Ok(())
+    }
+
+    /// Runs the core for a single instruction, returning the reason the
+    /// core stopped.  This enables single-stepping through the guest
+    /// debug interface, runs the core, and then disables it again.  If
+    /// the instruction completed without any other exit, this returns
+    /// [`Pause::Debug`]; otherwise, it returns the exit
+    /// the instruction caused (e.g. a port IO exit).
+    ///
+    /// With an in-kernel irqchip, an interrupt may be injected before
+    /// the instruction executes, in which case the step lands on the
+    /// first instruction of the interrupt handler instead.  This
+    /// requires the [`Capability::SetGuestDebug`] capability.
+    pub fn step_once(&mut self) -> Result<Pause> {
+        self.set_guest_debug(GuestDebugBuilder::new().single_step())?;
+        let result = self.run();
+        // Single-stepping is disabled even if the run failed, so that
+        // the core isn't left stepping.
+        let disabled = self.set_guest_debug(&GuestDebugBuilder::new());
+        let run = result?;
+        disabled?;
+        Ok(Pause::from(run.exit_reason, &run.exit))
+    }
+}
@@ -0,0 +1,32 @@
+use super::exit::ExitEoi;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// An end-of-interrupt from the guest, for a level-triggered interrupt
+/// routed through a userspace IOAPIC.  This only occurs with a split
+/// irqchip (see [`Capability::SplitIrqChip`]), where the local APICs
+/// are in the kernel but the IOAPIC is emulated in userspace.
+///
+/// When this is received, the IOAPIC must clear the remote IRR bit of
+/// every redirection entry with this vector.  If the device behind such
+/// an entry is still asserting its line, the interrupt must then be
+/// delivered again; otherwise, the line should be lowered.
+pub struct EoiExit(u8);
+
+impl EoiExit {
+    /// The vector the guest acknowledged.
+    pub fn vector(&self) -> u8 {
+        self.0
+    }
+}
+
+impl<'c> From<&'c ExitEoi> for EoiExit {
+    fn from(exit: &'c ExitEoi) -> EoiExit {
+        EoiExit(exit.vector)
+    }
+}
+
+impl From<ExitEoi> for EoiExit {
+    fn from(exit: ExitEoi) -> EoiExit {
+        EoiExit(exit.vector)
+    }
+}
@@ -0,0 +1,65 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use std::os::unix::io::AsRawFd;
+
+impl Core {
+    /// Retrieves the pending events of the core; i.e. any exceptions,
+    /// interrupts, or NMIs that are pending or being injected, along
+    /// with the interrupt shadow.  This is only supported on x86.
+    pub fn events(&self) -> Result<kvm::VcpuEvents> {
+        let mut events: kvm::VcpuEvents = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_vcpu_events(self.as_raw_fd(), &mut events as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_vcpu_events"))?;
+        Ok(events)
+    }
+
+    /// Sets the pending events of the core.  The exception, interrupt,
+    /// and NMI injection state is always set; the NMI pending state,
+    /// SIPI vector, interrupt shadow, and SMM state are only set if the
+    /// corresponding `KVM_VCPUEVENT_VALID_*` bit is set in `flags`.
+    pub fn set_events(&mut self, events: &kvm::VcpuEvents) -> Result<()> {
+        unsafe { kvm::kvm_set_vcpu_events(self.as_raw_fd(), events as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_vcpu_events"))?;
+        Ok(())
+    }
+
+    /// Injects an exception into the core, which is delivered the next
+    /// time the core is run.  This is how faults are delivered to the
+    /// guest, e.g. a page fault when emulating demand paging.
+    ///
+    /// If an error code is given, it is pushed by the processor as part
+    /// of delivering the exception; the kernel only uses the error code
+    /// if `has_error_code` is set, which this does.  Only some vectors
+    /// push an error code (e.g. #DF, #TS, #NP, #SS, #GP, #PF, and #AC);
+    /// giving one for any other vector confuses the guest.  For a page
+    /// fault, the faulting address must be put in `CR2` separately.
+    pub fn inject_exception(&mut self, vector: u8, error_code: Option<u32>) -> Result<()> {
+        let mut events = self.events()?;
+        events.exception.injected = 1;
+        events.exception.nr = vector;
+        events.exception.has_error_code = error_code.is_some() as u8;
+        events.exception.error_code = error_code.unwrap_or(0);
+        // Only the exception should change; the optional state is left
+        // as is.
+        events.flags = 0;
+        self.set_events(&events)
+    }
+
+    /// Clears any exceptions, interrupts, and NMIs that are pending or
+    /// being injected into the core.  This should be done when
+    /// resetting the core, so that events from before the reset are not
+    /// delivered after it.
+    pub fn clear_pending_events(&mut self) -> Result<()> {
+        let mut events = self.events()?;
+        events.exception.injected = 0;
+        events.exception.pending = 0;
+        events.exception.has_error_code = 0;
+        events.exception.error_code = 0;
+        events.interrupt.injected = 0;
+        events.nmi.injected = 0;
+        events.nmi.pending = 0;
+        events.flags = kvm::KVM_VCPUEVENT_VALID_NMI_PENDING;
+        self.set_events(&events)
+    }
+}
@@ -0,0 +1,27 @@
+use super::kick::RunMap;
+use std::sync::Arc;
+
+/// A guard that kicks the vCPU out of its run loop when dropped.  This
+/// is created through [`Core::run_guard`], and is meant to be held by
+/// a supervising thread; if that thread returns early or unwinds, the
+/// guard sets `immediate_exit` in the shared run structure, so that
+/// the vCPU thread does not re-enter the guest, and signals the vCPU
+/// thread with [`KICK_SIGNAL`] if it is running the core, so that a
+/// vCPU already inside the guest leaves it right away.
+#[derive(Debug)]
+pub struct RunGuard(pub(super) Option<Arc<RunMap>>);
+
+impl RunGuard {
+    /// Consumes the guard without requesting an exit.
+    pub fn disarm(mut self) {
+        self.0.take();
+    }
+}
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        if let Some(ref map) = self.0 {
+            map.kick();
+        }
+    }
+}
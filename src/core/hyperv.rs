@@ -0,0 +1,97 @@
+//! Hyper-V enlightenments.  These are paravirtualized interfaces that
+//! Windows guests use when they detect that they are running under
+//! Hyper-V, and that greatly improve their performance.
+//!
+//! Windows detects the hypervisor through CPUID: leaf 1 must have the
+//! hypervisor bit (bit 31 of ECX) set, leaf `0x40000000` must report the
+//! vendor `"Microsoft Hv"`, and leaf `0x40000001` must report the
+//! interface `"Hv#1"`.  The leaves returned by [`Core::hyperv_cpuid`]
+//! cover `0x40000000` through `0x4000000A`; they must be merged into the
+//! rest of the CPUID table before it's given to [`Core::set_cpuid`].
+
+use super::cpuid::cpuid_entry;
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+
+/// The last Hyper-V CPUID leaf.
+const HYPERV_MAX_LEAF: u32 = 0x4000_000a;
+
+bitflags! {
+    /// The Hyper-V enlightenments to expose to the guest.  These are
+    /// the privileges reported in CPUID leaf `0x40000003`, EAX.
+    pub struct HypervFeature: u32 {
+        /// The virtual processor run time MSR.
+        const VP_RUNTIME = 1 << 0;
+        /// The partition reference counter MSR.
+        const TIME_REF_COUNT = 1 << 1;
+        /// The synthetic interrupt controller.  Requires
+        /// [`Capability::HypervSynic`].
+        const SYNIC = 1 << 2;
+        /// The synthetic timers.  Requires the synthetic interrupt
+        /// controller.
+        const STIMER = 1 << 3;
+        /// The APIC access MSRs.
+        const APIC = 1 << 4;
+        /// The hypercall MSRs.
+        const HYPERCALL = 1 << 5;
+        /// The virtual processor index MSR.  Requires
+        /// [`Capability::HypervVpIndex`].
+        const VP_INDEX = 1 << 6;
+        /// The partition reference TSC page.  Requires
+        /// [`Capability::HypervTime`].
+        const REFERENCE_TSC = 1 << 9;
+        /// The TSC and APIC frequency MSRs.
+        const FREQUENCY = 1 << 11;
+    }
+}
+
+impl Core {
+    /// Enables the Hyper-V synthetic interrupt controller on this core.
+    /// This must be done on every core, and requires the
+    /// [`Capability::HypervSynic`] capability.
+    pub fn enable_hyperv_synic(&mut self) -> Result<()> {
+        self.assert_extension(Capability::HypervSynic)?;
+        self.enable_capability(Capability::HypervSynic, 0, [0; 4])
+    }
+
+    /// Creates the Hyper-V CPUID leaves for the given enlightenments, for
+    /// a machine with the given number of cores.  This errors if any of
+    /// the enlightenments are not supported by the host.  See the module
+    /// documentation for how these should be used.
+    pub fn hyperv_cpuid(
+        &self,
+        features: HypervFeature,
+        cores: u32,
+    ) -> Result<Vec<kvm::CpuidEntry2>> {
+        self.assert_extension(Capability::Hyperv)?;
+        if features.contains(HypervFeature::SYNIC) {
+            self.assert_extension(Capability::HypervSynic)?;
+        }
+        if features.contains(HypervFeature::VP_INDEX) {
+            self.assert_extension(Capability::HypervVpIndex)?;
+        }
+        if features.contains(HypervFeature::REFERENCE_TSC) {
+            self.assert_extension(Capability::HypervTime)?;
+        }
+
+        let mut entries = vec![
+            // "Microsoft Hv"
+            cpuid_entry(0x4000_0000, HYPERV_MAX_LEAF, 0x7263_694d, 0x666f_736f, 0x7648_2074),
+            // "Hv#1"
+            cpuid_entry(0x4000_0001, 0x3123_7648, 0, 0, 0),
+            cpuid_entry(0x4000_0002, 0, 0, 0, 0),
+            cpuid_entry(0x4000_0003, features.bits(), 0, 0, 0),
+            // No recommendations, and never notify on spinlock retries.
+            cpuid_entry(0x4000_0004, 0, 0xffff_ffff, 0, 0),
+            cpuid_entry(0x4000_0005, cores, cores, 0, 0),
+        ];
+
+        for leaf in 0x4000_0006..=HYPERV_MAX_LEAF {
+            entries.push(cpuid_entry(leaf, 0, 0, 0, 0));
+        }
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,151 @@
+use super::exit::ExitIo;
+use kvm_sys as kvm;
+use std::slice;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u8)]
+/// The direction of a port IO access, from the perspective of the
+/// guest.
+pub enum Direction {
+    /// The guest is reading from the port; the data must be filled in
+    /// before the core is run again.
+    In = kvm::KVM_EXIT_IO_IN,
+    /// The guest is writing to the port.
+    Out = kvm::KVM_EXIT_IO_OUT,
+}
+
+/// A port IO exit, along with the data being transferred.  The data is
+/// stored in the mapping of the core, after the run structure itself;
+/// it is a packed array of `count` values, each `size` bytes long.
+#[derive(Copy, Clone)]
+pub struct Io<'c> {
+    exit: &'c ExitIo,
+    data: &'c [u8],
+}
+
+impl<'c> Io<'c> {
+    pub(super) fn from(run: &'c kvm::Run, size: usize) -> Option<Io<'c>> {
+        if run.exit_reason != kvm::KVM_EXIT_IO {
+            return None;
+        }
+
+        let exit = unsafe { &run.exit.io };
+        data_range(exit, size).map(|(offset, len)| Io {
+            exit,
+            data: unsafe {
+                slice::from_raw_parts((run as *const kvm::Run as *const u8).add(offset), len)
+            },
+        })
+    }
+
+    /// The raw exit information.
+    pub fn exit(&self) -> &'c ExitIo {
+        self.exit
+    }
+
+    /// The direction of the access.
+    pub fn direction(&self) -> Direction {
+        direction(self.exit)
+    }
+
+    /// The port being accessed.
+    pub fn port(&self) -> u16 {
+        self.exit.port
+    }
+
+    /// The size of each value being transferred; one, two, or four
+    /// bytes.
+    pub fn size(&self) -> u8 {
+        self.exit.size
+    }
+
+    /// The number of values being transferred.  This is greater than
+    /// one for string instructions (e.g. `rep outsb`).
+    pub fn count(&self) -> u32 {
+        self.exit.count
+    }
+
+    /// The data being transferred.  For an `Out` access, this is the
+    /// data the guest wrote.
+    pub fn data(&self) -> &'c [u8] {
+        self.data
+    }
+}
+
+/// A port IO exit, along with the data being transferred, mutably.  See
+/// [`Io`].
+pub struct IoMut<'c> {
+    exit: ExitIo,
+    data: &'c mut [u8],
+}
+
+impl<'c> IoMut<'c> {
+    pub(super) fn from(run: &'c mut kvm::Run, size: usize) -> Option<IoMut<'c>> {
+        if run.exit_reason != kvm::KVM_EXIT_IO {
+            return None;
+        }
+
+        let exit = unsafe { run.exit.io };
+        data_range(&exit, size).map(move |(offset, len)| IoMut {
+            exit,
+            data: unsafe {
+                slice::from_raw_parts_mut((run as *mut kvm::Run as *mut u8).add(offset), len)
+            },
+        })
+    }
+
+    /// The raw exit information.
+    pub fn exit(&self) -> &ExitIo {
+        &self.exit
+    }
+
+    /// The direction of the access.
+    pub fn direction(&self) -> Direction {
+        direction(&self.exit)
+    }
+
+    /// The port being accessed.
+    pub fn port(&self) -> u16 {
+        self.exit.port
+    }
+
+    /// The size of each value being transferred.
+    pub fn size(&self) -> u8 {
+        self.exit.size
+    }
+
+    /// The number of values being transferred.
+    pub fn count(&self) -> u32 {
+        self.exit.count
+    }
+
+    /// The data being transferred.
+    pub fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    /// The data being transferred, mutably.  For an `In` access, this
+    /// must be filled in with the data the guest is reading.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data
+    }
+}
+
+fn direction(exit: &ExitIo) -> Direction {
+    if exit.direction == kvm::KVM_EXIT_IO_IN {
+        Direction::In
+    } else {
+        Direction::Out
+    }
+}
+
+/// The offset and length of the data for the exit, if it fits within the
+/// mapping of the core.
+fn data_range(exit: &ExitIo, size: usize) -> Option<(usize, usize)> {
+    let offset = exit.data_offset as usize;
+    let len = exit.size as usize * exit.count as usize;
+    offset
+        .checked_add(len)
+        .filter(|end| *end <= size)
+        .map(|_| (offset, len))
+}
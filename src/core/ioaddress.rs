@@ -0,0 +1,37 @@
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+/// An address that the guest can access through an IO exit; either a
+/// port, for port IO, or a guest physical address, for MMIO.  All ports
+/// are ordered before all memory addresses.
+pub enum IoAddress {
+    Port(u16),
+    Memory(u64),
+}
+
+impl IoAddress {
+    /// The numeric value of the address, regardless of its space.
+    pub fn value(self) -> u64 {
+        match self {
+            IoAddress::Port(port) => port as u64,
+            IoAddress::Memory(address) => address,
+        }
+    }
+
+    /// Whether or not this is a port address.
+    pub fn is_port(self) -> bool {
+        match self {
+            IoAddress::Port(_) => true,
+            IoAddress::Memory(_) => false,
+        }
+    }
+
+    /// The offset of this address from the given base address.  This is
+    /// `None` if the addresses are in different spaces, or if this
+    /// address is below the base.
+    pub fn offset_from(self, base: IoAddress) -> Option<u64> {
+        if self.is_port() != base.is_port() {
+            return None;
+        }
+
+        self.value().checked_sub(base.value())
+    }
+}
@@ -0,0 +1,191 @@
+use error::*;
+use kvm_sys as kvm;
+use nix::libc;
+use nix::sys::signal::{self, SaFlags, SigAction, SigHandler, SigSet, Signal};
+use std::cell::Cell;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard, Once, PoisonError};
+
+/// The signal sent to the thread running a core, to kick the core out
+/// of the guest; e.g. by a [`RunGuard`], by [`Core::run_timeout`], or
+/// by [`Machine::pause_all`].
+///
+/// A thread blocks this signal the first time it runs a core, and the
+/// kernel only unblocks it while the thread is in the guest, through
+/// the signal mask of the core (`KVM_SET_SIGNAL_MASK`).  The signal
+/// thus never reaches a handler of the application on that thread:
+/// one that arrives while the core is in the guest makes it leave,
+/// one that arrives just before makes it leave as soon as it enters,
+/// and one that arrives after it left is discarded.  The thread must
+/// not unblock the signal itself.
+///
+/// If the process has no handler for the signal, a handler that does
+/// nothing is installed, so that a stray kick does not terminate the
+/// process; a handler the application installed is left in place.
+pub const KICK_SIGNAL: Signal = Signal::SIGUSR2;
+
+thread_local! {
+    /// Whether or not the current thread blocks [`KICK_SIGNAL`].
+    static BLOCKED: Cell<bool> = Cell::new(false);
+}
+
+/// The mapping of the run structure of a core.  This is shared with
+/// everything that may kick the core out of the guest from another
+/// thread, so that the mapping outlives all of them, and records the
+/// thread running the core, so that only a thread that is running the
+/// core is ever signaled.
+#[derive(Debug)]
+pub(crate) struct RunMap {
+    run: *mut kvm::Run,
+    size: usize,
+    thread: Mutex<Option<libc::pthread_t>>,
+    kicked: AtomicBool,
+    masked: AtomicBool,
+}
+
+// The run structure is only written to from other threads through
+// `immediate_exit`, which the kernel expects to be written to at any
+// time.
+unsafe impl Send for RunMap {}
+unsafe impl Sync for RunMap {}
+
+impl RunMap {
+    pub(super) fn new(run: *mut kvm::Run, size: usize) -> RunMap {
+        RunMap {
+            run,
+            size,
+            thread: Mutex::new(None),
+            kicked: AtomicBool::new(false),
+            masked: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<Option<libc::pthread_t>> {
+        self.thread.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Sets or clears `immediate_exit` in the run structure.
+    pub(super) fn set_immediate_exit(&self, value: u8) {
+        unsafe { ptr::write_volatile(&mut (*self.run).immediate_exit, value) };
+    }
+
+    /// Kicks the core out of the guest.  This sets `immediate_exit`,
+    /// so that the core does not enter the guest again, and signals
+    /// the thread running the core, if there is one, so that a core in
+    /// the guest leaves it.
+    pub(super) fn kick(&self) {
+        let thread = self.lock();
+        self.set_immediate_exit(1);
+        if let Some(thread) = *thread {
+            self.kicked.store(true, Ordering::SeqCst);
+            unsafe { libc::pthread_kill(thread, KICK_SIGNAL as libc::c_int) };
+        }
+    }
+
+    /// Records the calling thread as the thread running the core, until
+    /// the returned section is dropped.  The core must only enter the
+    /// guest within the section.
+    pub(super) fn enter(&self, fd: RawFd) -> Result<RunSection> {
+        install_kick_handler();
+        if !BLOCKED.with(Cell::get) {
+            let mut set = SigSet::empty();
+            set.add(KICK_SIGNAL);
+            set.thread_block()
+                .chain_err(|| ErrorKind::CoreApiError("pthread_sigmask"))?;
+            BLOCKED.with(|blocked| blocked.set(true));
+        }
+
+        if !self.masked.load(Ordering::SeqCst) {
+            set_signal_mask(fd)?;
+            self.masked.store(true, Ordering::SeqCst);
+        }
+
+        *self.lock() = Some(unsafe { libc::pthread_self() });
+        Ok(RunSection(self))
+    }
+}
+
+impl Drop for RunMap {
+    fn drop(&mut self) {
+        use nix::libc::c_void;
+        use nix::sys::mman::munmap;
+
+        let _ = unsafe { munmap(self.run as *mut c_void, self.size) };
+    }
+}
+
+/// The time a thread spends running a core.  See [`RunMap::enter`].
+pub(super) struct RunSection<'m>(&'m RunMap);
+
+impl<'m> Drop for RunSection<'m> {
+    fn drop(&mut self) {
+        *self.0.lock() = None;
+        // A kick that arrived after the core left the guest is still
+        // pending, and would make the next run leave right away.  The
+        // exit request made with it still covers that run.
+        if self.0.kicked.swap(false, Ordering::SeqCst) {
+            discard_kick();
+        }
+    }
+}
+
+/// Discards a pending [`KICK_SIGNAL`] of the calling thread, if there
+/// is one.
+pub(super) fn discard_kick() {
+    unsafe {
+        let mut set: libc::sigset_t = ::std::mem::zeroed();
+        libc::sigemptyset(&mut set);
+        libc::sigaddset(&mut set, KICK_SIGNAL as libc::c_int);
+        let timeout = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        libc::sigtimedwait(&set, ptr::null_mut(), &timeout);
+    }
+}
+
+/// Sets the signal mask the core runs the guest with: the mask of the
+/// calling thread, without [`KICK_SIGNAL`].
+fn set_signal_mask(fd: RawFd) -> Result<()> {
+    let mut current: libc::sigset_t = unsafe { ::std::mem::zeroed() };
+    unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut current) };
+    // The kernel expects its own 64-bit signal set, where bit `n - 1`
+    // is signal `n`.
+    let mut bits = 0u64;
+    for signal in 1..65 {
+        if unsafe { libc::sigismember(&current, signal) } == 1 {
+            bits |= 1 << (signal - 1);
+        }
+    }
+    bits &= !(1 << (KICK_SIGNAL as u64 - 1));
+
+    let buf: [u32; 3] = [8, bits as u32, (bits >> 32) as u32];
+    unsafe { kvm::kvm_set_signal_mask(fd, buf.as_ptr() as *const kvm::SignalMask) }
+        .chain_err(|| ErrorKind::CoreApiError("kvm_set_signal_mask"))?;
+    Ok(())
+}
+
+/// Installs a handler that does nothing for [`KICK_SIGNAL`], unless the
+/// process already handles or ignores the signal.  This is only done
+/// once; the handler is a safety net for a kick that reaches a thread
+/// that does not block the signal, which would otherwise terminate the
+/// process.
+fn install_kick_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        let mut current: libc::sigaction = ::std::mem::zeroed();
+        let queried = libc::sigaction(KICK_SIGNAL as libc::c_int, ptr::null(), &mut current);
+        if queried == 0 && current.sa_sigaction == libc::SIG_DFL {
+            let action = SigAction::new(
+                SigHandler::Handler(handle_kick),
+                SaFlags::empty(),
+                SigSet::empty(),
+            );
+            let _ = signal::sigaction(KICK_SIGNAL, &action);
+        }
+    });
+}
+
+extern "C" fn handle_kick(_: libc::c_int) {}
@@ -1,24 +1,204 @@
 use super::error::*;
+use super::machine::{Backings, Capability, CapabilityScope};
 use kvm_sys as kvm;
+use nix::sys::mman::MapFlags;
+use std::cell::Cell;
+use std::collections::VecDeque;
 use std::fs::File;
+use std::num::NonZeroU32;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
+use std::sync::Arc;
 
+#[cfg(target_arch = "aarch64")]
+mod arm;
+mod asynchronous;
+mod coalesced;
+mod cpuid;
 mod data;
+mod debug;
+mod eoi;
+mod events;
 mod exit;
+mod guard;
+pub mod hyperv;
+mod io;
+mod ioaddress;
+mod kick;
+mod msr;
+mod nested;
+mod one_reg;
+mod park;
+mod pause;
+#[cfg(target_arch = "s390x")]
+mod s390;
+mod snapshot;
 mod state;
+mod timeout;
+mod translation;
+mod tsc;
+pub mod wire;
 
-pub use self::data::{Data, DataMut};
+pub use self::asynchronous::{AsyncCore, Response};
+pub use self::coalesced::{CoalescedDrain, CoalescedWrite, COALESCED_RING_ENTRIES};
+pub use self::cpuid::cpuid_entry;
+pub use self::data::{Data, DataMut, SyncRegsFlag};
+pub use self::debug::{BreakpointKind, DebugRegs, GuestDebugBuilder, GuestDebugFlag};
+pub use self::eoi::EoiExit;
 pub use self::exit::{Exit, ExitMut};
+pub use self::guard::RunGuard;
+pub use self::io::{Direction, Io, IoMut};
+pub use self::ioaddress::IoAddress;
+pub use self::kick::KICK_SIGNAL;
+pub use self::msr::MSR_IA32_TSC_DEADLINE;
+pub use self::nested::{NestedFormat, NestedState};
+pub use self::one_reg::{RegisterId, ONE_REG_ARM64, ONE_REG_ARM_CORE, ONE_REG_SIZE_U64};
+pub use self::pause::Pause;
+pub use self::snapshot::{CoreSnapshot, SNAPSHOT_MSRS};
 pub use self::state::State;
+pub use self::translation::Translation;
+pub use self::wire::WireFormat;
+
+pub(crate) use self::park::ParkState;
+
+use self::kick::RunMap;
+use self::tsc::TscLimits;
 
 #[derive(Debug)]
-pub struct Core(pub(crate) File, *mut kvm::Run);
+/// A virtual CPU of a machine.  This is created through
+/// [`Machine::create_core`].
+///
+/// # Safety
+/// The core can be moved to another thread, e.g. to give each core a
+/// thread of its own, but it is not `Sync`: the run structure it maps is
+/// shared with the kernel, and is read and written without any
+/// synchronization.  The only operations that are meant to be done from
+/// another thread are exit requests, through a [`RunGuard`].
+///
+/// # Example
+/// The run structure is unmapped once the core (and every guard of it)
+/// is dropped, so creating and dropping cores does not leak mappings.
+///
+/// ```rust
+/// # use kvm::machine::MachineKind;
+/// # use kvm::system::System;
+/// # use std::fs;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let system = match System::new() {
+///     Ok(system) => system,
+///     Err(_) => return Ok(()),
+/// };
+///
+/// let mappings = || -> Result<usize, ::std::io::Error> {
+///     let maps = fs::read_to_string("/proc/self/maps")?;
+///     Ok(maps.lines().filter(|line| line.contains("kvm-vcpu")).count())
+/// };
+///
+/// let before = mappings()?;
+/// for _ in 0..16 {
+///     let machine = system.create_machine(MachineKind::Default)?;
+///     for id in 0..16 {
+///         let core = machine.create_core(id)?;
+///         drop(core);
+///     }
+/// }
+/// assert_eq!(mappings()?, before);
+/// #     Ok(())
+/// # }
+/// ```
+pub struct Core(
+    pub(crate) File,
+    *mut kvm::Run,
+    usize,
+    Option<File>,
+    Cell<Option<TscLimits>>,
+    bool,
+    Option<(Arc<ParkState>, usize)>,
+    Arc<RunMap>,
+    Option<Backings>,
+    Option<u32>,
+);
 
 impl Core {
-    pub(super) fn new(fd: RawFd) -> Result<Core> {
+    /// Adopts the core with the given file descriptor, with the size of
+    /// the mapping of its run structure, as reported by
+    /// [`System::core_mmap_size`].  The core is not known to belong to
+    /// any machine, and so it cannot check for capabilities.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open core, and is owned by the core
+    /// from then on, even if this fails.
+    pub unsafe fn from_raw_fd_with_size(fd: RawFd, size: usize) -> Result<Core> {
+        Core::build(fd, size, None, false)
+    }
+
+    /// Creates the core, keeping a handle to the machine it was created
+    /// from.  This handle is used to check for capabilities, as the
+    /// vCPU file descriptor itself does not support that.  The size is
+    /// the size of the mapping of the run structure, which includes the
+    /// data pages that follow the structure itself.  The irqchip flag is
+    /// whether the machine is known to have an in-kernel irqchip, and
+    /// the parking state is that of the machine; the core registers
+    /// itself with it.  The core also keeps the mappings owned by the
+    /// regions of the machine, as its handle keeps the machine open.
+    pub(crate) fn with_machine(
+        fd: RawFd,
+        machine: File,
+        size: usize,
+        irqchip: bool,
+        parking: &Arc<ParkState>,
+        backings: &Backings,
+    ) -> Result<Core> {
+        let mut core = Core::build(fd, size, Some(machine), irqchip)?;
+        core.8 = Some(backings.clone());
+        let key = parking.register(&core.7);
+        core.6 = Some((parking.clone(), key));
+        Ok(core)
+    }
+
+    fn build(fd: RawFd, size: usize, machine: Option<File>, irqchip: bool) -> Result<Core> {
         let file = unsafe { File::from_raw_fd(fd) };
-        let map = map_fd(fd)?;
-        Ok(Core(file, map))
+        let map = map_fd(fd, size)?;
+        let shared = Arc::new(RunMap::new(map, size));
+        Ok(Core(
+            file,
+            map,
+            size,
+            machine,
+            Cell::new(None),
+            irqchip,
+            None,
+            shared,
+            None,
+            None,
+        ))
+    }
+
+    /// Returns information about a specified extension/capability of
+    /// the machine this core belongs to.  See [`Machine::extension`]
+    /// for more information.  This fails if the core was not created
+    /// through [`Machine::create_core`].
+    pub fn extension(&self, cap: Capability) -> Result<i32> {
+        match self.3 {
+            Some(ref machine) => {
+                unsafe { kvm::kvm_check_extension(machine.as_raw_fd(), cap as i32) }
+                    .chain_err(|| ErrorKind::CoreApiError("kvm_check_extension"))
+            }
+            None => Err(ErrorKind::CoreApiError("kvm_check_extension").into()),
+        }
+    }
+
+    /// Requires the existance of an extension on the machine this core
+    /// belongs to.  See [`Machine::assert_extension`] for more
+    /// information.
+    pub fn assert_extension(&self, cap: Capability) -> Result<NonZeroU32> {
+        self.extension(cap)
+            .map(|v| NonZeroU32::new(v as u32))
+            .and_then(|value| {
+                value
+                    .map(Ok)
+                    .unwrap_or_else(|| Err(ErrorKind::MissingExtensionError(cap).into()))
+            })
     }
 
     /// Returns the current state of the core.  See [`State`] for more
@@ -45,23 +225,366 @@ impl Core {
         Ok(())
     }
 
+    /// Marks the core as the bootstrap processor, i.e. the core that
+    /// starts running at reset.  This sets the state to
+    /// [`State::Runnable`].
+    pub fn make_bsp(&self) -> Result<()> {
+        self.set_state(State::Runnable)
+    }
+
+    /// Marks the core as an application processor, i.e. a core that
+    /// waits for the bootstrap processor to start it.  This sets the
+    /// state to [`State::Uninitialized`], and is only supported on x86.
+    ///
+    /// The guest then starts the core by sending it an INIT IPI,
+    /// followed by a startup IPI (SIPI) whose vector gives the page the
+    /// core starts executing at, in real mode.  With an in-kernel APIC
+    /// (see [`Machine::create_irqchip`]), the kernel handles the whole
+    /// sequence, and the core starts running once the SIPI arrives; the
+    /// core must still be run as usual before then, as it waits inside
+    /// [`Core::run`].  With a userspace APIC, the VMM must emulate the
+    /// IPIs itself: on INIT, it sets the state to
+    /// [`State::InitReceived`]; on SIPI, it sets `CS` to the vector
+    /// shifted left by 8 (with a base of the vector shifted left by
+    /// 12), sets `IP` to zero, and marks the core [`State::Runnable`].
+    pub fn make_ap(&self) -> Result<()> {
+        self.set_state(State::Uninitialized)
+    }
+
+    /// Enables a capability on the core.  The flags and arguments are
+    /// dependent on the capability being enabled.  This errors if the
+    /// capability cannot be enabled on a core (see
+    /// [`Capability::scope`]); capabilities that affect the entire
+    /// machine must be enabled through [`Machine::enable_capability`]
+    /// instead.
+    pub fn enable_capability(
+        &mut self,
+        cap: Capability,
+        flags: u32,
+        args: [u64; 4],
+    ) -> Result<()> {
+        if cap.scope() != Some(CapabilityScope::Core) {
+            return Err(ErrorKind::CapabilityScopeError(cap, CapabilityScope::Core).into());
+        }
+
+        let enable = kvm::EnableCap {
+            cap: cap as u32,
+            flags,
+            args,
+            _pad: [0; 64],
+        };
+        unsafe { kvm::kvm_enable_cap(self.as_raw_fd(), &enable as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_enable_cap"))?;
+        Ok(())
+    }
+
+    /// Retrieves the general purpose registers of the core.  This is
+    /// only supported on x86.
+    pub fn regs(&self) -> Result<kvm::Regs> {
+        let mut regs: kvm::Regs = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_regs(self.as_raw_fd(), &mut regs as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_regs"))?;
+        Ok(regs)
+    }
+
+    /// The instruction pointer of the core.  If the general purpose
+    /// registers are synchronized through the run structure (see
+    /// [`Core::sync_regs`] and [`SyncRegsFlag::REGS`]), this is read
+    /// from there, which is only a memory read.  Otherwise, this falls
+    /// back to [`Core::regs`], which retrieves every general purpose
+    /// register through an API call, just to read one of them.
+    ///
+    /// The synchronized registers are those of the last exit, along with
+    /// any changes made through [`DataMut::sync_regs_mut`]; they do not
+    /// reflect [`Core::set_regs`] until the core runs again, and are
+    /// empty before the first run.  This is only supported on x86.
+    pub fn rip(&self) -> Result<u64> {
+        let data = self.data();
+        if data.valid_regs().contains(SyncRegsFlag::REGS) {
+            return Ok(data.sync_regs().regs.rip);
+        }
+        self.regs().map(|regs| regs.rip)
+    }
+
+    /// Sets the general purpose registers of the core.  This is only
+    /// supported on x86.
+    pub fn set_regs(&mut self, regs: &kvm::Regs) -> Result<()> {
+        unsafe { kvm::kvm_set_regs(self.as_raw_fd(), regs as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_regs"))?;
+        Ok(())
+    }
+
+    /// Retrieves the special registers of the core; i.e. the segment
+    /// registers, descriptor tables, and control registers.  This is
+    /// only supported on x86.
+    pub fn sregs(&self) -> Result<kvm::Sregs> {
+        let mut sregs: kvm::Sregs = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_sregs(self.as_raw_fd(), &mut sregs as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_sregs"))?;
+        Ok(sregs)
+    }
+
+    /// Sets the special registers of the core.  This is only supported
+    /// on x86.
+    pub fn set_sregs(&mut self, sregs: &kvm::Sregs) -> Result<()> {
+        unsafe { kvm::kvm_set_sregs(self.as_raw_fd(), sregs as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_sregs"))?;
+        Ok(())
+    }
+
+    /// Retrieves the floating point state of the core.  This is only
+    /// supported on x86.
+    pub fn fpu(&self) -> Result<kvm::Fpu> {
+        let mut fpu: kvm::Fpu = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_fpu(self.as_raw_fd(), &mut fpu as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_fpu"))?;
+        Ok(fpu)
+    }
+
+    /// Sets the floating point state of the core.  This is only
+    /// supported on x86.
+    pub fn set_fpu(&mut self, fpu: &kvm::Fpu) -> Result<()> {
+        unsafe { kvm::kvm_set_fpu(self.as_raw_fd(), fpu as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_fpu"))?;
+        Ok(())
+    }
+
+    /// Resets the floating point state of the core to the state the
+    /// processor has at power-on.  See [`Core::power_on_fpu`] for the
+    /// exact values.  This should be done when resetting the core, as
+    /// garbage in the floating point state may cause spurious #GP or
+    /// #UD faults in the guest.
+    pub fn reset_fpu(&mut self) -> Result<()> {
+        self.set_fpu(&Core::power_on_fpu())
+    }
+
+    /// The floating point state of an x86 processor at power-on.  The
+    /// x87 control word (FCW) is set to `0x37f`, i.e. all exceptions
+    /// masked, 64-bit precision, and round-to-nearest; the SSE control
+    /// register (MXCSR) is set to `0x1f80`, i.e. all exceptions masked
+    /// and round-to-nearest.  Every other value, including all of the
+    /// x87 and XMM registers, is cleared.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::core::Core;
+    /// let fpu = Core::power_on_fpu();
+    /// assert_eq!(fpu.fcw, 0x37f);
+    /// assert_eq!(fpu.mxcsr, 0x1f80);
+    /// assert_eq!(fpu.fsw, 0);
+    /// ```
+    pub fn power_on_fpu() -> kvm::Fpu {
+        let mut fpu: kvm::Fpu = unsafe { ::std::mem::zeroed() };
+        fpu.fcw = 0x37f;
+        fpu.mxcsr = 0x1f80;
+        fpu
+    }
+
+    /// Resets the core to the state an x86 processor has at power-on,
+    /// for a reboot of the guest.  The core starts in real mode at
+    /// `F000:FFF0`, i.e. at guest address `0xffff_fff0`; the general
+    /// purpose registers are cleared, except for `RDX`, which holds the
+    /// processor signature (the `EAX` of CPUID leaf 1, from
+    /// [`Core::cpuid`], or `0x600` if no CPUID was set); paging and
+    /// protection are disabled, the floating point state is reset (see
+    /// [`Core::reset_fpu`]), and any pending events are cleared (see
+    /// [`Core::clear_pending_events`]).
+    ///
+    /// The state of the core is reset as well.  The bootstrap processor,
+    /// as flagged in its APIC base, is made runnable (see
+    /// [`Core::make_bsp`]); with an in-kernel irqchip, every other core
+    /// is made an application processor (see [`Core::make_ap`]), so
+    /// that it waits for the bootstrap processor to start it.  Without
+    /// one, the kernel only accepts a runnable core, and so every core
+    /// is made runnable; keeping the application processors from
+    /// running is then up to the VMM, as it emulates their IPIs.
+    ///
+    /// The APIC base is kept, and the registers of the local APIC and
+    /// the MSRs are not reset; the guest firmware sets these up again as
+    /// it boots.  This is only supported on x86.
+    pub fn reset(&mut self) -> Result<()> {
+        let signature = self
+            .cpuid()?
+            .iter()
+            .find(|entry| entry.function == 1)
+            .map_or(0x600, |entry| entry.eax);
+        let mut regs: kvm::Regs = unsafe { ::std::mem::zeroed() };
+        regs.rip = 0xfff0;
+        regs.rdx = signature as u64;
+        // Bit 1 of RFLAGS is reserved, and always set.
+        regs.rflags = 0x2;
+        self.set_regs(&regs)?;
+
+        let mut sregs: kvm::Sregs = unsafe { ::std::mem::zeroed() };
+        sregs.apic_base = self.sregs()?.apic_base;
+        sregs.cs = power_on_segment(0xf000, 0xffff_0000, 0xb, 1);
+        sregs.ds = power_on_segment(0, 0, 0x3, 1);
+        sregs.es = sregs.ds;
+        sregs.fs = sregs.ds;
+        sregs.gs = sregs.ds;
+        sregs.ss = sregs.ds;
+        sregs.ldt = power_on_segment(0, 0, 0x2, 0);
+        sregs.tr = power_on_segment(0, 0, 0xb, 0);
+        sregs.gdt.limit = 0xffff;
+        sregs.idt.limit = 0xffff;
+        // Caching and write-through are disabled, and the FPU is native
+        // (ET).
+        sregs.cr0 = 0x6000_0010;
+        self.set_sregs(&sregs)?;
+
+        self.reset_fpu()?;
+        self.clear_pending_events()?;
+
+        // Bit 8 of the APIC base flags the bootstrap processor.
+        if sregs.apic_base & 0x100 != 0 || !self.5 {
+            self.make_bsp()
+        } else {
+            self.make_ap()
+        }
+    }
+
+    /// Notifies the guest that it was paused, through the kvmclock
+    /// shared page.  This keeps the guest from flagging a soft lockup
+    /// once it is resumed, as its clock will have jumped forward.  This
+    /// must be called on every core while the guest is paused, e.g. for
+    /// migration or snapshotting.  This requires the
+    /// [`Capability::KvmclockCtrl`] capability, and errors if the guest
+    /// has not set up kvmclock.
+    pub fn kvmclock_ctrl(&mut self) -> Result<()> {
+        self.assert_extension(Capability::KvmclockCtrl)?;
+        unsafe { kvm::kvm_kvmclock_ctrl(self.as_raw_fd()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_kvmclock_ctrl"))?;
+        Ok(())
+    }
+
+    /// Retrieves the nested virtualization state of the core.  This is
+    /// needed to migrate a guest that is running its own hypervisor
+    /// (nested VMX or SVM).  This requires the
+    /// [`Capability::NestedState`] capability.
+    pub fn nested_state(&self) -> Result<NestedState> {
+        self.assert_extension(Capability::NestedState)?;
+        // First, we ask with just the header, to figure out how large
+        // the state is.  The kernel fills in the size it needs, and
+        // returns E2BIG if the buffer is too small.
+        let mut state = NestedState::with_size(self::nested::HEADER_SIZE);
+        match unsafe { kvm::kvm_get_nested_state(self.as_raw_fd(), state.as_mut_ptr()) } {
+            Ok(_) => return Ok(state),
+            Err(::nix::Error::Sys(::nix::errno::Errno::E2BIG)) => {}
+            Err(err) => {
+                return Err(err).chain_err(|| ErrorKind::CoreApiError("kvm_get_nested_state"))
+            }
+        }
+
+        let mut state = NestedState::with_size(state.size());
+        unsafe { kvm::kvm_get_nested_state(self.as_raw_fd(), state.as_mut_ptr()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_nested_state"))?;
+        Ok(state)
+    }
+
+    /// Sets the nested virtualization state of the core.  The state
+    /// should be one retrieved through [`Core::nested_state`], likely
+    /// on another host.  This requires the [`Capability::NestedState`]
+    /// capability.
+    pub fn set_nested_state(&self, state: &NestedState) -> Result<()> {
+        self.assert_extension(Capability::NestedState)?;
+        unsafe { kvm::kvm_set_nested_state(self.as_raw_fd(), state.as_ptr()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_nested_state"))?;
+        Ok(())
+    }
+
+    /// Sets the register sets that the kernel should synchronize through
+    /// the run structure on every exit.  This avoids having to retrieve
+    /// and set the registers through separate API calls around every
+    /// exit, which is much faster for exit-heavy workloads.  The
+    /// registers can then be accessed through [`Data::sync_regs`] and
+    /// [`DataMut::sync_regs_mut`].
+    ///
+    /// This requires the [`Capability::SyncRegs`] capability, and that
+    /// every given register set be supported by it.
+    pub fn sync_regs(&mut self, regs: SyncRegsFlag) -> Result<()> {
+        let supported = self.assert_extension(Capability::SyncRegs)?;
+        if !SyncRegsFlag::from_bits_truncate(supported.get() as u64).contains(regs) {
+            return Err(ErrorKind::MissingExtensionError(Capability::SyncRegs).into());
+        }
+
+        unsafe { (*self.1).kvm_valid_regs = regs.bits() };
+        Ok(())
+    }
+
+    /// Sets the guest physical address of the virtual APIC page.  With a
+    /// userspace APIC, this allows the kernel to read and write the task
+    /// priority register (TPR) from the page, instead of exiting on
+    /// every access to it.  The page must be within a region registered
+    /// with the machine; the kernel uses the layout of the Windows
+    /// "kvm-vapic" option ROM (`struct vapic_state`), and so the option
+    /// ROM is expected to maintain it.  This requires the
+    /// [`Capability::Vapic`] capability.
+    pub fn set_vapic_addr(&mut self, gpa: u64) -> Result<()> {
+        self.assert_extension(Capability::Vapic)?;
+        let addr = kvm::VapicAddr { vapic_addr: gpa };
+        unsafe { kvm::kvm_set_vapic_addr(self.as_raw_fd(), &addr as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_vapic_addr"))?;
+        Ok(())
+    }
+
     /// Retrieves a read-only version of the data for the CPU.  Since
-    /// CPUs cannot be sent across threads, this is safe.
+    /// CPUs cannot be shared across threads, this is safe.
     pub fn data<'c>(&'c self) -> Data<'c> {
-        Data(unsafe { &*self.1 })
+        Data(unsafe { &*self.1 }, self.2)
     }
 
     /// Retrieves a read-write version of the data for the CPU.  Since
     /// this requires a mutable reference to the CPU, and the CPU cannot
     /// be shared across threads, this is safe.
     pub fn data_mut<'c>(&'c mut self) -> DataMut<'c> {
-        DataMut(unsafe { &mut *self.1 })
+        DataMut(unsafe { &mut *self.1 }, self.2)
     }
 
     /// Runs the vCPU.
+    ///
+    /// # Example
+    /// This runs a small real-mode program placed at the reset vector,
+    /// which repeatedly writes to a port.  If KVM is not available on
+    /// this host, this does nothing.
+    ///
+    /// ```rust
+    /// # use kvm::core::Pause;
+    /// # use kvm::machine::{MachineKind, RegionOptions};
+    /// # use kvm::memory::Slab;
+    /// # use kvm::system::System;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let system = match System::new() {
+    ///     Ok(system) => system,
+    ///     Err(_) => return Ok(()),
+    /// };
+    ///
+    /// // mov al, 0x2a; out 0x10, al; jmp -6
+    /// let mut slab = Slab::from_anon(0x10000)?;
+    /// slab.write(0xfff0, &[0xb0, 0x2a, 0xe6, 0x10, 0xeb, 0xfa])?;
+    ///
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// machine.set_tss_address(0xfffb_d000)?;
+    /// let mut region = RegionOptions::new(0);
+    /// region.addr(0xffff_0000).source(slab.as_mut_slice()?);
+    /// machine.set_region(region)?;
+    ///
+    /// let mut core = machine.create_core(0)?;
+    /// for _ in 0..3 {
+    ///     let run = core.run()?;
+    ///     match Pause::from(run.exit_reason, &run.exit) {
+    ///         Pause::Io(io) => assert_eq!(io.port, 0x10),
+    ///         _ => panic!("expected a port IO exit"),
+    ///     }
+    ///     assert_eq!(core.data().io().map(|io| io.data()), Some(&[0x2a][..]));
+    /// }
+    /// #     Ok(())
+    /// # }
+    /// ```
     pub fn run(&mut self) -> Result<kvm::Run> {
+        let section = self.7.enter(self.as_raw_fd())?;
         unsafe { kvm::kvm_run(self.as_raw_fd()) }
             .chain_err(|| ErrorKind::CoreApiError("kvm_run"))?;
+        drop(section);
         Ok(unsafe { *self.1 })
     }
 
@@ -70,22 +593,151 @@ impl Core {
     pub fn jaunt(&mut self) -> Result<kvm::Run> {
         let previous = unsafe { (*self.1).immediate_exit };
         unsafe { (*self.1).immediate_exit = 1 };
+        let section = self.7.enter(self.as_raw_fd())?;
         unsafe { kvm::kvm_run(self.as_raw_fd()) }
             .chain_err(|| ErrorKind::CoreApiError("kvm_run"))?;
+        drop(section);
         unsafe { (*self.1).immediate_exit = previous };
         Ok(unsafe { *self.1 })
     }
 
+    /// Requests that the vCPU not enter the guest.  This sets
+    /// `immediate_exit` in the run structure, so any following call to
+    /// [`Core::run`] returns immediately with `EINTR`, until
+    /// [`Core::clear_exit_request`] is called.
+    pub fn request_exit(&self) {
+        unsafe { ptr::write_volatile(&mut (*self.1).immediate_exit, 1) };
+    }
+
+    /// Clears a previous exit request, made either through
+    /// [`Core::request_exit`] or by dropping a [`RunGuard`].
+    pub fn clear_exit_request(&self) {
+        unsafe { ptr::write_volatile(&mut (*self.1).immediate_exit, 0) };
+    }
+
+    /// Creates a guard that kicks the core out of the guest when it is
+    /// dropped.  This allows a supervising thread to make sure that
+    /// the vCPU stops running if it panics or returns early.  Call
+    /// [`RunGuard::disarm`] to drop the guard without requesting an
+    /// exit, and [`Core::clear_exit_request`] to run the core again
+    /// after the guard fired.
+    ///
+    /// The guard shares the mapping of the run structure with the core,
+    /// and so it may outlive the core; kicking a core that was dropped
+    /// does nothing.
+    pub fn run_guard(&self) -> RunGuard {
+        RunGuard(Some(self.7.clone()))
+    }
+
     /// Sends an interrupt on a given line to the CPU.  This is needed
     /// to inform the CPU of events.
+    ///
+    /// This is only valid if the machine has no in-kernel irqchip, i.e.
+    /// the interrupt controller is emulated in userspace; otherwise, the
+    /// kernel rejects it.  If the core was created after
+    /// [`Machine::create_irqchip`], this errors with
+    /// [`ErrorKind::InKernelIrqchipError`]; interrupts must instead be
+    /// raised through the irqchip, with [`Machine::set_irq_level`] or
+    /// an irqfd.
     pub fn interrupt(&mut self, irq: u32) -> Result<()> {
+        if self.5 {
+            return Err(ErrorKind::InKernelIrqchipError.into());
+        }
         let interrupt = kvm::Interrupt { irq };
         unsafe { kvm::kvm_interrupt(self.as_raw_fd(), &interrupt) }
             .chain_err(|| ErrorKind::CoreApiError("kvm_interrupt"))?;
         Ok(())
     }
+
+    /// Runs the core until it stops for a reason other than a `HLT`
+    /// instruction, returning that pause.  On every halt, `wait` is
+    /// called to get the next interrupt vector, blocking for as long as
+    /// it needs to; the interrupt is then injected through
+    /// [`Core::interrupt`], and the core is resumed.  If `wait` returns
+    /// `None`, the halt is returned instead, e.g. so that the caller
+    /// can shut down.
+    ///
+    /// The interrupt is only injected once the core can accept it, i.e.
+    /// once it is ready for injection and has interrupts enabled; until
+    /// then, an interrupt window is requested, and the core is resumed
+    /// without it.  If the core stops for another reason in the
+    /// meantime, the interrupt is kept, and is injected by the next call
+    /// to this once the window opens.
+    ///
+    /// Halts only cause exits without an in-kernel irqchip.  With one
+    /// (see [`Machine::create_irqchip`]), the kernel waits for the
+    /// interrupt itself, and interrupts are raised through
+    /// [`Machine::set_irq_level`] or an irqfd instead.
+    pub fn run_handling_hlt<F>(&mut self, mut wait: F) -> Result<Pause>
+    where
+        F: FnMut() -> Option<u32>,
+    {
+        loop {
+            let run = self.run()?;
+            match Pause::from(run.exit_reason, &run.exit) {
+                // While an interrupt is waiting for the window, the halt
+                // is ended by injecting that one.
+                Pause::Halt if self.9.is_none() => match wait() {
+                    Some(vector) => self.9 = Some(vector),
+                    None => return Ok(Pause::Halt),
+                },
+                Pause::Halt => {}
+                Pause::IrqWindowOpen if self.9.is_some() => {}
+                pause => return Ok(pause),
+            }
+
+            let ready = {
+                let data = self.data();
+                data.ready_for_interrupt_injection() && data.if_flag()
+            };
+            if let (true, Some(vector)) = (ready, self.9) {
+                self.interrupt(vector)?;
+                self.9 = None;
+            }
+
+            let pending = self.9.is_some();
+            self.data_mut().set_request_interrupt_window(pending);
+        }
+    }
+
+    /// Injects the next interrupt from the queue, if the core is able
+    /// to accept it.  Otherwise, this requests an interrupt window, so
+    /// that the core exits with [`Pause::IrqWindowOpen`] once it can;
+    /// this should then be called again.  This returns whether or not
+    /// an interrupt was injected.
+    ///
+    /// The interrupt window is requested for as long as there are
+    /// interrupts in the queue.  This is only meaningful without an
+    /// in-kernel irqchip, i.e. when the APIC is emulated in userspace.
+    pub fn inject_pending(&mut self, vector_queue: &mut VecDeque<u32>) -> Result<bool> {
+        let ready = {
+            let data = self.data();
+            data.ready_for_interrupt_injection() && data.if_flag()
+        };
+
+        let injected = match vector_queue.front() {
+            Some(&vector) if ready => {
+                self.interrupt(vector)?;
+                vector_queue.pop_front();
+                true
+            }
+            _ => false,
+        };
+
+        let pending = !vector_queue.is_empty();
+        self.data_mut().set_request_interrupt_window(pending);
+        Ok(injected)
+    }
 }
 
+// The run structure is shared with the guards of the core, and is
+// unmapped once the core and all of them are dropped; nothing ties it
+// to the thread that created the core, and the kernel handles a core
+// being run from a different thread than before.  Since the core is
+// not `Sync`, only one thread at a time can access the structure
+// through it.
+unsafe impl Send for Core {}
+
 impl AsRawFd for Core {
     fn as_raw_fd(&self) -> RawFd {
         self.0.as_raw_fd()
@@ -93,31 +745,83 @@ impl AsRawFd for Core {
 }
 
 impl FromRawFd for Core {
+    /// Adopts the core with the given file descriptor.  The size of the
+    /// mapping of its run structure is not known from the core itself,
+    /// and so this opens the system to query it; this panics if that
+    /// fails, or if the core cannot be mapped.  See
+    /// [`Core::from_raw_fd_with_size`] to give the size instead.
     unsafe fn from_raw_fd(fd: RawFd) -> Core {
-        Core::new(fd).unwrap()
+        let size = super::system::core_mmap_size().unwrap();
+        Core::from_raw_fd_with_size(fd, size).unwrap()
     }
 }
 
 impl IntoRawFd for Core {
     fn into_raw_fd(self) -> RawFd {
-        self.0.into_raw_fd()
+        // We can't move the file out of the core, since it implements
+        // drop; instead, we release everything except the file.
+        let fd = self.as_raw_fd();
+        let mut core = ::std::mem::ManuallyDrop::new(self);
+        if let Some((ref state, key)) = core.6 {
+            state.unregister(key);
+        }
+        unsafe {
+            ::std::ptr::drop_in_place(&mut core.3);
+            ::std::ptr::drop_in_place(&mut core.6);
+            ::std::ptr::drop_in_place(&mut core.7);
+        }
+        // The mappings of the regions of the machine are leaked, as the
+        // core, and so the machine, stays open.
+        fd
+    }
+}
+
+impl Drop for Core {
+    fn drop(&mut self) {
+        if let Some((ref state, key)) = self.6 {
+            state.unregister(key);
+        }
     }
 }
 
-fn map_fd(fd: RawFd) -> Result<*mut kvm::Run> {
+/// Maps the run structure of the core into memory.  We prefer to lock
+/// the mapping, as that keeps the state of the vCPU from ever being
+/// swapped out; however, locking can fail without `CAP_IPC_LOCK`, or
+/// when running under a memory cgroup with a low locked memory limit
+/// (as is common in containers).  In that case, we fall back to an
+/// unlocked mapping, which works the same, but may be swapped.
+fn map_fd(fd: RawFd, size: usize) -> Result<*mut kvm::Run> {
+    map_fd_with(fd, size, MapFlags::MAP_SHARED | MapFlags::MAP_LOCKED)
+        .or_else(|_| map_fd_with(fd, size, MapFlags::MAP_SHARED))
+}
+
+fn map_fd_with(fd: RawFd, size: usize, flags: MapFlags) -> Result<*mut kvm::Run> {
     use nix::libc::c_void;
     use nix::sys::mman::*;
-    use std::mem::size_of;
 
     unsafe {
         mmap(
             0 as *mut c_void,
-            size_of::<kvm::Run>(),
+            size,
             ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
-            MapFlags::MAP_SHARED | MapFlags::MAP_LOCKED,
+            flags,
             fd,
             0,
         )
     }.map(|point| point as *mut kvm::Run)
     .chain_err(|| ErrorKind::MapCoreError)
 }
+
+/// A segment as it is at power-on: present, with a limit of 64KiB.
+/// `s` is whether it is a code or data segment, rather than a system
+/// segment.
+fn power_on_segment(selector: u16, base: u64, type_: u8, s: u8) -> kvm::Segment {
+    let mut segment: kvm::Segment = unsafe { ::std::mem::zeroed() };
+    segment.selector = selector;
+    segment.base = base;
+    segment.limit = 0xffff;
+    segment.type_ = type_;
+    segment.present = 1;
+    segment.s = s;
+    segment
+}
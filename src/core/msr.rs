@@ -0,0 +1,119 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+
+/// The index of the TSC deadline MSR, `IA32_TSC_DEADLINE`.
+pub const MSR_IA32_TSC_DEADLINE: u32 = 0x6e0;
+
+impl Core {
+    /// Retrieves the value of the given model-specific register.  This
+    /// errors with [`ErrorKind::UnsupportedMsrError`] if the kernel does
+    /// not support the register.  This is only supported on x86.
+    pub fn msr(&self, index: u32) -> Result<u64> {
+        let mut entry: kvm::MsrEntry = unsafe { ::std::mem::zeroed() };
+        entry.index = index;
+        let mut buf = msrs_buffer(&[entry]);
+        let msrs = buf.as_mut_ptr() as *mut kvm::Msrs;
+        // The kernel returns the number of registers it read, stopping
+        // at the first one it does not support.
+        let read = unsafe { kvm::kvm_get_msrs(self.as_raw_fd(), msrs) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_msrs"))?;
+        if read != 1 {
+            return Err(ErrorKind::UnsupportedMsrError(index).into());
+        }
+
+        Ok(unsafe { (*(*msrs).entries.as_ptr()).data })
+    }
+
+    /// Sets the value of the given model-specific register.  This
+    /// errors if the kernel does not support the register, or rejects
+    /// the value.  This is only supported on x86.
+    pub fn set_msr(&mut self, index: u32, value: u64) -> Result<()> {
+        if self.set_msrs(&[(index, value)])? != 1 {
+            return Err(ErrorKind::CoreApiError("kvm_set_msrs").into());
+        }
+
+        Ok(())
+    }
+
+    /// Sets the values of the given model-specific registers, as pairs
+    /// of index and value, in a single API call.  The kernel sets them
+    /// in order, stopping at the first one it does not support or whose
+    /// value it rejects; this returns the number it set.  If that is
+    /// less than the number given, the entry at that position is the
+    /// one that was rejected, and none after it were set.
+    ///
+    /// This only errors if the call itself fails.  This is only
+    /// supported on x86.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::machine::MachineKind;
+    /// # use kvm::system::System;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let system = match System::new() {
+    ///     Ok(system) => system,
+    ///     Err(_) => return Ok(()),
+    /// };
+    ///
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// let mut core = machine.create_core(0)?;
+    /// // `IA32_TSC`, then an index no processor defines.
+    /// let msrs = [(0x10, 0x1000), (0xdead_beef, 0), (0x10, 0x2000)];
+    /// assert_eq!(core.set_msrs(&msrs)?, 1);
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn set_msrs(&mut self, msrs: &[(u32, u64)]) -> Result<usize> {
+        let entries = msrs
+            .iter()
+            .map(|&(index, value)| {
+                let mut entry: kvm::MsrEntry = unsafe { ::std::mem::zeroed() };
+                entry.index = index;
+                entry.data = value;
+                entry
+            }).collect::<Vec<_>>();
+        let mut buf = msrs_buffer(&entries);
+        let raw = buf.as_mut_ptr() as *mut kvm::Msrs;
+        let written = unsafe { kvm::kvm_set_msrs(self.as_raw_fd(), raw) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_msrs"))?;
+        Ok(written as usize)
+    }
+
+    /// Retrieves the TSC deadline of the core, i.e. the value of the TSC
+    /// at which the APIC timer fires when it is in TSC-deadline mode.
+    /// This is zero if the timer is not armed.
+    ///
+    /// This requires an in-kernel local APIC, i.e. an irqchip created
+    /// through [`Machine::create_irqchip`], or a split irqchip.  The
+    /// kernel then emulates the timer itself, and this is mainly useful
+    /// for inspection, or for saving and restoring the core.  Without
+    /// one, the kernel ignores writes to the MSR, and reads it back as
+    /// zero, and so the timer cannot be emulated from here.
+    pub fn tsc_deadline(&self) -> Result<u64> {
+        self.msr(MSR_IA32_TSC_DEADLINE)
+    }
+
+    /// Sets the TSC deadline of the core.  See [`Core::tsc_deadline`].
+    /// Setting it to zero disarms the timer.
+    pub fn set_tsc_deadline(&mut self, deadline: u64) -> Result<()> {
+        self.set_msr(MSR_IA32_TSC_DEADLINE, deadline)
+    }
+}
+
+/// Builds the variable-length structure for the MSR API calls, with
+/// the given entries.  The buffer is made of words, so that it is
+/// aligned for the entries.
+pub(super) fn msrs_buffer(entries: &[kvm::MsrEntry]) -> Vec<u64> {
+    let size = size_of::<kvm::Msrs>() + entries.len() * size_of::<kvm::MsrEntry>();
+    let mut buf = vec![0u64; (size + 7) / 8];
+    let msrs = buf.as_mut_ptr() as *mut kvm::Msrs;
+    unsafe {
+        (*msrs).nmsrs = entries.len() as u32;
+        ptr::copy_nonoverlapping(entries.as_ptr(), (*msrs).entries.as_mut_ptr(), entries.len());
+    }
+    buf
+}
@@ -0,0 +1,85 @@
+use byteorder::{ByteOrder, NativeEndian};
+use kvm_sys as kvm;
+use std::mem::size_of;
+
+/// The size of the fixed header of the nested state; the opaque,
+/// vendor-specific data follows it.
+pub(super) const HEADER_SIZE: usize = size_of::<kvm::NestedState>();
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The format of the nested state.  This is determined by the vendor
+/// of the host processor.
+pub enum NestedFormat {
+    /// Intel VMX.
+    Vmx,
+    /// AMD SVM.
+    Svm,
+    /// A format that this library does not know about.
+    Unknown(u16),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+/// The nested virtualization state of a core.  This is an opaque blob,
+/// prefixed with a small header describing the format and the size of
+/// the blob.  The blob should be considered to be only meaningful to a
+/// host with the same format.
+pub struct NestedState(Vec<u8>);
+
+impl NestedState {
+    pub(super) fn with_size(size: usize) -> NestedState {
+        let mut data = vec![0u8; size.max(HEADER_SIZE)];
+        let size = data.len() as u32;
+        NativeEndian::write_u32(&mut data[4..8], size);
+        NestedState(data)
+    }
+
+    /// Creates the nested state from a blob previously retrieved
+    /// through [`NestedState::as_bytes`].  Returns `None` if the blob
+    /// is shorter than the header, or shorter than the size the header
+    /// claims.
+    pub fn from_bytes(bytes: &[u8]) -> Option<NestedState> {
+        if bytes.len() < HEADER_SIZE {
+            return None;
+        }
+
+        let state = NestedState(bytes.to_vec());
+        if state.size() > bytes.len() {
+            None
+        } else {
+            Some(state)
+        }
+    }
+
+    /// The flags of the nested state, e.g. whether or not the guest was
+    /// in guest mode at the time.
+    pub fn flags(&self) -> u16 {
+        NativeEndian::read_u16(&self.0[0..2])
+    }
+
+    /// The format of the nested state.
+    pub fn format(&self) -> NestedFormat {
+        match NativeEndian::read_u16(&self.0[2..4]) {
+            kvm::KVM_STATE_NESTED_FORMAT_VMX => NestedFormat::Vmx,
+            kvm::KVM_STATE_NESTED_FORMAT_SVM => NestedFormat::Svm,
+            other => NestedFormat::Unknown(other),
+        }
+    }
+
+    /// The total size of the nested state, including the header.
+    pub fn size(&self) -> usize {
+        NativeEndian::read_u32(&self.0[4..8]) as usize
+    }
+
+    /// The entire nested state, including the header.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub(super) fn as_ptr(&self) -> *const kvm::NestedState {
+        self.0.as_ptr() as *const kvm::NestedState
+    }
+
+    pub(super) fn as_mut_ptr(&mut self) -> *mut kvm::NestedState {
+        self.0.as_mut_ptr() as *mut kvm::NestedState
+    }
+}
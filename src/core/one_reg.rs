@@ -0,0 +1,111 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use std::os::unix::io::AsRawFd;
+
+/// The architecture bits of a register identifier for arm64.
+pub const ONE_REG_ARM64: u64 = 0x6000_0000_0000_0000;
+/// The size bits of a register identifier for a 64-bit register.
+pub const ONE_REG_SIZE_U64: u64 = 0x0030_0000_0000_0000;
+/// The coprocessor bits of a register identifier for the arm64 core
+/// registers, i.e. those in the kernel's `struct kvm_regs`.
+pub const ONE_REG_ARM_CORE: u64 = 0x0010 << 16;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u64)]
+/// The core registers of an arm64 core, for [`Core::one_reg`] and
+/// [`Core::set_one_reg`].  The discriminant of each register is its
+/// offset into the kernel's `struct kvm_regs`, in 32-bit words; the
+/// full identifier is given by [`RegisterId::id`].
+///
+/// # Example
+/// ```rust
+/// # use kvm::core::RegisterId;
+/// assert_eq!(RegisterId::X0.id(), 0x6030_0000_0010_0000);
+/// assert_eq!(RegisterId::X30.id(), 0x6030_0000_0010_003c);
+/// assert_eq!(RegisterId::Pc.id(), 0x6030_0000_0010_0040);
+/// assert_eq!(u64::from(RegisterId::Pstate), 0x6030_0000_0010_0042);
+/// ```
+pub enum RegisterId {
+    X0 = 0,
+    X1 = 2,
+    X2 = 4,
+    X3 = 6,
+    X4 = 8,
+    X5 = 10,
+    X6 = 12,
+    X7 = 14,
+    X8 = 16,
+    X9 = 18,
+    X10 = 20,
+    X11 = 22,
+    X12 = 24,
+    X13 = 26,
+    X14 = 28,
+    X15 = 30,
+    X16 = 32,
+    X17 = 34,
+    X18 = 36,
+    X19 = 38,
+    X20 = 40,
+    X21 = 42,
+    X22 = 44,
+    X23 = 46,
+    X24 = 48,
+    X25 = 50,
+    X26 = 52,
+    X27 = 54,
+    X28 = 56,
+    X29 = 58,
+    X30 = 60,
+    /// The stack pointer of EL0, `SP_EL0`.
+    Sp = 62,
+    /// The program counter.
+    Pc = 64,
+    /// The processor state, `PSTATE`.
+    Pstate = 66,
+}
+
+impl RegisterId {
+    /// The identifier of the register, as the kernel expects it.
+    pub fn id(self) -> u64 {
+        ONE_REG_ARM64 | ONE_REG_SIZE_U64 | ONE_REG_ARM_CORE | self as u64
+    }
+}
+
+impl From<RegisterId> for u64 {
+    fn from(reg: RegisterId) -> u64 {
+        reg.id()
+    }
+}
+
+impl Core {
+    /// Retrieves a single 64-bit register of the core, by its
+    /// identifier.  The identifier encodes the architecture, the size
+    /// of the register, and the register itself; see [`RegisterId`]
+    /// for the arm64 core registers.  This is how registers are
+    /// accessed on arm64, which has no equivalent of [`Core::regs`];
+    /// x86 does not support it.
+    pub fn one_reg(&self, id: impl Into<u64>) -> Result<u64> {
+        let mut value = 0u64;
+        let reg = kvm::OneReg {
+            id: id.into(),
+            addr: &mut value as *mut u64 as u64,
+        };
+        unsafe { kvm::kvm_get_one_reg(self.as_raw_fd(), &reg as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_one_reg"))?;
+        Ok(value)
+    }
+
+    /// Sets a single 64-bit register of the core, by its identifier.
+    /// See [`Core::one_reg`].
+    pub fn set_one_reg(&mut self, id: impl Into<u64>, value: u64) -> Result<()> {
+        let reg = kvm::OneReg {
+            id: id.into(),
+            addr: &value as *const u64 as u64,
+        };
+        unsafe { kvm::kvm_set_one_reg(self.as_raw_fd(), &reg as *const _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_one_reg"))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use super::kick::RunMap;
+use super::Core;
+use error::*;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+
+/// The shared state for parking the cores of a machine.  See
+/// [`Machine::pause_all`] for the protocol.
+#[derive(Debug, Default)]
+pub(crate) struct ParkState {
+    inner: Mutex<Inner>,
+    changed: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requested: bool,
+    next: usize,
+    cores: Vec<Entry>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    key: usize,
+    map: Arc<RunMap>,
+    // Whether or not the run loop of the core follows the protocol,
+    // i.e. it called `park_if_requested` at least once.  A pause only
+    // kicks and waits on such cores.
+    active: bool,
+    parked: bool,
+    // Whether or not the core was kicked by the current pause, and so
+    // has an exit request to clear once it is over.
+    kicked: bool,
+}
+
+impl Inner {
+    fn entry(&mut self, key: usize) -> Option<&mut Entry> {
+        self.cores.iter_mut().find(|entry| entry.key == key)
+    }
+
+    /// Whether or not every core that follows the protocol is parked.
+    fn all_parked(&self) -> bool {
+        self.cores.iter().all(|entry| !entry.active || entry.parked)
+    }
+}
+
+impl ParkState {
+    fn lock(&self) -> MutexGuard<Inner> {
+        self.inner.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Adds a core, returning the key it is known by.
+    pub(super) fn register(&self, map: &Arc<RunMap>) -> usize {
+        let mut inner = self.lock();
+        let key = inner.next;
+        inner.next += 1;
+        inner.cores.push(Entry {
+            key,
+            map: map.clone(),
+            active: false,
+            parked: false,
+            kicked: false,
+        });
+        key
+    }
+
+    /// Removes a core, e.g. because it was dropped.  A pause waiting on
+    /// the core no longer does.
+    pub(super) fn unregister(&self, key: usize) {
+        let mut inner = self.lock();
+        inner.cores.retain(|entry| entry.key != key);
+        self.changed.notify_all();
+    }
+
+    /// Whether or not a pause is in effect.
+    pub(super) fn requested(&self) -> bool {
+        self.lock().requested
+    }
+
+    /// Whether or not the given core is held by a pause in effect.
+    pub(super) fn holds(&self, key: usize) -> bool {
+        let mut inner = self.lock();
+        let requested = inner.requested;
+        inner.entry(key).map_or(false, |entry| requested && entry.active)
+    }
+
+    /// Requests that every core park, and waits until every core that
+    /// follows the protocol has.
+    pub(crate) fn pause(&self) -> Result<()> {
+        let mut inner = self.lock();
+        if !inner.requested {
+            inner.requested = true;
+            for entry in inner.cores.iter_mut().filter(|entry| entry.active) {
+                entry.map.kick();
+                entry.kicked = true;
+            }
+        }
+
+        while inner.requested && !inner.all_parked() {
+            inner = self
+                .changed
+                .wait(inner)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+        Ok(())
+    }
+
+    /// Lets every parked core run again.
+    pub(crate) fn resume(&self) {
+        let mut inner = self.lock();
+        inner.requested = false;
+        self.changed.notify_all();
+    }
+
+    /// Parks the calling core while a pause is in effect.  Once it is
+    /// over, the exit request made by the pause is cleared.
+    fn park(&self, key: usize) {
+        let mut inner = self.lock();
+        let requested = inner.requested;
+        match inner.entry(key) {
+            Some(entry) => {
+                entry.active = true;
+                entry.parked = requested;
+            }
+            None => return,
+        }
+
+        if requested {
+            self.changed.notify_all();
+            while inner.requested {
+                inner = self
+                    .changed
+                    .wait(inner)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+
+        if let Some(entry) = inner.entry(key) {
+            entry.parked = false;
+            if entry.kicked {
+                entry.kicked = false;
+                entry.map.set_immediate_exit(0);
+            }
+        }
+    }
+}
+
+impl Core {
+    /// Blocks while the machine of this core is paused, through
+    /// [`Machine::pause_all`].  The run loop of a core created through
+    /// the machine joins the protocol the first time it calls this, and
+    /// must then call this before every run of the core; see
+    /// [`Machine::pause_all`] for the protocol.  This does nothing for
+    /// a core that was not created through a machine.
+    ///
+    /// Once the pause is over, the exit request the pause made is
+    /// cleared, along with any other exit request made during it (see
+    /// [`Core::request_exit`]).
+    pub fn park_if_requested(&self) {
+        if let Some((ref state, key)) = self.6 {
+            state.park(key);
+        }
+    }
+
+    /// Whether or not the machine of this core is paused, i.e.
+    /// [`Machine::pause_all`] was called, and [`Machine::resume_all`]
+    /// has not been since.  While this is true, a core that follows the
+    /// protocol does not run again: it parks at its next call to
+    /// [`Core::park_if_requested`], and any run before that returns
+    /// immediately with `EINTR`.
+    pub fn pause_requested(&self) -> bool {
+        match self.6 {
+            Some((ref state, _)) => state.requested(),
+            None => false,
+        }
+    }
+
+    /// Whether or not the core is held by the pause of its machine, i.e.
+    /// a pause is in effect, and the run loop of the core follows the
+    /// protocol (see [`Core::park_if_requested`]).  Such a core is
+    /// parked, or parks at its next call to
+    /// [`Core::park_if_requested`]; [`Machine::pause_all`] waits on it.
+    /// A core whose loop never called [`Core::park_if_requested`] is not
+    /// held, and keeps running through the pause.
+    pub fn is_parked(&self) -> bool {
+        match self.6 {
+            Some((ref state, key)) => state.holds(key),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,225 @@
+use super::exit::*;
+use super::Exit;
+use kvm_sys as kvm;
+
+#[derive(Copy, Clone)]
+/// The reason the core stopped running.  Unlike [`Exit`], this is a
+/// copy of the exit information, and so is not tied to the lifetime of
+/// the core; this allows it to be sent across threads, or to be kept
+/// around after the core is run again.
+///
+/// Exits that have no information attached to them are given their own
+/// variants.  Exit reasons that this library does not know about are
+/// given as `Invalid`.
+pub enum Pause {
+    Hw(ExitUnknown),
+    FailEntry(ExitFailEntry),
+    Ex(ExitException),
+    Io(ExitIo),
+    Mmio(ExitMmio),
+    Hypercall(ExitHypercall),
+    TprAccess(ExitTprAccess),
+    S390Sieic(ExitS390Sieic),
+    S390ResetFlags(u64),
+    S390Ucontrol(ExitS390Ucontrol),
+    Dcr(ExitDcr),
+    Internal(ExitInternal),
+    Osi(ExitOsi),
+    PaprHcall(ExitPaprHcall),
+    S390Tsch(ExitS390Tsch),
+    Epr(ExitEpr),
+    SystemEvent(ExitSystemEvent),
+    S390Stsi(ExitS390Stsi),
+    Eoi(ExitEoi),
+    /// The core executed a `HLT` instruction.  This only occurs if
+    /// there is no in-kernel irqchip.
+    Halt,
+    /// The core was shut down, e.g. due to a triple fault.
+    Shutdown,
+    /// The core is able to accept an interrupt.  This only occurs if it
+    /// was requested, through `request_interrupt_window`.
+    IrqWindowOpen,
+    /// The core was interrupted by a signal, or by an exit request.
+    Interrupted,
+    SetTpr,
+    Nmi,
+    Watchdog,
+    /// The core hit a debug event, e.g. a breakpoint or the end of a
+    /// single step.  See [`Core::set_guest_debug`].  The fields are as
+    /// on x86: the exception vector (`#DB` or `#BP`), the instruction
+    /// pointer, and the values of `DR6` and `DR7`.
+    Debug {
+        exception: u32,
+        pc: u64,
+        dr6: u64,
+        dr7: u64,
+    },
+    /// An exit reason this library does not know about.
+    Invalid(u32),
+}
+
+impl Pause {
+    /// The exit reason for this pause.
+    pub fn reason(&self) -> u32 {
+        self.split().0
+    }
+
+    /// Splits the pause into the exit reason and the exit information.
+    /// This is the reverse of [`Pause::from`].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::core::Pause;
+    /// let pause = Pause::Debug {
+    ///     exception: 1,
+    ///     pc: 0x1000,
+    ///     dr6: 0xffff_4ff0,
+    ///     dr7: 0x400,
+    /// };
+    /// let (reason, raw) = pause.split();
+    /// match Pause::from(reason, &raw) {
+    ///     Pause::Debug { exception, pc, dr6, dr7 } => {
+    ///         assert_eq!((exception, pc, dr6, dr7), (1, 0x1000, 0xffff_4ff0, 0x400));
+    ///     }
+    ///     _ => panic!("expected a debug pause"),
+    /// }
+    /// ```
+    pub fn split(&self) -> (u32, kvm::Exit) {
+        let none = unsafe { ::std::mem::zeroed() };
+        match self {
+            Pause::Hw(v) => Exit::Hw(v).split(),
+            Pause::FailEntry(v) => Exit::FailEntry(v).split(),
+            Pause::Ex(v) => Exit::Ex(v).split(),
+            Pause::Io(v) => Exit::Io(v).split(),
+            Pause::Mmio(v) => Exit::Mmio(v).split(),
+            Pause::Hypercall(v) => Exit::Hypercall(v).split(),
+            Pause::TprAccess(v) => Exit::TprAccess(v).split(),
+            Pause::S390Sieic(v) => Exit::S390Sieic(v).split(),
+            Pause::S390ResetFlags(v) => Exit::S390ResetFlags(*v).split(),
+            Pause::S390Ucontrol(v) => Exit::S390Ucontrol(v).split(),
+            Pause::Dcr(v) => Exit::Dcr(v).split(),
+            Pause::Internal(v) => Exit::Internal(v).split(),
+            Pause::Osi(v) => Exit::Osi(v).split(),
+            Pause::PaprHcall(v) => Exit::PaprHcall(v).split(),
+            Pause::S390Tsch(v) => Exit::S390Tsch(v).split(),
+            Pause::Epr(v) => Exit::Epr(v).split(),
+            Pause::SystemEvent(v) => Exit::SystemEvent(v).split(),
+            Pause::S390Stsi(v) => Exit::S390Stsi(v).split(),
+            Pause::Eoi(v) => Exit::Eoi(v).split(),
+            Pause::Halt => (kvm::KVM_EXIT_HLT, none),
+            Pause::Shutdown => (kvm::KVM_EXIT_SHUTDOWN, none),
+            Pause::IrqWindowOpen => (kvm::KVM_EXIT_IRQ_WINDOW_OPEN, none),
+            Pause::Interrupted => (kvm::KVM_EXIT_INTR, none),
+            Pause::SetTpr => (kvm::KVM_EXIT_SET_TPR, none),
+            Pause::Nmi => (kvm::KVM_EXIT_NMI, none),
+            Pause::Watchdog => (kvm::KVM_EXIT_WATCHDOG, none),
+            Pause::Debug {
+                exception,
+                pc,
+                dr6,
+                dr7,
+            } => {
+                let mut raw: kvm::Exit = none;
+                unsafe {
+                    raw.debug.arch.exception = *exception;
+                    raw.debug.arch.pc = *pc;
+                    raw.debug.arch.dr6 = *dr6;
+                    raw.debug.arch.dr7 = *dr7;
+                }
+                (kvm::KVM_EXIT_DEBUG, raw)
+            }
+            Pause::Invalid(reason) => (*reason, none),
+        }
+    }
+
+    /// Creates a pause from the given exit reason and exit information.
+    ///
+    /// # Example
+    /// Every exit reason, known or not, survives a round trip through
+    /// the pause, whether it comes from the raw exit or from a run
+    /// structure:
+    ///
+    /// ```rust
+    /// # extern crate kvm;
+    /// # extern crate kvm_sys;
+    /// # use kvm::core::Pause;
+    /// # use std::mem::discriminant;
+    /// # fn main() {
+    /// let raw: kvm_sys::Exit = unsafe { ::std::mem::zeroed() };
+    /// for reason in 0..64 {
+    ///     let pause = Pause::from(reason, &raw);
+    ///     let (split, copy): (u32, kvm_sys::Exit) = pause.into();
+    ///     assert_eq!(split, reason);
+    ///     assert!(discriminant(&pause) == discriminant(&Pause::from(split, &copy)));
+    ///
+    ///     let mut run: kvm_sys::Run = unsafe { ::std::mem::zeroed() };
+    ///     run.exit_reason = split;
+    ///     run.exit = copy;
+    ///     let from_run: Pause = run.into();
+    ///     assert!(discriminant(&pause) == discriminant(&from_run));
+    /// }
+    /// # }
+    /// ```
+    pub fn from(reason: u32, raw: &kvm::Exit) -> Pause {
+        match Exit::from(reason, raw) {
+            Some(exit) => exit.into(),
+            None => match reason {
+                kvm::KVM_EXIT_HLT => Pause::Halt,
+                kvm::KVM_EXIT_SHUTDOWN => Pause::Shutdown,
+                kvm::KVM_EXIT_IRQ_WINDOW_OPEN => Pause::IrqWindowOpen,
+                kvm::KVM_EXIT_INTR => Pause::Interrupted,
+                kvm::KVM_EXIT_SET_TPR => Pause::SetTpr,
+                kvm::KVM_EXIT_NMI => Pause::Nmi,
+                kvm::KVM_EXIT_WATCHDOG => Pause::Watchdog,
+                kvm::KVM_EXIT_DEBUG => {
+                    let arch = unsafe { raw.debug.arch };
+                    Pause::Debug {
+                        exception: arch.exception,
+                        pc: arch.pc,
+                        dr6: arch.dr6,
+                        dr7: arch.dr7,
+                    }
+                }
+                _ => Pause::Invalid(reason),
+            },
+        }
+    }
+}
+
+impl<'c> From<Exit<'c>> for Pause {
+    fn from(exit: Exit<'c>) -> Pause {
+        match exit {
+            Exit::Hw(v) => Pause::Hw(*v),
+            Exit::FailEntry(v) => Pause::FailEntry(*v),
+            Exit::Ex(v) => Pause::Ex(*v),
+            Exit::Io(v) => Pause::Io(*v),
+            Exit::Mmio(v) => Pause::Mmio(*v),
+            Exit::Hypercall(v) => Pause::Hypercall(*v),
+            Exit::TprAccess(v) => Pause::TprAccess(*v),
+            Exit::S390Sieic(v) => Pause::S390Sieic(*v),
+            Exit::S390ResetFlags(v) => Pause::S390ResetFlags(v),
+            Exit::S390Ucontrol(v) => Pause::S390Ucontrol(*v),
+            Exit::Dcr(v) => Pause::Dcr(*v),
+            Exit::Internal(v) => Pause::Internal(*v),
+            Exit::Osi(v) => Pause::Osi(*v),
+            Exit::PaprHcall(v) => Pause::PaprHcall(*v),
+            Exit::S390Tsch(v) => Pause::S390Tsch(*v),
+            Exit::Epr(v) => Pause::Epr(*v),
+            Exit::SystemEvent(v) => Pause::SystemEvent(*v),
+            Exit::S390Stsi(v) => Pause::S390Stsi(*v),
+            Exit::Eoi(v) => Pause::Eoi(*v),
+        }
+    }
+}
+
+impl From<kvm::Run> for Pause {
+    fn from(run: kvm::Run) -> Pause {
+        Pause::from(run.exit_reason, &run.exit)
+    }
+}
+
+impl Into<(u32, kvm::Exit)> for Pause {
+    fn into(self) -> (u32, kvm::Exit) {
+        self.split()
+    }
+}
@@ -0,0 +1,29 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+use std::os::unix::io::AsRawFd;
+
+impl Core {
+    /// Performs the initial CPU reset of the core, as defined by the
+    /// s390 architecture.  This clears most of the control registers
+    /// and the PSW, and should be done before the core is started for
+    /// the first time.
+    pub fn s390_initial_reset(&mut self) -> Result<()> {
+        unsafe { kvm::kvm_s390_initial_reset(self.as_raw_fd()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_s390_initial_reset"))?;
+        Ok(())
+    }
+
+    /// Performs the normal CPU reset of the core, as defined by the s390
+    /// architecture.  This only clears pending interrupts and the
+    /// runtime instrumentation state, leaving the rest of the core
+    /// intact.  This requires the [`Capability::S390VcpuResets`]
+    /// capability.
+    pub fn s390_normal_reset(&mut self) -> Result<()> {
+        self.assert_extension(Capability::S390VcpuResets)?;
+        unsafe { kvm::kvm_s390_normal_reset(self.as_raw_fd()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_s390_normal_reset"))?;
+        Ok(())
+    }
+}
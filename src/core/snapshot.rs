@@ -0,0 +1,93 @@
+use super::{Core, DebugRegs, State};
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+
+/// The model-specific registers gathered by [`Core::snapshot_state`]:
+/// the `SYSENTER` registers, the `SYSCALL` registers, the TSC, the TSC
+/// deadline, `IA32_MISC_ENABLE`, and the PAT.
+pub const SNAPSHOT_MSRS: &[u32] = &[
+    0x0000_0174, // IA32_SYSENTER_CS
+    0x0000_0175, // IA32_SYSENTER_ESP
+    0x0000_0176, // IA32_SYSENTER_EIP
+    0xc000_0081, // STAR
+    0xc000_0082, // LSTAR
+    0xc000_0083, // CSTAR
+    0xc000_0084, // SFMASK
+    0xc000_0102, // KERNEL_GS_BASE
+    0x0000_0010, // IA32_TIME_STAMP_COUNTER
+    0x0000_06e0, // IA32_TSC_DEADLINE
+    0x0000_01a0, // IA32_MISC_ENABLE
+    0x0000_0277, // IA32_PAT
+];
+
+#[derive(Debug, Clone)]
+/// The state of a core, gathered through [`Core::snapshot_state`].
+pub struct CoreSnapshot {
+    /// The general purpose registers.
+    pub regs: kvm::Regs,
+    /// The special registers.
+    pub sregs: kvm::Sregs,
+    /// The floating point state.
+    pub fpu: kvm::Fpu,
+    /// The pending events, if the [`Capability::VcpuEvents`] capability
+    /// is available.
+    pub events: Option<kvm::VcpuEvents>,
+    /// The debug registers, if the [`Capability::DebugRegs`] capability
+    /// is available.
+    pub debug_regs: Option<DebugRegs>,
+    /// The multiprocessing state.
+    pub state: State,
+    /// The values of the registers in [`SNAPSHOT_MSRS`] that the kernel
+    /// supports, as pairs of the index and the value.
+    pub msrs: Vec<(u32, u64)>,
+}
+
+impl Core {
+    /// Gathers the state of the core into a single structure, through
+    /// the individual getters.  This is the per-core half of a snapshot
+    /// of the machine, and is useful for debugging.
+    ///
+    /// The events and debug registers are skipped, and left as `None`,
+    /// if their capabilities are absent; any of [`SNAPSHOT_MSRS`] that
+    /// the kernel does not support are left out of the MSRs.  Every
+    /// other piece is required, and this errors if it cannot be
+    /// retrieved.  This is only supported on x86.
+    pub fn snapshot_state(&self) -> Result<CoreSnapshot> {
+        let events = if self.extension(Capability::VcpuEvents)? != 0 {
+            Some(self.events()?)
+        } else {
+            None
+        };
+        let debug_regs = if self.extension(Capability::DebugRegs)? != 0 {
+            Some(self.debug_registers()?)
+        } else {
+            None
+        };
+        let mut msrs = Vec::with_capacity(SNAPSHOT_MSRS.len());
+        for &index in SNAPSHOT_MSRS {
+            match self.msr(index) {
+                Ok(value) => msrs.push((index, value)),
+                Err(ref err) if is_unsupported_msr(err) => {}
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(CoreSnapshot {
+            regs: self.regs()?,
+            sregs: self.sregs()?,
+            fpu: self.fpu()?,
+            events,
+            debug_regs,
+            state: self.state()?,
+            msrs,
+        })
+    }
+}
+
+fn is_unsupported_msr(err: &Error) -> bool {
+    match *err.kind() {
+        ErrorKind::UnsupportedMsrError(_) => true,
+        _ => false,
+    }
+}
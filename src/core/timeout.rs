@@ -0,0 +1,104 @@
+use super::kick::{self, KICK_SIGNAL};
+use super::{Core, Pause};
+use error::*;
+use kvm_sys as kvm;
+use nix::errno::Errno;
+use nix::libc;
+use std::cell::RefCell;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::time::Duration;
+
+thread_local! {
+    /// The timer of the current thread, created the first time the
+    /// thread runs a core with a timeout.
+    static TIMER: RefCell<Option<Timer>> = RefCell::new(None);
+}
+
+/// A POSIX timer that sends [`KICK_SIGNAL`] to the thread that created
+/// it, and to no other thread.
+struct Timer(libc::timer_t);
+
+impl Timer {
+    fn new() -> Result<Timer> {
+        let mut event: libc::sigevent = unsafe { ::std::mem::zeroed() };
+        event.sigev_notify = libc::SIGEV_THREAD_ID;
+        event.sigev_signo = KICK_SIGNAL as libc::c_int;
+        event.sigev_notify_thread_id = unsafe { libc::syscall(libc::SYS_gettid) } as libc::c_int;
+
+        let mut timer: libc::timer_t = ptr::null_mut();
+        if unsafe { libc::timer_create(libc::CLOCK_MONOTONIC, &mut event, &mut timer) } < 0 {
+            return Err(::nix::Error::last()).chain_err(|| ErrorKind::CoreApiError("timer_create"));
+        }
+        Ok(Timer(timer))
+    }
+
+    /// Arms the timer to expire once, after the given duration.
+    fn arm(&self, dur: Duration) -> Result<()> {
+        // A zero expiration disarms the timer, so it is at least a
+        // nanosecond.
+        let dur = dur.max(Duration::new(0, 1));
+        let mut spec: libc::itimerspec = unsafe { ::std::mem::zeroed() };
+        spec.it_value.tv_sec = dur.as_secs() as libc::time_t;
+        spec.it_value.tv_nsec = dur.subsec_nanos() as libc::c_long;
+        if unsafe { libc::timer_settime(self.0, 0, &spec, ptr::null_mut()) } < 0 {
+            return Err(::nix::Error::last()).chain_err(|| ErrorKind::CoreApiError("timer_settime"));
+        }
+        Ok(())
+    }
+
+    /// Disarms the timer, returning whether or not it had expired.
+    fn disarm(&self) -> bool {
+        let spec: libc::itimerspec = unsafe { ::std::mem::zeroed() };
+        let mut old: libc::itimerspec = unsafe { ::std::mem::zeroed() };
+        unsafe { libc::timer_settime(self.0, 0, &spec, &mut old) };
+        old.it_value.tv_sec == 0 && old.it_value.tv_nsec == 0
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        unsafe { libc::timer_delete(self.0) };
+    }
+}
+
+impl Core {
+    /// Runs the core for at most the given duration.  This returns the
+    /// pause of the core if it stopped on its own, or `None` if the
+    /// timeout expired first.
+    ///
+    /// The calling thread has a timer of its own, which is created the
+    /// first time this is called on it, and reused afterwards.  The
+    /// timer is armed for the duration, and once it expires, it sends
+    /// [`KICK_SIGNAL`] to the calling thread only, which makes the core
+    /// leave the guest; see [`KICK_SIGNAL`] for how the signal is kept
+    /// from reaching any handler.  The timer is disarmed before this
+    /// returns, and a signal it sent after the core left is discarded.
+    pub fn run_timeout(&mut self, dur: Duration) -> Result<Option<Pause>> {
+        TIMER.with(|timer| {
+            let mut timer = timer.borrow_mut();
+            if timer.is_none() {
+                *timer = Some(Timer::new()?);
+            }
+            let timer = timer.as_ref().unwrap();
+
+            let section = self.7.enter(self.as_raw_fd())?;
+            timer.arm(dur)?;
+            let result = unsafe { kvm::kvm_run(self.as_raw_fd()) };
+            let fired = timer.disarm();
+            drop(section);
+            if fired {
+                kick::discard_kick();
+            }
+
+            match result {
+                Ok(_) => {
+                    let run = unsafe { &*self.1 };
+                    Ok(Some(Pause::from(run.exit_reason, &run.exit)))
+                }
+                Err(::nix::Error::Sys(Errno::EINTR)) if fired => Ok(None),
+                Err(err) => Err(err).chain_err(|| ErrorKind::CoreApiError("kvm_run")),
+            }
+        })
+    }
+}
@@ -0,0 +1,89 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use machine::Machine;
+use std::cmp;
+use std::os::unix::io::AsRawFd;
+
+/// The size of a guest page.  A guest virtual range is translated a
+/// page at a time, as contiguous virtual pages need not be contiguous
+/// physically.
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The result of translating a guest virtual address through the page
+/// tables of a core.  See [`Core::translate`].
+pub struct Translation {
+    /// The guest physical address the virtual address maps to.  This is
+    /// only meaningful if `valid` is true.
+    pub physical_address: u64,
+    /// Whether or not the virtual address is mapped.
+    pub valid: bool,
+    /// Whether or not the mapping is writeable.
+    pub writeable: bool,
+    /// Whether or not the mapping is accessible from user mode.
+    pub usermode: bool,
+}
+
+impl Core {
+    /// Translates a guest virtual address into a guest physical
+    /// address, using the current paging state of the core (e.g. `CR3`
+    /// on x86).  Unlike the translation of guest physical addresses
+    /// through the regions of the machine, this follows the guest's own
+    /// page tables.
+    ///
+    /// The result is only meaningful while the paging state of the
+    /// guest is stable; once the core is run again, the guest may have
+    /// switched address spaces, or changed the mapping.  This is only
+    /// supported on x86.
+    pub fn translate(&self, gva: u64) -> Result<Translation> {
+        let mut translation: kvm::Translation = unsafe { ::std::mem::zeroed() };
+        translation.linear_address = gva;
+        unsafe { kvm::kvm_translate(self.as_raw_fd(), &mut translation as *mut _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_translate"))?;
+
+        Ok(Translation {
+            physical_address: translation.physical_address,
+            valid: translation.valid != 0,
+            writeable: translation.writeable != 0,
+            usermode: translation.usermode != 0,
+        })
+    }
+
+    /// Reads `len` bytes of guest memory at the current instruction
+    /// pointer of the core, e.g. to feed to a disassembler.  The
+    /// address is the linear address of `CS:RIP`, and is resolved
+    /// through the guest's current paging with [`Core::translate`], a
+    /// page at a time; the bytes are then read through
+    /// [`Machine::read_guest`], from the machine the core belongs to.
+    ///
+    /// This errors with [`ErrorKind::UnmappedGuestVirtualAddressError`]
+    /// if a page of the range is not mapped by the guest, and as
+    /// [`Machine::read_guest`] does if the guest maps it to memory that
+    /// no region covers (e.g. MMIO).  This is only supported on x86.
+    ///
+    /// # Safety
+    /// See [`Machine::read_guest`].
+    pub unsafe fn current_instruction_bytes(
+        &self,
+        machine: &Machine,
+        len: usize,
+    ) -> Result<Vec<u8>> {
+        let linear = self.sregs()?.cs.base.wrapping_add(self.rip()?);
+        let mut bytes = vec![0u8; len];
+        let mut offset = 0;
+        while offset < len {
+            let gva = linear.wrapping_add(offset as u64);
+            let translation = self.translate(gva)?;
+            if !translation.valid {
+                return Err(ErrorKind::UnmappedGuestVirtualAddressError(gva).into());
+            }
+
+            let chunk = cmp::min(len - offset, (PAGE_SIZE - gva % PAGE_SIZE) as usize);
+            machine.read_guest(translation.physical_address, &mut bytes[offset..offset + chunk])?;
+            offset += chunk;
+        }
+
+        Ok(bytes)
+    }
+}
@@ -0,0 +1,73 @@
+use super::Core;
+use error::*;
+use kvm_sys as kvm;
+use machine::Capability;
+use std::os::unix::io::AsRawFd;
+
+/// How far the kernel lets the frequency of the guest's TSC stray from
+/// the host's without scaling it, in parts per million.  This matches
+/// the kernel's default `tsc_tolerance_ppm`.
+const TSC_TOLERANCE_PPM: u64 = 250;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What the host supports for the TSC frequency of the core.  This is
+/// queried the first time the frequency is set, before it is changed.
+pub(super) struct TscLimits {
+    /// Whether the host can scale the TSC, per
+    /// [`Capability::TscControl`].
+    scaling: bool,
+    /// The frequency of the host's TSC, in kHz.
+    host_khz: u32,
+}
+
+impl Core {
+    /// Retrieves the frequency of the core's TSC, in kHz.  This
+    /// requires the [`Capability::GetTscKhz`] capability.
+    pub fn tsc_khz(&self) -> Result<u32> {
+        self.assert_extension(Capability::GetTscKhz)?;
+        unsafe { kvm::kvm_get_tsc_khz(self.as_raw_fd()) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_get_tsc_khz"))
+            .map(|khz| khz as u32)
+    }
+
+    /// Sets the frequency of the core's TSC, in kHz.  This is needed
+    /// when migrating a guest to a host with a different TSC frequency,
+    /// so that time in the guest keeps passing at the same rate.
+    ///
+    /// If the host cannot scale the TSC (see
+    /// [`Capability::TscControl`]), the kernel only accepts frequencies
+    /// within 250 parts per million of the host's own, and otherwise
+    /// either fails or silently lets the guest's clock drift; this
+    /// errors with [`ErrorKind::TscScalingUnsupportedError`] instead.
+    /// With scaling, the kernel checks the frequency against the limits
+    /// of the hardware.  The host's frequency and capability are
+    /// queried on the first call, and kept for later ones.
+    pub fn set_tsc_khz(&mut self, khz: u32) -> Result<()> {
+        let limits = self.tsc_limits()?;
+        if !limits.scaling {
+            let host = limits.host_khz as u64;
+            let tolerance = host * TSC_TOLERANCE_PPM / 1_000_000;
+            let max = (host + tolerance) as u32;
+            if (khz as u64) < host - tolerance || khz > max {
+                return Err(ErrorKind::TscScalingUnsupportedError(khz, max).into());
+            }
+        }
+
+        unsafe { kvm::kvm_set_tsc_khz(self.as_raw_fd(), khz as _) }
+            .chain_err(|| ErrorKind::CoreApiError("kvm_set_tsc_khz"))?;
+        Ok(())
+    }
+
+    fn tsc_limits(&self) -> Result<TscLimits> {
+        if let Some(limits) = self.4.get() {
+            return Ok(limits);
+        }
+
+        let limits = TscLimits {
+            scaling: self.extension(Capability::TscControl)? != 0,
+            host_khz: self.tsc_khz()?,
+        };
+        self.4.set(Some(limits));
+        Ok(limits)
+    }
+}
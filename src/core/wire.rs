@@ -0,0 +1,304 @@
+//! A binary format for the register state of a core, for migration.
+//! Unlike the raw structures, the layout of this format does not depend
+//! on the kernel headers the library was built against.
+//!
+//! Every value starts with a six-byte header: the format version (one
+//! byte, currently `1`), the kind of value (one byte; `1` for
+//! registers, `2` for special registers, and `3` for the floating point
+//! state), and the length of the payload in bytes (four bytes).  The
+//! payload follows, as the fields of the structure in declaration
+//! order, each in little-endian byte order.  Segments are encoded as
+//! their base, limit, and selector, followed by the type, present, DPL,
+//! DB, S, L, G, AVL, and unusable bytes; descriptor tables as their
+//! base and limit.  Padding is not encoded.
+
+use byteorder::{ByteOrder, LittleEndian};
+use error::*;
+use kvm_sys as kvm;
+
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 6;
+const KIND_REGS: u8 = 1;
+const KIND_SREGS: u8 = 2;
+const KIND_FPU: u8 = 3;
+
+/// A structure that can be converted to and from the migration format.
+/// See the module documentation for the layout.
+///
+/// # Example
+/// ```rust
+/// # extern crate kvm;
+/// # extern crate kvm_sys;
+/// # use kvm::core::WireFormat;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let mut regs: kvm_sys::Regs = unsafe { ::std::mem::zeroed() };
+/// regs.rip = 0xfff0;
+/// regs.rflags = 0x2;
+///
+/// let bytes = regs.to_bytes();
+/// assert_eq!(&bytes[..6], &[1, 1, 144, 0, 0, 0]);
+/// let decoded = kvm_sys::Regs::from_bytes(&bytes)?;
+/// assert_eq!(decoded.rip, 0xfff0);
+/// assert!(kvm_sys::Sregs::from_bytes(&bytes).is_err());
+/// #     Ok(())
+/// # }
+/// ```
+pub trait WireFormat: Sized {
+    /// Encodes the value.
+    fn to_bytes(&self) -> Vec<u8>;
+
+    /// Decodes a value.  This errors if the version, kind, or length in
+    /// the header don't match, or if the data is truncated.
+    fn from_bytes(bytes: &[u8]) -> Result<Self>;
+}
+
+impl WireFormat for kvm::Regs {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(KIND_REGS);
+        for value in &[
+            self.rax, self.rbx, self.rcx, self.rdx, self.rsi, self.rdi, self.rsp, self.rbp,
+            self.r8, self.r9, self.r10, self.r11, self.r12, self.r13, self.r14, self.r15,
+            self.rip, self.rflags,
+        ] {
+            writer.u64(*value);
+        }
+        writer.finish()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<kvm::Regs> {
+        let mut reader = Reader::new(bytes, KIND_REGS)?;
+        let mut regs: kvm::Regs = unsafe { ::std::mem::zeroed() };
+        for value in &mut [
+            &mut regs.rax, &mut regs.rbx, &mut regs.rcx, &mut regs.rdx, &mut regs.rsi,
+            &mut regs.rdi, &mut regs.rsp, &mut regs.rbp, &mut regs.r8, &mut regs.r9,
+            &mut regs.r10, &mut regs.r11, &mut regs.r12, &mut regs.r13, &mut regs.r14,
+            &mut regs.r15, &mut regs.rip, &mut regs.rflags,
+        ] {
+            **value = reader.u64()?;
+        }
+        reader.finish()?;
+        Ok(regs)
+    }
+}
+
+impl WireFormat for kvm::Sregs {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(KIND_SREGS);
+        for segment in &[
+            &self.cs, &self.ds, &self.es, &self.fs, &self.gs, &self.ss, &self.tr, &self.ldt,
+        ] {
+            writer.segment(segment);
+        }
+        writer.dtable(&self.gdt);
+        writer.dtable(&self.idt);
+        for value in &[
+            self.cr0, self.cr2, self.cr3, self.cr4, self.cr8, self.efer, self.apic_base,
+        ] {
+            writer.u64(*value);
+        }
+        for value in &self.interrupt_bitmap {
+            writer.u64(*value);
+        }
+        writer.finish()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<kvm::Sregs> {
+        let mut reader = Reader::new(bytes, KIND_SREGS)?;
+        let mut sregs: kvm::Sregs = unsafe { ::std::mem::zeroed() };
+        for segment in &mut [
+            &mut sregs.cs, &mut sregs.ds, &mut sregs.es, &mut sregs.fs, &mut sregs.gs,
+            &mut sregs.ss, &mut sregs.tr, &mut sregs.ldt,
+        ] {
+            reader.segment(segment)?;
+        }
+        reader.dtable(&mut sregs.gdt)?;
+        reader.dtable(&mut sregs.idt)?;
+        for value in &mut [
+            &mut sregs.cr0, &mut sregs.cr2, &mut sregs.cr3, &mut sregs.cr4, &mut sregs.cr8,
+            &mut sregs.efer, &mut sregs.apic_base,
+        ] {
+            **value = reader.u64()?;
+        }
+        for value in &mut sregs.interrupt_bitmap {
+            *value = reader.u64()?;
+        }
+        reader.finish()?;
+        Ok(sregs)
+    }
+}
+
+impl WireFormat for kvm::Fpu {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = Writer::new(KIND_FPU);
+        for register in &self.fpr {
+            writer.bytes(register);
+        }
+        writer.u16(self.fcw);
+        writer.u16(self.fsw);
+        writer.u8(self.ftwx);
+        writer.u16(self.last_opcode);
+        writer.u64(self.last_ip);
+        writer.u64(self.last_dp);
+        for register in &self.xmm {
+            writer.bytes(register);
+        }
+        writer.u32(self.mxcsr);
+        writer.finish()
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<kvm::Fpu> {
+        let mut reader = Reader::new(bytes, KIND_FPU)?;
+        let mut fpu: kvm::Fpu = unsafe { ::std::mem::zeroed() };
+        for register in &mut fpu.fpr {
+            register.copy_from_slice(reader.bytes(16)?);
+        }
+        fpu.fcw = reader.u16()?;
+        fpu.fsw = reader.u16()?;
+        fpu.ftwx = reader.u8()?;
+        fpu.last_opcode = reader.u16()?;
+        fpu.last_ip = reader.u64()?;
+        fpu.last_dp = reader.u64()?;
+        for register in &mut fpu.xmm {
+            register.copy_from_slice(reader.bytes(16)?);
+        }
+        fpu.mxcsr = reader.u32()?;
+        reader.finish()?;
+        Ok(fpu)
+    }
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn new(kind: u8) -> Writer {
+        Writer(vec![VERSION, kind, 0, 0, 0, 0])
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        let mut buf = [0u8; 2];
+        LittleEndian::write_u16(&mut buf, value);
+        self.0.extend_from_slice(&buf);
+    }
+
+    fn u32(&mut self, value: u32) {
+        let mut buf = [0u8; 4];
+        LittleEndian::write_u32(&mut buf, value);
+        self.0.extend_from_slice(&buf);
+    }
+
+    fn u64(&mut self, value: u64) {
+        let mut buf = [0u8; 8];
+        LittleEndian::write_u64(&mut buf, value);
+        self.0.extend_from_slice(&buf);
+    }
+
+    fn bytes(&mut self, value: &[u8]) {
+        self.0.extend_from_slice(value);
+    }
+
+    fn segment(&mut self, segment: &kvm::Segment) {
+        self.u64(segment.base);
+        self.u32(segment.limit);
+        self.u16(segment.selector);
+        for value in &[
+            segment.type_, segment.present, segment.dpl, segment.db, segment.s, segment.l,
+            segment.g, segment.avl, segment.unusable,
+        ] {
+            self.u8(*value);
+        }
+    }
+
+    fn dtable(&mut self, dtable: &kvm::Dtable) {
+        self.u64(dtable.base);
+        self.u16(dtable.limit);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let len = (self.0.len() - HEADER_SIZE) as u32;
+        LittleEndian::write_u32(&mut self.0[2..HEADER_SIZE], len);
+        self.0
+    }
+}
+
+struct Reader<'b>(&'b [u8]);
+
+impl<'b> Reader<'b> {
+    fn new(bytes: &'b [u8], kind: u8) -> Result<Reader<'b>> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(ErrorKind::InvalidWireFormatError("truncated header").into());
+        }
+
+        if bytes[0] != VERSION {
+            return Err(ErrorKind::InvalidWireFormatError("unknown version").into());
+        }
+
+        if bytes[1] != kind {
+            return Err(ErrorKind::InvalidWireFormatError("unexpected kind").into());
+        }
+
+        let len = LittleEndian::read_u32(&bytes[2..HEADER_SIZE]) as usize;
+        if bytes.len() - HEADER_SIZE != len {
+            return Err(ErrorKind::InvalidWireFormatError("length mismatch").into());
+        }
+
+        Ok(Reader(&bytes[HEADER_SIZE..]))
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'b [u8]> {
+        if self.0.len() < len {
+            return Err(ErrorKind::InvalidWireFormatError("truncated payload").into());
+        }
+
+        let (value, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(value)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        self.bytes(1).map(|bytes| bytes[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.bytes(2).map(LittleEndian::read_u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.bytes(4).map(LittleEndian::read_u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.bytes(8).map(LittleEndian::read_u64)
+    }
+
+    fn segment(&mut self, segment: &mut kvm::Segment) -> Result<()> {
+        segment.base = self.u64()?;
+        segment.limit = self.u32()?;
+        segment.selector = self.u16()?;
+        for value in &mut [
+            &mut segment.type_, &mut segment.present, &mut segment.dpl, &mut segment.db,
+            &mut segment.s, &mut segment.l, &mut segment.g, &mut segment.avl,
+            &mut segment.unusable,
+        ] {
+            **value = self.u8()?;
+        }
+        Ok(())
+    }
+
+    fn dtable(&mut self, dtable: &mut kvm::Dtable) -> Result<()> {
+        dtable.base = self.u64()?;
+        dtable.limit = self.u16()?;
+        Ok(())
+    }
+
+    fn finish(self) -> Result<()> {
+        if self.0.is_empty() {
+            Ok(())
+        } else {
+            Err(ErrorKind::InvalidWireFormatError("trailing data").into())
+        }
+    }
+}
@@ -0,0 +1,332 @@
+use super::Device;
+use core::{DataMut, Direction, IoAddress};
+use error::*;
+use kvm_sys as kvm;
+use std::collections::BTreeMap;
+use std::ops::Bound::{Excluded, Unbounded};
+use std::u64;
+
+/// An access dispatched by a [`DeviceBus`], as given to its trace hook.
+/// See [`DeviceBus::set_trace`].
+#[derive(Debug, Copy, Clone)]
+pub struct IoTrace<'d> {
+    /// The address being accessed.
+    pub address: IoAddress,
+    /// Whether the guest is reading or writing.  For MMIO, this is
+    /// [`Direction::Out`] for writes.
+    pub direction: Direction,
+    /// The data of the access: the data being written, or the data the
+    /// device provided for a read.  Its length is the size of the
+    /// access.  For reads no device claimed, this is left as the run
+    /// structure had it.
+    pub data: &'d [u8],
+    /// Whether or not a device on the bus owns the address.
+    pub claimed: bool,
+}
+
+/// A set of devices, keyed by the range of addresses they own.  Port IO
+/// and MMIO devices live on the same bus, as [`IoAddress`] keeps the two
+/// address spaces apart.
+#[derive(Default)]
+pub struct DeviceBus {
+    devices: BTreeMap<(IoAddress, u64), Box<dyn Device>>,
+    trace: Option<Box<dyn FnMut(IoTrace)>>,
+}
+
+impl DeviceBus {
+    /// Creates an empty bus.
+    pub fn new() -> DeviceBus {
+        DeviceBus::default()
+    }
+
+    /// Registers a device for the `len` addresses starting at the given
+    /// address.  The device is given offsets relative to this address.
+    /// This errors with [`ErrorKind::DeviceAddressConflictError`] if the
+    /// range overlaps the range of a device already on the bus, naming
+    /// that device's range; ranges that only touch are fine.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::core::IoAddress;
+    /// # use kvm::devices::{DeviceBus, Serial};
+    /// # use kvm::ErrorKind;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let mut bus = DeviceBus::new();
+    /// bus.insert(IoAddress::Port(0x3f8), 8, Box::new(Serial::new(Vec::new())))?;
+    /// // Adjacent ranges, and the same range in the other address space.
+    /// bus.insert(IoAddress::Port(0x3f0), 8, Box::new(Serial::new(Vec::new())))?;
+    /// bus.insert(IoAddress::Port(0x400), 8, Box::new(Serial::new(Vec::new())))?;
+    /// bus.insert(IoAddress::Memory(0x3f8), 8, Box::new(Serial::new(Vec::new())))?;
+    ///
+    /// for &(port, len) in &[(0x3f8, 8), (0x3fc, 2), (0x3f4, 8), (0x3ff, 1), (0x3e8, 16)] {
+    ///     match bus.insert(IoAddress::Port(port), len, Box::new(Serial::new(Vec::new()))) {
+    ///         Err(ref err) => match *err.kind() {
+    ///             ErrorKind::DeviceAddressConflictError(_, _) => {}
+    ///             _ => panic!("unexpected error: {}", err),
+    ///         },
+    ///         Ok(_) => panic!("registered {:#x} over another device", port),
+    ///     }
+    /// }
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn insert(&mut self, address: IoAddress, len: u64, device: Box<dyn Device>) -> Result<()> {
+        if let Some((base, existing)) = self.conflict(address, len) {
+            return Err(ErrorKind::DeviceAddressConflictError(base, existing).into());
+        }
+        self.devices.insert((address, len), device);
+        Ok(())
+    }
+
+    /// The range of the device that overlaps the given range, if any.
+    /// Devices on the bus never overlap each other, so only the devices
+    /// on either side of the address need to be checked.
+    fn conflict(&self, address: IoAddress, len: u64) -> Option<(IoAddress, u64)> {
+        let before = self
+            .devices
+            .range(..=(address, u64::MAX))
+            .next_back()
+            .map(|(key, _)| *key)
+            .filter(|&(base, existing)| {
+                address
+                    .offset_from(base)
+                    .map_or(false, |offset| offset < existing)
+            });
+        let after = || {
+            self.devices
+                .range((Excluded((address, u64::MAX)), Unbounded))
+                .next()
+                .map(|(key, _)| *key)
+                .filter(|&(base, _)| base.offset_from(address).map_or(false, |offset| offset < len))
+        };
+        before.or_else(after)
+    }
+
+    /// Removes the device registered at the given address, returning it.
+    pub fn remove(&mut self, address: IoAddress) -> Option<Box<dyn Device>> {
+        let key = self
+            .devices
+            .range((address, 0)..=(address, u64::MAX))
+            .next()
+            .map(|(key, _)| *key);
+        key.and_then(|key| self.devices.remove(&key))
+    }
+
+    /// Finds the device that owns the given address, along with the
+    /// offset of the address within the device's range.
+    pub fn find(&mut self, address: IoAddress) -> Option<(&mut Box<dyn Device>, u64)> {
+        find(&mut self.devices, address)
+    }
+
+    /// Installs a hook that is called for every port IO and MMIO access
+    /// that goes through [`DeviceBus::dispatch`], after the device (if
+    /// any) has handled it.  A string instruction is traced once for
+    /// every value it transfers.  This replaces any previous hook.
+    ///
+    /// This is meant for debugging device models and guest drivers.
+    /// Without a hook, dispatching only checks that none is installed.
+    /// With one, the hook runs on the thread of the core, before the
+    /// core can run again.  A slow hook, e.g. one that writes every
+    /// access to a log, therefore slows down the guest on every IO
+    /// exit.
+    ///
+    /// # Example
+    /// ```rust
+    /// # extern crate kvm;
+    /// # extern crate kvm_sys;
+    /// # use kvm::core::{Direction, IoAddress};
+    /// # use kvm::devices::{Device, DeviceBus, IoTrace};
+    /// # use kvm::machine::MachineKind;
+    /// # use kvm::system::System;
+    /// # use std::cell::RefCell;
+    /// # use std::mem::zeroed;
+    /// # use std::rc::Rc;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// struct Constant(u8);
+    ///
+    /// impl Device for Constant {
+    ///     fn read(&mut self, _: u64, data: &mut [u8]) {
+    ///         for byte in data.iter_mut() {
+    ///             *byte = self.0;
+    ///         }
+    ///     }
+    ///
+    ///     fn write(&mut self, _: u64, _: &[u8]) {}
+    /// }
+    ///
+    /// let mut bus = DeviceBus::new();
+    /// bus.insert(IoAddress::Port(0x80), 1, Box::new(Constant(0xaa)))?;
+    /// bus.insert(IoAddress::Memory(0xd000_0000), 0x1000, Box::new(Constant(0x55)))?;
+    ///
+    /// let log = Rc::new(RefCell::new(Vec::new()));
+    /// let hook = log.clone();
+    /// bus.set_trace(Box::new(move |trace: IoTrace| {
+    ///     let data = trace.data.to_vec();
+    ///     hook.borrow_mut()
+    ///         .push((trace.address, trace.direction, data, trace.claimed));
+    /// }));
+    ///
+    /// let system = match System::new() {
+    ///     Ok(system) => system,
+    ///     Err(_) => return Ok(()),
+    /// };
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// let mut core = machine.create_core(0)?;
+    ///
+    /// // A `rep insb` of two values from a claimed port.
+    /// let mut raw: kvm_sys::Exit = unsafe { zeroed() };
+    /// unsafe {
+    ///     raw.io.direction = kvm_sys::KVM_EXIT_IO_IN;
+    ///     raw.io.size = 1;
+    ///     raw.io.port = 0x80;
+    ///     raw.io.count = 2;
+    ///     raw.io.data_offset = 0x1000;
+    /// }
+    /// core.data_mut().set_exit_reason(kvm_sys::KVM_EXIT_IO);
+    /// core.data_mut().set_raw_exit(raw);
+    /// assert!(bus.dispatch(core.data_mut())?);
+    /// assert_eq!(core.data().io().unwrap().data(), &[0xaa, 0xaa]);
+    ///
+    /// // An `outb` to a port no device owns.
+    /// unsafe {
+    ///     raw.io.direction = kvm_sys::KVM_EXIT_IO_OUT;
+    ///     raw.io.port = 0x81;
+    ///     raw.io.count = 1;
+    /// }
+    /// core.data_mut().set_raw_exit(raw);
+    /// core.data_mut().io_mut().unwrap().data_mut()[0] = 0x12;
+    /// assert!(!bus.dispatch(core.data_mut())?);
+    ///
+    /// // A claimed MMIO read, and an MMIO write no device owns.
+    /// let mut raw: kvm_sys::Exit = unsafe { zeroed() };
+    /// unsafe {
+    ///     raw.mmio.phys_addr = 0xd000_0010;
+    ///     raw.mmio.len = 4;
+    /// }
+    /// core.data_mut().set_exit_reason(kvm_sys::KVM_EXIT_MMIO);
+    /// core.data_mut().set_raw_exit(raw);
+    /// assert!(bus.dispatch(core.data_mut())?);
+    ///
+    /// unsafe {
+    ///     raw.mmio.phys_addr = 0xe000_0000;
+    ///     raw.mmio.data = [1, 2, 0, 0, 0, 0, 0, 0];
+    ///     raw.mmio.len = 2;
+    ///     raw.mmio.is_write = 1;
+    /// }
+    /// core.data_mut().set_raw_exit(raw);
+    /// assert!(!bus.dispatch(core.data_mut())?);
+    ///
+    /// // The string instruction is traced once for each of its values.
+    /// assert_eq!(
+    ///     *log.borrow(),
+    ///     vec![
+    ///         (IoAddress::Port(0x80), Direction::In, vec![0xaa], true),
+    ///         (IoAddress::Port(0x80), Direction::In, vec![0xaa], true),
+    ///         (IoAddress::Port(0x81), Direction::Out, vec![0x12], false),
+    ///         (IoAddress::Memory(0xd000_0010), Direction::In, vec![0x55; 4], true),
+    ///         (IoAddress::Memory(0xe000_0000), Direction::Out, vec![1, 2], false),
+    ///     ]
+    /// );
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn set_trace(&mut self, trace: Box<dyn FnMut(IoTrace)>) {
+        self.trace = Some(trace);
+    }
+
+    /// Removes the hook installed by [`DeviceBus::set_trace`], if any.
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    /// Routes the current exit of a core to the device that owns the
+    /// address being accessed.  For reads, the data the device provides
+    /// is written back into the run structure, so the core can be run
+    /// again immediately.
+    ///
+    /// This returns `false` if the exit is not a port IO or MMIO exit,
+    /// or if no device owns the address; in that case, the exit is left
+    /// untouched.  This errors if the data for a port IO exit lies
+    /// outside of the mapping of the core.
+    pub fn dispatch(&mut self, mut data: DataMut) -> Result<bool> {
+        match data.exit_reason() {
+            kvm::KVM_EXIT_IO => {
+                let mut io = data
+                    .io_mut()
+                    .ok_or_else(|| Error::from(ErrorKind::CoreApiError("kvm_run")))?;
+                let size = io.size() as usize;
+                let direction = io.direction();
+                let address = IoAddress::Port(io.port());
+                let mut found = find(&mut self.devices, address);
+                let claimed = found.is_some();
+
+                // String instructions transfer several values through the
+                // same port, one after another.
+                for chunk in io.data_mut().chunks_mut(size.max(1)) {
+                    if let Some((ref mut device, offset)) = found {
+                        match direction {
+                            Direction::In => device.read(offset, chunk),
+                            Direction::Out => device.write(offset, chunk),
+                        }
+                    }
+                    if let Some(ref mut trace) = self.trace {
+                        trace(IoTrace {
+                            address,
+                            direction,
+                            data: chunk,
+                            claimed,
+                        });
+                    }
+                }
+
+                Ok(claimed)
+            }
+
+            kvm::KVM_EXIT_MMIO => {
+                let mmio = unsafe { &mut data.as_mut().exit.mmio };
+                let len = (mmio.len as usize).min(mmio.data.len());
+                let address = IoAddress::Memory(mmio.phys_addr);
+                let write = mmio.is_write != 0;
+                let claimed = match find(&mut self.devices, address) {
+                    Some((device, offset)) => {
+                        if write {
+                            device.write(offset, &mmio.data[..len]);
+                        } else {
+                            device.read(offset, &mut mmio.data[..len]);
+                        }
+                        true
+                    }
+                    None => false,
+                };
+
+                if let Some(ref mut trace) = self.trace {
+                    trace(IoTrace {
+                        address,
+                        direction: if write { Direction::Out } else { Direction::In },
+                        data: &mmio.data[..len],
+                        claimed,
+                    });
+                }
+
+                Ok(claimed)
+            }
+
+            _ => Ok(false),
+        }
+    }
+}
+
+fn find(
+    devices: &mut BTreeMap<(IoAddress, u64), Box<dyn Device>>,
+    address: IoAddress,
+) -> Option<(&mut Box<dyn Device>, u64)> {
+    devices
+        .range_mut(..=(address, u64::MAX))
+        .next_back()
+        .and_then(|(&(base, len), device)| {
+            address
+                .offset_from(base)
+                .filter(|offset| *offset < len)
+                .map(|offset| (device, offset))
+        })
+}
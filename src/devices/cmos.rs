@@ -0,0 +1,204 @@
+use super::Device;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The port of the index register of the CMOS on a PC.  The data
+/// register follows it.
+pub const CMOS_PORT: u16 = 0x70;
+/// The number of ports a [`Cmos`] occupies.
+pub const CMOS_PORTS: u64 = 2;
+
+const SECONDS: u8 = 0x00;
+const MINUTES: u8 = 0x02;
+const HOURS: u8 = 0x04;
+const WEEKDAY: u8 = 0x06;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+const STATUS_A: u8 = 0x0a;
+const STATUS_B: u8 = 0x0b;
+const STATUS_C: u8 = 0x0c;
+const STATUS_D: u8 = 0x0d;
+const CENTURY: u8 = 0x32;
+
+// The divider is set for a 32.768 kHz base, with a 1024 Hz rate.
+const STATUS_A_DEFAULT: u8 = 0x26;
+// 24-hour mode, with the values in BCD.
+const STATUS_B_DEFAULT: u8 = 0x02;
+// The battery is good.
+const STATUS_D_VALID: u8 = 0x80;
+
+/// An emulated CMOS and real-time clock.  The guest selects a register
+/// by writing its index to the first port, and then reads or writes
+/// the register through the second.  The top bit of the index disables
+/// NMIs on real hardware, and is ignored.
+///
+/// The following registers are implemented:
+///
+/// - `0x00` to `0x09` and `0x32`, the clock, read from the host's
+///   clock on every access, in BCD and 24-hour mode.  Writes to them
+///   are ignored, so the guest cannot set the clock.
+/// - `0x0a` to `0x0d`, the status registers.  The clock never updates
+///   in the middle of an access, raises no interrupts, and always
+///   reports a good battery.  Writes to them are ignored.
+/// - `0x15` and `0x16`, the conventional memory size, and `0x17`,
+///   `0x18`, `0x30`, `0x31`, `0x34`, `0x35`, and `0x5b` to `0x5d`, the
+///   extended memory sizes.  These are set through
+///   [`Cmos::set_memory_size`].
+///
+/// Every other register is plain storage, initially zero.
+///
+/// # Example
+/// ```rust
+/// # use kvm::devices::{Cmos, Device};
+/// let mut cmos = Cmos::new();
+/// cmos.set_memory_size(128 << 20, 0);
+///
+/// let mut value = [0];
+/// cmos.write(0, &[0x35]);
+/// cmos.read(1, &mut value);
+/// assert_eq!(value[0], 0x07);
+///
+/// cmos.write(0, &[0x32]);
+/// cmos.read(1, &mut value);
+/// assert_eq!(value[0], 0x20);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cmos {
+    index: u8,
+    data: [u8; 128],
+}
+
+impl Cmos {
+    /// Creates a CMOS with no memory size set.
+    pub fn new() -> Cmos {
+        Cmos {
+            index: 0,
+            data: [0; 128],
+        }
+    }
+
+    /// Sets the memory size the firmware reads from the CMOS.  `low` is
+    /// the size of the memory below 4 GiB, in bytes, including the
+    /// first megabyte; `high` is the size of the memory above 4 GiB.
+    /// The conventional memory is always reported as 640 KiB.
+    pub fn set_memory_size(&mut self, low: u64, high: u64) {
+        // Kilobytes above 1 MiB, up to 64 MiB.
+        let extended = (low.saturating_sub(1 << 20) >> 10).min(0xfc00) as u16;
+        // 64 KiB blocks above 16 MiB.
+        let above_16m = (low.saturating_sub(16 << 20) >> 16).min(0xffff) as u16;
+        // 64 KiB blocks above 4 GiB.
+        let above_4g = (high >> 16).min(0xff_ffff) as u32;
+
+        self.set_word(0x15, 640);
+        self.set_word(0x17, extended);
+        self.set_word(0x30, extended);
+        self.set_word(0x34, above_16m);
+        self.data[0x5b] = above_4g as u8;
+        self.data[0x5c] = (above_4g >> 8) as u8;
+        self.data[0x5d] = (above_4g >> 16) as u8;
+    }
+
+    /// The value of the given register, as the guest would read it.
+    pub fn get(&self, index: u8) -> u8 {
+        let index = index & 0x7f;
+        match index {
+            SECONDS | MINUTES | HOURS | WEEKDAY | DAY | MONTH | YEAR | CENTURY => {
+                clock(index)
+            }
+            STATUS_A => STATUS_A_DEFAULT,
+            STATUS_B => STATUS_B_DEFAULT,
+            STATUS_C => 0,
+            STATUS_D => STATUS_D_VALID,
+            _ => self.data[index as usize],
+        }
+    }
+
+    /// Sets the given register.  Writes to the clock and status
+    /// registers are ignored.
+    pub fn set(&mut self, index: u8, value: u8) {
+        let index = index & 0x7f;
+        match index {
+            SECONDS..=STATUS_D | CENTURY => {}
+            _ => self.data[index as usize] = value,
+        }
+    }
+
+    fn set_word(&mut self, index: usize, value: u16) {
+        self.data[index] = value as u8;
+        self.data[index + 1] = (value >> 8) as u8;
+    }
+}
+
+impl Default for Cmos {
+    fn default() -> Cmos {
+        Cmos::new()
+    }
+}
+
+impl Device for Cmos {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = match offset + index as u64 {
+                0 => self.index,
+                1 => self.get(self.index),
+                _ => 0xff,
+            };
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            match offset + index as u64 {
+                0 => self.index = *byte & 0x7f,
+                1 => {
+                    let register = self.index;
+                    self.set(register, *byte)
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Reads the given clock register from the host's clock, in BCD.
+fn clock(index: u8) -> u8 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    let days = (now / 86400) as i64;
+    let seconds = now % 86400;
+    let (year, month, day) = civil_from_days(days);
+
+    let value = match index {
+        SECONDS => seconds % 60,
+        MINUTES => seconds / 60 % 60,
+        HOURS => seconds / 3600,
+        // The epoch was a Thursday; Sunday is one.
+        WEEKDAY => ((days + 4) % 7 + 1) as u64,
+        DAY => day as u64,
+        MONTH => month as u64,
+        YEAR => (year % 100) as u64,
+        _ => (year / 100) as u64,
+    };
+
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+/// Converts a number of days since the epoch to a year, month, and day,
+/// in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Shift the epoch to 0000-03-01, so that leap days fall at the end
+    // of each year, and split the days into 400-year eras.
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * month + 2) / 5 + 1) as u32;
+    let month = if month < 10 { month + 3 } else { month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
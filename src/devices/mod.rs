@@ -0,0 +1,24 @@
+//! Emulated devices, for handling the IO exits of a core in userspace.
+//! Each device claims a range of addresses on a [`DeviceBus`], which
+//! routes the IO and MMIO exits of a core to the device that owns the
+//! address being accessed.
+
+mod bus;
+mod cmos;
+mod serial;
+
+pub use self::bus::{DeviceBus, IoTrace};
+pub use self::cmos::{Cmos, CMOS_PORT, CMOS_PORTS};
+pub use self::serial::{Serial, COM1_PORT, SERIAL_PORTS};
+
+/// A device that handles guest accesses to a range of addresses.  The
+/// offsets given are relative to the start of the range the device was
+/// registered with.
+pub trait Device {
+    /// Handles a read from the guest.  The data must be filled in with
+    /// the value the guest reads; it is as long as the access.
+    fn read(&mut self, offset: u64, data: &mut [u8]);
+
+    /// Handles a write from the guest.
+    fn write(&mut self, offset: u64, data: &[u8]);
+}
@@ -0,0 +1,150 @@
+use super::Device;
+use std::io::Write;
+
+/// The port of the first serial port on a PC, `COM1`.
+pub const COM1_PORT: u16 = 0x3f8;
+/// The number of ports a [`Serial`] occupies.
+pub const SERIAL_PORTS: u64 = 8;
+
+const DATA: u64 = 0;
+const INTERRUPT_ENABLE: u64 = 1;
+const INTERRUPT_ID: u64 = 2;
+const LINE_CONTROL: u64 = 3;
+const MODEM_CONTROL: u64 = 4;
+const LINE_STATUS: u64 = 5;
+const MODEM_STATUS: u64 = 6;
+const SCRATCH: u64 = 7;
+
+const LINE_CONTROL_DLAB: u8 = 0x80;
+// The transmit holding register and the transmitter are both empty.
+const LINE_STATUS_IDLE: u8 = 0x60;
+// No interrupt is pending.
+const INTERRUPT_ID_NONE: u8 = 0x01;
+const FIFO_ENABLED: u8 = 0xc0;
+// Clear to send, data set ready, and carrier detect.
+const MODEM_STATUS_READY: u8 = 0xb0;
+
+/// An emulated 16550 UART, for use as a guest console.  Bytes the guest
+/// transmits are written to the sink; the guest never receives any.
+/// The transmitter is always empty, so the guest never waits on it.
+/// The UART does not raise interrupts, so the guest must poll it, as
+/// most early consoles do.
+///
+/// The UART occupies [`SERIAL_PORTS`] ports, starting at its base port;
+/// on a PC, the first serial port is at [`COM1_PORT`].  Errors from the
+/// sink are ignored, as the guest has no way to see them.
+///
+/// # Example
+/// ```rust
+/// # use kvm::core::IoAddress;
+/// # use kvm::devices::{Device, DeviceBus, Serial, COM1_PORT, SERIAL_PORTS};
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let mut serial = Serial::new(Vec::new());
+/// serial.write(0, b"H");
+/// serial.write(0, b"i");
+/// assert_eq!(serial.sink(), b"Hi");
+///
+/// let mut status = [0];
+/// serial.read(5, &mut status);
+/// assert_eq!(status[0] & 0x20, 0x20);
+///
+/// let mut bus = DeviceBus::new();
+/// bus.insert(IoAddress::Port(COM1_PORT), SERIAL_PORTS, Box::new(serial))?;
+/// #     Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Serial<W: Write> {
+    sink: W,
+    divisor: u16,
+    interrupt_enable: u8,
+    fifo_control: u8,
+    line_control: u8,
+    modem_control: u8,
+    scratch: u8,
+}
+
+impl<W: Write> Serial<W> {
+    /// Creates a UART that writes transmitted bytes to the given sink.
+    pub fn new(sink: W) -> Serial<W> {
+        Serial {
+            sink,
+            // 9600 baud.
+            divisor: 12,
+            interrupt_enable: 0,
+            fifo_control: 0,
+            line_control: 0x03,
+            modem_control: 0x08,
+            scratch: 0,
+        }
+    }
+
+    /// The sink that transmitted bytes are written to.
+    pub fn sink(&self) -> &W {
+        &self.sink
+    }
+
+    /// The sink that transmitted bytes are written to, mutably.
+    pub fn sink_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
+
+    /// Consumes the UART, returning the sink.
+    pub fn into_inner(self) -> W {
+        self.sink
+    }
+
+    fn dlab(&self) -> bool {
+        self.line_control & LINE_CONTROL_DLAB != 0
+    }
+
+    fn read_register(&mut self, offset: u64) -> u8 {
+        match offset {
+            DATA if self.dlab() => self.divisor as u8,
+            INTERRUPT_ENABLE if self.dlab() => (self.divisor >> 8) as u8,
+            // Nothing is ever received.
+            DATA => 0,
+            INTERRUPT_ENABLE => self.interrupt_enable,
+            INTERRUPT_ID => INTERRUPT_ID_NONE | (self.fifo_control & FIFO_ENABLED),
+            LINE_CONTROL => self.line_control,
+            MODEM_CONTROL => self.modem_control,
+            LINE_STATUS => LINE_STATUS_IDLE,
+            MODEM_STATUS => MODEM_STATUS_READY,
+            SCRATCH => self.scratch,
+            _ => 0,
+        }
+    }
+
+    fn write_register(&mut self, offset: u64, value: u8) {
+        match offset {
+            DATA if self.dlab() => self.divisor = (self.divisor & 0xff00) | value as u16,
+            INTERRUPT_ENABLE if self.dlab() => {
+                self.divisor = (self.divisor & 0x00ff) | (value as u16) << 8
+            }
+            DATA => {
+                let _ = self.sink.write_all(&[value]).and_then(|_| self.sink.flush());
+            }
+            INTERRUPT_ENABLE => self.interrupt_enable = value & 0x0f,
+            INTERRUPT_ID => self.fifo_control = if value & 0x01 != 0 { FIFO_ENABLED } else { 0 },
+            LINE_CONTROL => self.line_control = value,
+            MODEM_CONTROL => self.modem_control = value & 0x1f,
+            SCRATCH => self.scratch = value,
+            _ => {}
+        }
+    }
+}
+
+impl<W: Write> Device for Serial<W> {
+    fn read(&mut self, offset: u64, data: &mut [u8]) {
+        // The registers are a byte wide; wider accesses span several.
+        for (index, byte) in data.iter_mut().enumerate() {
+            *byte = self.read_register(offset + index as u64);
+        }
+    }
+
+    fn write(&mut self, offset: u64, data: &[u8]) {
+        for (index, byte) in data.iter().enumerate() {
+            self.write_register(offset + index as u64, *byte);
+        }
+    }
+}
@@ -1,4 +1,8 @@
 error_chain!{
+    foreign_links {
+        Nix(::nix::Error) #[doc = "An error from a system call, with its errno."];
+    }
+
     errors {
         UnavailableSystemError {
             description("unable to open kvm device")
@@ -16,6 +20,26 @@ error_chain!{
         CreateIrqFdError {}
         NotifyIrqFdError {}
 
+        ResampleIrqFdRequiredError {
+            description("the irqfd has no resample eventfd")
+            display("the irqfd has no resample eventfd; it must be created as a resampling irqfd")
+        }
+
+        CreateEventFdError {
+            description("an error occurred while creating an eventfd")
+            display("an error occurred while creating an eventfd")
+        }
+
+        ReadEventFdError {
+            description("an error occurred while reading from an eventfd")
+            display("an error occurred while reading from an eventfd")
+        }
+
+        WriteEventFdError {
+            description("an error occurred while writing to an eventfd")
+            display("an error occurred while writing to an eventfd")
+        }
+
         SystemApiError(req: &'static str) {
             description("an error occurred while trying to handle an api request")
             display("an error occurred while trying to handle api request `{}'", req)
@@ -31,6 +55,16 @@ error_chain!{
             display("an error occurred while trying to handle api request `{}'", req)
         }
 
+        CreateMachineError(kind: ::machine::MachineKind, reason: &'static str) {
+            description("the machine could not be created")
+            display("the machine {:?} could not be created: {}", kind, reason)
+        }
+
+        CoreThreadError {
+            description("the thread running the core stopped unexpectedly")
+            display("the thread running the core stopped unexpectedly")
+        }
+
         MapCoreError {
             description("an error occurred while attempting to map the core into memory")
             display("an error occurred while attempting to map the core into memory")
@@ -41,6 +75,126 @@ error_chain!{
             display("the extension {:?} was missing from the system", cap)
         }
 
+        MapSlabError {
+            description("an error occurred while attempting to map a slab into memory")
+            display("an error occurred while attempting to map a slab into memory")
+        }
+
+        SlabBoundsError(offset: u64, len: usize) {
+            description("an access was made outside of the bounds of a slab")
+            display("an access of {} bytes at offset {:#x} was outside of the bounds of the slab", len, offset)
+        }
+
+        ReadOnlySlabError {
+            description("a write was made to a read-only slab")
+            display("a write was made to a read-only slab")
+        }
+
+        InvalidElfError(reason: &'static str) {
+            description("the given ELF file could not be loaded")
+            display("the given ELF file could not be loaded: {}", reason)
+        }
+
+        InvalidBzImageError(reason: &'static str) {
+            description("the given bzImage could not be loaded")
+            display("the given bzImage could not be loaded: {}", reason)
+        }
+
+        InvalidBootParamsError(reason: &'static str) {
+            description("the zero page could not be built")
+            display("the zero page could not be built: {}", reason)
+        }
+
+        InvalidPageTablesError(reason: &'static str) {
+            description("the page tables could not be set up")
+            display("the page tables could not be set up: {}", reason)
+        }
+
+        CapabilityScopeError(cap: ::machine::Capability, scope: ::machine::CapabilityScope) {
+            description("a capability was enabled in the wrong scope")
+            display("the capability {:?} cannot be enabled on a {:?}", cap, scope)
+        }
+
+        TscScalingUnsupportedError(requested: u32, max: u32) {
+            description("the requested TSC frequency is not supported by the host")
+            display("the requested TSC frequency of {} kHz is not supported by the host (at most {} kHz)", requested, max)
+        }
+
+        UnmappedGuestAddressError(gpa: u64) {
+            description("a guest address was not backed by any region")
+            display("the guest address {:#x} was not backed by any region", gpa)
+        }
+
+        UnmappedGuestVirtualAddressError(gva: u64) {
+            description("a guest virtual address was not mapped by the guest")
+            display("the guest virtual address {:#x} is not mapped by the guest", gva)
+        }
+
+        GuestRegionBoundaryError(gpa: u64, len: usize) {
+            description("a guest memory access crossed the end of a region")
+            display("an access of {} bytes at guest address {:#x} crossed the end of its region", len, gpa)
+        }
+
+        ReadOnlyGuestAddressError(gpa: u64) {
+            description("a write was made to a read-only region")
+            display("the guest address {:#x} is in a read-only region", gpa)
+        }
+
+        UnsupportedMsrError(index: u32) {
+            description("a model-specific register was not supported by the kernel")
+            display("the model-specific register {:#x} is not supported by the kernel", index)
+        }
+
+        UnalignedRegionError(field: &'static str, value: u64) {
+            description("a region was not aligned to a page")
+            display("the {} of the region, {:#x}, is not aligned to a page", field, value)
+        }
+
+        MmioOverlapError(addr: u64, len: u64) {
+            description("an MMIO range overlaps a memory region")
+            display("the MMIO range of {} bytes at {:#x} overlaps a memory region", len, addr)
+        }
+
+        DeviceAddressConflictError(address: ::core::IoAddress, len: u64) {
+            description("a device was registered over the addresses of another")
+            display("the addresses overlap those of the device registered for {} addresses at {:?}", len, address)
+        }
+
+        InvalidWireFormatError(reason: &'static str) {
+            description("the given register state could not be decoded")
+            display("the given register state could not be decoded: {}", reason)
+        }
+
+        InvalidBreakpointError(reason: &'static str) {
+            description("an invalid hardware breakpoint was given")
+            display("an invalid hardware breakpoint was given: {}", reason)
+        }
+
+        UnsupportedGuestDebugError(flags: u32) {
+            description("a guest debug flag is not supported by the host")
+            display("the guest debug flags {:#x} are not supported by the host", flags)
+        }
+
+        IrqChipRequiredError {
+            description("an irqchip must be created first")
+            display("an irqchip must be created before this operation")
+        }
+
+        InvalidIrqError(irq: u32) {
+            description("an IRQ was not handled by the irqchip")
+            display("the IRQ {} is not handled by the irqchip", irq)
+        }
+
+        InKernelIrqchipError {
+            description("an interrupt was injected into a core with an in-kernel irqchip")
+            display("the machine has an in-kernel irqchip; interrupts must be raised through it, e.g. with `Machine::set_irq_level', instead of injected into the core")
+        }
+
+        CoresCreatedError(req: &'static str) {
+            description("an operation was done after a core was created")
+            display("`{}' must be done before any cores are created", req)
+        }
+
         InvalidVersionError(got: i32, expected: i32) {
             description("invalid KVM API version received")
             display("invalid KVM API version received; expected {}, got {}", expected, got)
@@ -0,0 +1,83 @@
+use super::EventFdFlag;
+use error::*;
+use mio::event::Evented;
+use mio::unix::EventedFd;
+use mio::{Poll, PollOpt, Ready, Token};
+use nix::sys::eventfd::{self, EfdFlags};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+/// A plain eventfd, without any reactor registration.  This is what
+/// [`EventFd`] registers with the reactor.
+pub struct BasicEventFd(File);
+
+impl BasicEventFd {
+    /// Creates a new, non-blocking eventfd with a counter of zero.
+    pub fn new() -> Result<BasicEventFd> {
+        BasicEventFd::with_value(0, EventFdFlag::empty())
+    }
+
+    /// Creates a new, non-blocking eventfd with the given initial
+    /// counter and flags.  See [`EventFd::new_with_value`].
+    pub fn with_value(initval: u32, flags: EventFdFlag) -> Result<BasicEventFd> {
+        let flags = EfdFlags::from_bits_truncate(flags.bits()) | EfdFlags::EFD_NONBLOCK;
+        eventfd::eventfd(initval, flags)
+            .map(|v| BasicEventFd(unsafe { File::from_raw_fd(v) }))
+            .chain_err(|| ErrorKind::CreateEventFdError)
+    }
+}
+
+impl AsRawFd for BasicEventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+impl AsRef<File> for BasicEventFd {
+    fn as_ref(&self) -> &File {
+        &self.0
+    }
+}
+
+impl Evented for BasicEventFd {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.as_raw_fd()).deregister(poll)
+    }
+}
+
+impl Read for BasicEventFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for BasicEventFd {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
@@ -0,0 +1,162 @@
+//! Plain eventfds, registered with the tokio reactor.  Unlike
+//! [`IoEventFd`] and [`IrqFd`], these are not attached to a machine;
+//! they can be used to signal between the threads of a VMM, or passed
+//! to other devices that accept an eventfd.
+
+use byteorder::{ByteOrder, NativeEndian};
+use error::*;
+use nix::libc;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use tokio::prelude::*;
+use tokio::reactor::{Handle, PollEvented2};
+
+mod basic;
+
+pub use self::basic::BasicEventFd;
+
+bitflags! {
+    /// The flags for creating an eventfd.  Eventfds are always created
+    /// non-blocking, as they are driven by the reactor.
+    pub struct EventFdFlag: i32 {
+        /// Each read returns one, and decrements the counter by one,
+        /// instead of returning the counter and resetting it to zero.
+        /// The initial counter is then the number of reads that succeed
+        /// before any write.
+        const SEMAPHORE = libc::EFD_SEMAPHORE;
+        /// The eventfd is closed when the process executes another
+        /// program.
+        const CLOEXEC = libc::EFD_CLOEXEC;
+    }
+}
+
+/// An eventfd, registered with a reactor.  This is a stream of the
+/// values read from the eventfd; every read returns the counter of the
+/// eventfd, and resets it to zero.
+///
+/// # Example
+/// ```rust
+/// # extern crate kvm;
+/// # extern crate tokio;
+/// # use kvm::eventfd::EventFd;
+/// # use tokio::prelude::*;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let fd = EventFd::new()?;
+/// fd.write_value(3)?;
+/// fd.write_value(4)?;
+/// let (value, _) = fd.into_future().wait().map_err(|(err, _)| err)?;
+/// assert_eq!(value, Some(7));
+/// #     Ok(())
+/// # }
+/// ```
+pub struct EventFd(PollEvented2<BasicEventFd>);
+
+impl EventFd {
+    /// Creates a new eventfd, registered with the default reactor.
+    pub fn new() -> Result<EventFd> {
+        BasicEventFd::new().map(|fd| EventFd(PollEvented2::new(fd)))
+    }
+
+    /// Creates a new eventfd with the given initial counter and flags,
+    /// registered with the default reactor.  The counter is immediately
+    /// readable if it is non-zero.  With [`EventFdFlag::SEMAPHORE`], the
+    /// counter is instead read one at a time, and so it can be used as
+    /// a semaphore holding that many permits.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::eventfd::{EventFd, EventFdFlag};
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let mut fd = EventFd::new_with_value(5, EventFdFlag::empty())?;
+    /// assert_eq!(fd.read_value()?, 5);
+    ///
+    /// let mut fd = EventFd::new_with_value(2, EventFdFlag::SEMAPHORE)?;
+    /// assert_eq!(fd.read_value()?, 1);
+    /// assert_eq!(fd.read_value()?, 1);
+    /// assert!(fd.read_value().is_err());
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn new_with_value(initval: u32, flags: EventFdFlag) -> Result<EventFd> {
+        BasicEventFd::with_value(initval, flags).map(|fd| EventFd(PollEvented2::new(fd)))
+    }
+
+    /// Creates a new eventfd, registered with the given reactor instead
+    /// of the default one.
+    pub fn new_with_handle(handle: &Handle) -> Result<EventFd> {
+        let fd = BasicEventFd::new()?;
+        PollEvented2::new_with_handle(fd, handle)
+            .map(EventFd)
+            .chain_err(|| ErrorKind::CreateEventFdError)
+    }
+
+    /// Reads the next value from the eventfd, without going through the
+    /// reactor.  See [`IoEventFd::read_value`].
+    pub fn read_value(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.0
+            .get_mut()
+            .read_exact(&mut buf)
+            .chain_err(|| ErrorKind::ReadEventFdError)?;
+        Ok(NativeEndian::read_u64(&buf))
+    }
+
+    /// Adds the given value to the counter of the eventfd, waking any
+    /// task waiting on it.  This errors if the counter would overflow.
+    pub fn write_value(&self, value: u64) -> Result<()> {
+        let mut buf = [0u8; 8];
+        NativeEndian::write_u64(&mut buf, value);
+        self.0
+            .get_ref()
+            .as_ref()
+            .write_all(&buf)
+            .chain_err(|| ErrorKind::WriteEventFdError)
+    }
+
+    /// Converts the eventfd into a stream of the values read from it.
+    /// This is the same as using the eventfd as a stream directly, but
+    /// allows the stream to be passed around without exposing the rest
+    /// of the eventfd.
+    pub fn into_value_stream(mut self) -> impl Stream<Item = u64, Error = Error> {
+        stream::poll_fn(move || self.poll_value())
+    }
+
+    fn poll_value(&mut self) -> Result<Async<Option<u64>>> {
+        // Reads from an eventfd always return all eight bytes, or fail
+        // with EAGAIN, so we never need to keep a partial read around.
+        let mut buf = [0u8; 8];
+        match self
+            .0
+            .poll_read(&mut buf)
+            .chain_err(|| ErrorKind::ReadEventFdError)?
+        {
+            Async::Ready(0) => Ok(Async::Ready(None)),
+            Async::Ready(8) => Ok(Async::Ready(Some(NativeEndian::read_u64(&buf)))),
+            Async::Ready(_) => Err(ErrorKind::ReadEventFdError.into()),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.get_ref().as_raw_fd()
+    }
+}
+
+impl Read for EventFd {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl AsyncRead for EventFd {}
+
+impl Stream for EventFd {
+    type Item = u64;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>> {
+        self.poll_value()
+    }
+}
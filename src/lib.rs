@@ -1,3 +1,23 @@
+//! Bindings to the Linux KVM API, for running virtual machines.
+//!
+//! The API is the same on every architecture, but much of it is not
+//! supported everywhere, and the kernel then fails the call.  The
+//! supported architectures are:
+//!
+//! | Architecture | Supported                                                    |
+//! |--------------|--------------------------------------------------------------|
+//! | `x86_64`     | Everything, unless documented otherwise.                     |
+//! | `aarch64`    | Machines, cores, regions, IO, and one-reg access.            |
+//! | `s390x`      | Machines, cores, regions, IO, storage keys, and core resets. |
+//!
+//! Methods documented as x86-only (e.g. the general purpose and special
+//! registers, MSRs, CPUID, the irqchip, and the PIT) are still built on
+//! `aarch64` and `s390x`; the API calls behind them fail there, with an
+//! API error.  Other architectures, e.g. `powerpc64`, are not
+//! supported: the run structure and register layouts assumed here do
+//! not match theirs, and so the crate refuses to build for them rather
+//! than build code that cannot work.
+
 extern crate kvm_sys;
 #[macro_use]
 extern crate error_chain;
@@ -8,9 +28,19 @@ extern crate byteorder;
 extern crate mio;
 extern crate tokio;
 
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "s390x"
+)))]
+compile_error!("kvm only supports x86_64, aarch64, and s390x hosts");
+
 pub mod core;
+pub mod devices;
 mod error;
+pub mod eventfd;
 pub mod machine;
+pub mod memory;
 pub mod system;
 
 pub use self::error::{Error, ErrorKind};
@@ -0,0 +1,75 @@
+use super::Machine;
+use error::*;
+use kvm_sys as kvm;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+/// An optional feature of an ARM core, requested when it is
+/// initialized.  See [`ArmVcpuInit::set_feature`].
+pub enum ArmFeature {
+    /// The core starts powered off, and is started through PSCI.
+    PowerOff = kvm::KVM_ARM_VCPU_POWER_OFF,
+    /// The core starts in AArch32 mode.
+    El1_32Bit = kvm::KVM_ARM_VCPU_EL1_32BIT,
+    /// The core uses version 0.2 of PSCI, instead of 0.1.
+    Psci0_2 = kvm::KVM_ARM_VCPU_PSCI_0_2,
+    /// The core has a PMUv3.
+    PmuV3 = kvm::KVM_ARM_VCPU_PMU_V3,
+    /// The core has SVE.  The core must then be finalized before it is
+    /// run.
+    Sve = kvm::KVM_ARM_VCPU_SVE,
+    /// The core has address pointer authentication.
+    PtrauthAddress = kvm::KVM_ARM_VCPU_PTRAUTH_ADDRESS,
+    /// The core has generic pointer authentication.
+    PtrauthGeneric = kvm::KVM_ARM_VCPU_PTRAUTH_GENERIC,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The target and features an ARM core is initialized with, through
+/// [`Core::arm_vcpu_init`].  The target is usually the one the host
+/// prefers, through [`Machine::arm_preferred_target`].
+pub struct ArmVcpuInit {
+    /// The kind of processor the core emulates.
+    pub target: u32,
+    /// The bitmap of requested features; see [`ArmFeature`].
+    pub features: [u32; 7],
+}
+
+impl ArmVcpuInit {
+    /// Requests the given feature.
+    pub fn set_feature(&mut self, feature: ArmFeature) -> &mut Self {
+        let bit = feature as u32;
+        self.features[(bit / 32) as usize] |= 1 << (bit % 32);
+        self
+    }
+
+    /// Whether or not the given feature is requested.
+    pub fn has_feature(&self, feature: ArmFeature) -> bool {
+        let bit = feature as u32;
+        self.features[(bit / 32) as usize] & (1 << (bit % 32)) != 0
+    }
+
+    pub(crate) fn raw(&self) -> kvm::VcpuInit {
+        kvm::VcpuInit {
+            target: self.target,
+            features: self.features,
+        }
+    }
+}
+
+impl Machine {
+    /// Retrieves the target the host prefers for the cores of the
+    /// machine, i.e. the one matching the host processor, with no
+    /// features requested.  Features can then be requested before the
+    /// cores are initialized with it.
+    pub fn arm_preferred_target(&self) -> Result<ArmVcpuInit> {
+        let mut init: kvm::VcpuInit = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_arm_preferred_target(self.as_raw_fd(), &mut init as *mut _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_arm_preferred_target"))?;
+        Ok(ArmVcpuInit {
+            target: init.target,
+            features: init.features,
+        })
+    }
+}
@@ -0,0 +1,49 @@
+/// An iterator over the dirty pages in a dirty log, as returned by
+/// [`Machine::dirty_pages`].  This yields the index of each dirty page
+/// within the slot, in ascending order; the guest address of a page is
+/// the address of the region plus the index times the page size.
+///
+/// # Example
+/// ```rust
+/// # use kvm::machine::DirtyPages;
+/// let bitmap = vec![0b1001, 0, 1 << 63, 0x8000_0000_0000_0001];
+/// let pages: Vec<u64> = DirtyPages::new(bitmap).collect();
+/// assert_eq!(pages, vec![0, 3, 191, 192, 255]);
+///
+/// assert_eq!(DirtyPages::new(vec![0; 4]).next(), None);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DirtyPages {
+    bitmap: Vec<u64>,
+    index: usize,
+    word: u64,
+}
+
+impl DirtyPages {
+    /// Creates an iterator over the given dirty bitmap, as returned by
+    /// [`Machine::dirty_log`].
+    pub fn new(bitmap: Vec<u64>) -> DirtyPages {
+        let word = bitmap.first().cloned().unwrap_or(0);
+        DirtyPages {
+            bitmap,
+            index: 0,
+            word,
+        }
+    }
+}
+
+impl Iterator for DirtyPages {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        while self.word == 0 {
+            self.index += 1;
+            self.word = *self.bitmap.get(self.index)?;
+        }
+
+        let bit = self.word.trailing_zeros();
+        // Clears the lowest set bit.
+        self.word &= self.word - 1;
+        Some(self.index as u64 * 64 + bit as u64)
+    }
+}
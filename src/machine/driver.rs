@@ -0,0 +1,190 @@
+use super::{IoEventFd, IrqFd, Lifecycle, LifecycleNotifier};
+use byteorder::{ByteOrder, NativeEndian};
+use core::{AsyncCore, Core, Pause, Response};
+use error::*;
+use std::io::Write;
+use tokio::prelude::*;
+use tokio::reactor::PollEvented2;
+
+#[derive(Copy, Clone)]
+/// An event from a [`CoreDriver`].
+pub enum DriverEvent {
+    /// The core paused.  The core does not run again until
+    /// [`CoreDriver::respond`] is called.
+    Pause(Pause),
+    /// The guest rang a doorbell; the index is the one returned by
+    /// [`CoreDriver::add_doorbell`], and the value is the counter read
+    /// from the eventfd.
+    Doorbell(usize, u64),
+}
+
+/// Drives a core along with the eventfds attached to it, as a single
+/// stream.  The doorbells (ioeventfds) of the core are polled alongside
+/// the core itself, and interrupts can be raised through the attached
+/// irqfds.  Every pause is yielded, and must be responded to before the
+/// core runs again.
+///
+/// Irqfds require an in-kernel irqchip (see
+/// [`Machine::create_irqchip`]), with which the kernel handles halts
+/// itself: a halted core waits inside the kernel, and an interrupt
+/// raised through [`CoreDriver::notify`] wakes it there, without any
+/// pause.  Without an in-kernel irqchip, a halt is yielded like any
+/// other pause; the interrupt that ends it must then be injected
+/// through [`Core::interrupt`], e.g. after [`CoreDriver::into_inner`],
+/// and the core resumed.
+///
+/// # Example
+/// This runs a real-mode program that halts until interrupt 4 is
+/// raised, and then writes to a port.  If KVM is not available on this
+/// host, this does nothing.
+///
+/// ```rust
+/// # extern crate kvm;
+/// # extern crate tokio;
+/// # use kvm::core::Pause;
+/// # use kvm::machine::{CoreDriver, DriverEvent, IrqFdFlag, MachineKind, RegionOptions};
+/// # use kvm::memory::Slab;
+/// # use kvm::system::System;
+/// # use tokio::prelude::*;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let system = match System::new() {
+///     Ok(system) => system,
+///     Err(_) => return Ok(()),
+/// };
+///
+/// // The interrupt vector table points interrupt 4 at an `iret`.
+/// let mut low = Slab::from_anon(0x1000)?;
+/// low.write(0x10, &[0x00, 0x05, 0x00, 0x00])?;
+/// low.write(0x500, &[0xcf])?;
+/// // sti; hlt; mov al, 0x2a; out 0x10, al; jmp $
+/// let mut high = Slab::from_anon(0x10000)?;
+/// high.write(0xfff0, &[0xfb, 0xf4, 0xb0, 0x2a, 0xe6, 0x10, 0xeb, 0xfe])?;
+///
+/// let machine = system.create_machine(MachineKind::Default)?;
+/// machine.set_tss_address(0xfffb_d000)?;
+/// machine.create_irqchip()?;
+/// let mut region = RegionOptions::new(0);
+/// region.addr(0).source(low.as_mut_slice()?);
+/// machine.set_region(region)?;
+/// let mut region = RegionOptions::new(1);
+/// region.addr(0xffff_0000).source(high.as_mut_slice()?);
+/// machine.set_region(region)?;
+///
+/// let core = machine.create_core(0)?;
+/// let mut driver = CoreDriver::new(core.into_async());
+/// let irq = driver.add_irqfd(machine.create_irqfd(4, IrqFdFlag::empty())?);
+///
+/// // The core halts inside the kernel, and the interrupt wakes it up,
+/// // whether it is raised before or after the halt.
+/// driver.notify(irq)?;
+/// let (event, driver) = driver.into_future().wait().map_err(|(err, _)| err)?;
+/// match event {
+///     Some(DriverEvent::Pause(Pause::Io(io))) => assert_eq!(io.port, 0x10),
+///     _ => panic!("expected a port IO exit"),
+/// }
+/// driver.into_inner()?;
+/// #     Ok(())
+/// # }
+/// ```
+pub struct CoreDriver<'m> {
+    core: AsyncCore,
+    doorbells: Vec<PollEvented2<IoEventFd<'m>>>,
+    irqfds: Vec<IrqFd<'m>>,
+    lifecycle: Option<LifecycleNotifier>,
+}
+
+impl<'m> CoreDriver<'m> {
+    /// Creates a driver for the given core, with no doorbells or irqfds.
+    pub fn new(core: AsyncCore) -> CoreDriver<'m> {
+        CoreDriver {
+            core,
+            doorbells: vec![],
+            irqfds: vec![],
+            lifecycle: None,
+        }
+    }
+
+    /// Adds a doorbell to the driver, returning its index.  The
+    /// doorbell is registered with the default reactor.
+    pub fn add_doorbell(&mut self, doorbell: IoEventFd<'m>) -> usize {
+        self.doorbells.push(PollEvented2::new(doorbell));
+        self.doorbells.len() - 1
+    }
+
+    /// Adds an irqfd to the driver, returning its index.
+    pub fn add_irqfd(&mut self, irqfd: IrqFd<'m>) -> usize {
+        self.irqfds.push(irqfd);
+        self.irqfds.len() - 1
+    }
+
+    /// Feeds the lifecycle changes the core asks for to the given
+    /// notifier, and so to its [`LifecycleWatcher`].  The pauses that
+    /// cause them are still yielded by the driver as usual.
+    pub fn watch_lifecycle(&mut self, notifier: LifecycleNotifier) {
+        self.lifecycle = Some(notifier);
+    }
+
+    /// Raises an interrupt through the irqfd with the given index.  A
+    /// core halted inside the kernel is woken by the interrupt.
+    pub fn notify(&mut self, index: usize) -> Result<()> {
+        let mut data = [0u8; 8];
+        NativeEndian::write_u64(&mut data, 1);
+        self.irqfds
+            .get_mut(index)
+            .ok_or_else(|| Error::from(ErrorKind::NotifyIrqFdError))?
+            .write_all(&data)
+            .chain_err(|| ErrorKind::NotifyIrqFdError)?;
+        Ok(())
+    }
+
+    /// Responds to the last pause of the core.  See
+    /// [`AsyncCore::respond`].
+    pub fn respond(&mut self, response: Response) -> Result<()> {
+        self.core.respond(response)
+    }
+
+    /// Stops the core, returning it.  See [`AsyncCore::into_inner`].
+    pub fn into_inner(self) -> Result<Core> {
+        self.core.into_inner()
+    }
+
+    fn poll_doorbells(&mut self) -> Result<Option<(usize, u64)>> {
+        for (index, doorbell) in self.doorbells.iter_mut().enumerate() {
+            let mut buf = [0u8; 8];
+            match doorbell
+                .poll_read(&mut buf)
+                .chain_err(|| ErrorKind::ReadIoEventFdError)?
+            {
+                Async::Ready(8) => return Ok(Some((index, NativeEndian::read_u64(&buf)))),
+                Async::Ready(_) => return Err(ErrorKind::ReadIoEventFdError.into()),
+                Async::NotReady => {}
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+impl<'m> Stream for CoreDriver<'m> {
+    type Item = DriverEvent;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>> {
+        if let Some((index, value)) = self.poll_doorbells()? {
+            return Ok(Async::Ready(Some(DriverEvent::Doorbell(index, value))));
+        }
+
+        match self.core.poll()? {
+            Async::Ready(Some(pause)) => {
+                if let (Some(lifecycle), Some(notifier)) =
+                    (Lifecycle::from_pause(&pause), self.lifecycle.as_mut())
+                {
+                    notifier.notify(lifecycle);
+                }
+                Ok(Async::Ready(Some(DriverEvent::Pause(pause))))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use super::Machine;
-use byteorder::{ByteOrder, NativeEndian};
+use byteorder::{BigEndian, ByteOrder, LittleEndian, NativeEndian};
 use error::*;
 use kvm_sys as kvm;
 use mio::event::Evented;
@@ -10,7 +10,7 @@ use std::io::{self, Read};
 use std::ops::Drop;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use tokio::prelude::*;
-use tokio::reactor::PollEvented2;
+use tokio::reactor::{Handle, PollEvented2};
 
 bitflags! {
     pub struct IoEventFdFlag: u32 {
@@ -28,6 +28,53 @@ bitflags! {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The byte order of the values that the guest writes.  For guests
+/// running on the same architecture as the host, this should always be
+/// `Native`.  Big-endian guests (s390x, and ppc64 when not running in
+/// little-endian mode) should use `Big`; x86, ARM, and ppc64le guests
+/// use `Little`.
+pub enum Endian {
+    Native,
+    Little,
+    Big,
+}
+
+impl Default for Endian {
+    fn default() -> Endian {
+        Endian::Native
+    }
+}
+
+impl Endian {
+    /// Converts a value, as the guest sees it, into the value the host
+    /// kernel compares against when the guest writes `length` bytes.
+    /// Lengths of zero, or greater than eight, are returned unchanged.
+    pub fn datamatch(self, value: u64, length: u32) -> u64 {
+        let length = length as usize;
+        if length == 0 || length > 8 {
+            return value;
+        }
+
+        let mut buf = [0u8; 8];
+        match self {
+            Endian::Native => return value,
+            Endian::Little => LittleEndian::write_uint(&mut buf, value, length),
+            Endian::Big => BigEndian::write_uint(&mut buf, value, length),
+        }
+        NativeEndian::read_uint(&buf, length)
+    }
+
+    /// Reads an 8-byte value in this byte order.
+    pub fn read_u64(self, buf: &[u8]) -> u64 {
+        match self {
+            Endian::Native => NativeEndian::read_u64(buf),
+            Endian::Little => LittleEndian::read_u64(buf),
+            Endian::Big => BigEndian::read_u64(buf),
+        }
+    }
+}
+
 /// An IoEventFd.  This is a structure that allows userspace to poll for
 /// reads/writes to data locations, instead of having to have the VM
 /// exit, handle the request, and go back into the VM.  That way, when
@@ -51,8 +98,11 @@ impl<'m> IoEventFd<'m> {
             .chain_err(|| ErrorKind::CreateIoEventFdError)
     }
 
-    /// Reads the next value from the EventFd.  This will block until
-    /// the value is available.
+    /// Reads the next value from the EventFd.  The eventfd is created
+    /// non-blocking, so this does not wait for a value; if none is
+    /// pending, this errors with [`ErrorKind::ReadIoEventFdError`].
+    /// Use [`IoEventFd::try_read_value`] to tell the two apart, or
+    /// [`IoEventFd::stream`] to wait for values.
     pub fn read_value(&mut self) -> Result<u64> {
         let mut buf = [0u8; 8];
         self.read_exact(&mut buf)
@@ -60,14 +110,50 @@ impl<'m> IoEventFd<'m> {
         Ok(NativeEndian::read_u64(&buf))
     }
 
+    /// Reads the next value from the EventFd, if one is pending.  This
+    /// returns `None` if the guest has not accessed the address since
+    /// the last read.
+    pub fn try_read_value(&mut self) -> Result<Option<u64>> {
+        let mut buf = [0u8; 8];
+        match self.file.read(&mut buf) {
+            Ok(8) => Ok(Some(NativeEndian::read_u64(&buf))),
+            Ok(_) => Err(ErrorKind::ReadIoEventFdError.into()),
+            Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(err) => Err(err).chain_err(|| ErrorKind::ReadIoEventFdError),
+        }
+    }
+
     /// Creates an event stream from this eventfd.
     pub fn stream<'s>(&'s mut self) -> IoEventStream<'s, 'm> {
+        self.stream_with_endian(Endian::Native)
+    }
+
+    /// Creates an event stream from this eventfd, decoding values with
+    /// the given byte order.  The eventfd counter itself is written by
+    /// the host kernel, so this only needs to differ from
+    /// `Endian::Native` if the eventfd is fed by something other than
+    /// the kernel.
+    pub fn stream_with_endian<'s>(&'s mut self, endian: Endian) -> IoEventStream<'s, 'm> {
         IoEventStream {
             ev: PollEvented2::new(self),
-            buf: [0; 8],
+            buf: [0; STREAM_BUFFER],
             len: 0,
+            endian,
         }
     }
+
+    /// Creates an event stream from this eventfd, registered with the
+    /// given reactor instead of the default one.
+    pub fn stream_with_handle<'s>(&'s mut self, handle: &Handle) -> Result<IoEventStream<'s, 'm>> {
+        PollEvented2::new_with_handle(self, handle)
+            .map(|ev| IoEventStream {
+                ev,
+                buf: [0; STREAM_BUFFER],
+                len: 0,
+                endian: Endian::Native,
+            })
+            .chain_err(|| ErrorKind::ReadIoEventFdError)
+    }
 }
 
 impl<'m> AsRawFd for IoEventFd<'m> {
@@ -164,13 +250,98 @@ impl<'m> Read for IoEventFd<'m> {
     }
 }
 
+/// The size of the read buffer of an [`IoEventStream`], in bytes.
+const STREAM_BUFFER: usize = 64;
+
 /// An event stream for an IoEventFd.  This will read to an 8-byte
 /// boundry, and yield the 8-byte value as a u64.  Once the u64 is
 /// yielded, the event should be considered "triggered."
+///
+/// A read that returns several values at once yields each of them in
+/// turn, before reading again.  The kernel coalesces accesses into a
+/// single value, but something else feeding the file descriptor may
+/// not.  The stream ends once a read returns no data; if that leaves
+/// part of a value behind, it errors with
+/// [`ErrorKind::ReadIoEventFdError`] instead.
+///
+/// # Example
+/// ```rust
+/// # extern crate byteorder;
+/// # extern crate kvm;
+/// # extern crate tokio;
+/// # use byteorder::{ByteOrder, NativeEndian};
+/// # use kvm::machine::{IoEventFdFlag, MachineKind};
+/// # use kvm::system::System;
+/// # use std::io::Write;
+/// # use tokio::prelude::*;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// # let system = match System::new() {
+/// #     Ok(system) => system,
+/// #     Err(_) => return Ok(()),
+/// # };
+/// let machine = system.create_machine(MachineKind::Default)?;
+/// let mut ioeventfd = machine.create_ioeventfd(0x1000, 4, 0, IoEventFdFlag::empty())?;
+///
+/// // Two notifications before the stream is polled are coalesced into
+/// // one value.
+/// let mut buf = [0u8; 8];
+/// for value in &[1, 2] {
+///     NativeEndian::write_u64(&mut buf, *value);
+///     (&*ioeventfd.as_ref()).write_all(&buf)?;
+/// }
+///
+/// let (value, _) = ioeventfd.stream().into_future().wait().map_err(|(err, _)| err)?;
+/// assert_eq!(value, Some(3));
+/// #     Ok(())
+/// # }
+/// ```
+///
+/// Here the file descriptor is fed by a pipe instead, so that two
+/// separate values arrive in a single 16-byte read; both are yielded
+/// from the one read.
+///
+/// ```rust
+/// # extern crate byteorder;
+/// # extern crate kvm;
+/// # extern crate nix;
+/// # extern crate tokio;
+/// # use byteorder::{ByteOrder, NativeEndian};
+/// # use kvm::machine::{IoEventFdFlag, MachineKind};
+/// # use kvm::system::System;
+/// # use nix::fcntl::OFlag;
+/// # use nix::unistd;
+/// # use std::os::unix::io::AsRawFd;
+/// # use tokio::prelude::*;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// # let system = match System::new() {
+/// #     Ok(system) => system,
+/// #     Err(_) => return Ok(()),
+/// # };
+/// let machine = system.create_machine(MachineKind::Default)?;
+/// let mut ioeventfd = machine.create_ioeventfd(0x1000, 4, 0, IoEventFdFlag::empty())?;
+///
+/// // Replace the eventfd with the read end of a pipe.  The kernel keeps
+/// // the original eventfd registered until the machine is dropped.
+/// let (read, write) = unistd::pipe2(OFlag::O_NONBLOCK)?;
+/// unistd::dup2(read, ioeventfd.as_raw_fd())?;
+/// unistd::close(read)?;
+///
+/// let mut buf = [0u8; 16];
+/// NativeEndian::write_u64(&mut buf[..8], 1);
+/// NativeEndian::write_u64(&mut buf[8..], 2);
+/// unistd::write(write, &buf)?;
+/// unistd::close(write)?;
+///
+/// let values = ioeventfd.stream().collect().wait()?;
+/// assert_eq!(values, vec![1, 2]);
+/// #     Ok(())
+/// # }
+/// ```
 pub struct IoEventStream<'m, 's: 'm> {
     ev: PollEvented2<&'m mut IoEventFd<'s>>,
-    buf: [u8; 8],
+    buf: [u8; STREAM_BUFFER],
     len: usize,
+    endian: Endian,
 }
 
 impl<'m, 's: 'm> Stream for IoEventStream<'m, 's> {
@@ -178,24 +349,27 @@ impl<'m, 's: 'm> Stream for IoEventStream<'m, 's> {
     type Error = Error;
 
     fn poll(&mut self) -> Result<Async<Option<Self::Item>>> {
-        let read_result = self
-            .ev
-            .poll_read(&mut self.buf[self.len..])
-            .chain_err(|| ErrorKind::ReadIoEventFdError)?;
-
-        match read_result {
-            Async::Ready(v) => {
-                self.len += v;
-                if self.len == 8 {
-                    let value = NativeEndian::read_u64(&self.buf);
-                    self.len = 0;
-                    Ok(Async::Ready(Some(value)))
-                } else {
-                    Ok(Async::NotReady)
-                }
+        loop {
+            // Values left over from a previous read are yielded before
+            // reading again.
+            if self.len >= 8 {
+                let value = self.endian.read_u64(&self.buf[..8]);
+                self.buf[..self.len].rotate_left(8);
+                self.len -= 8;
+                return Ok(Async::Ready(Some(value)));
             }
 
-            _ => Ok(Async::NotReady),
+            let read_result = self
+                .ev
+                .poll_read(&mut self.buf[self.len..])
+                .chain_err(|| ErrorKind::ReadIoEventFdError)?;
+
+            match read_result {
+                Async::Ready(0) if self.len == 0 => return Ok(Async::Ready(None)),
+                Async::Ready(0) => return Err(ErrorKind::ReadIoEventFdError.into()),
+                Async::Ready(v) => self.len += v,
+                Async::NotReady => return Ok(Async::NotReady),
+            }
         }
     }
 }
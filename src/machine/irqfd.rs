@@ -1,6 +1,7 @@
 use super::Machine;
 use byteorder::{ByteOrder, NativeEndian};
 use error::{Error, ErrorKind, ResultExt};
+use eventfd::EventFd;
 use kvm_sys as kvm;
 use mio::event::Evented;
 use mio::unix::EventedFd;
@@ -16,8 +17,8 @@ bitflags! {
     pub struct IrqFdFlag: u32 {
         /// Removes the IrqFd from the machine.  Do not use this.
         const DEASSIGN = kvm::KVM_IRQFD_FLAG_DEASSIGN;
-        /// This operation is not supported by this library.  Please do
-        /// not use it unless you know what you are doing.
+        /// The IrqFd is level-triggered, with a resample eventfd.  Use
+        /// [`Machine::create_resample_irqfd`] instead of setting this.
         const RESAMPLE = kvm::KVM_IRQFD_FLAG_RESAMPLE;
     }
 }
@@ -27,6 +28,7 @@ pub struct IrqFd<'m> {
     pub(super) file: File,
     pub(super) gsi: u32,
     pub(super) flags: IrqFdFlag,
+    pub(super) resample: Option<EventFd>,
 }
 
 impl<'m> IrqFd<'m> {
@@ -44,13 +46,42 @@ impl<'m> IrqFd<'m> {
             .map(|_| ())
             .map_err(|err| Error::with_chain(err, ErrorKind::NotifyIrqFdError))
     }
+
+    /// A stream that yields every time the guest acknowledges the
+    /// interrupt.  This is for level-triggered interrupts, created
+    /// through [`Machine::create_resample_irqfd`]; for any other IrqFd,
+    /// this errors with [`ErrorKind::ResampleIrqFdRequiredError`].
+    ///
+    /// The lifecycle of a level-triggered interrupt is:
+    ///
+    /// 1. the device has a condition to report, and so the IrqFd is
+    ///    notified, asserting the line;
+    /// 2. the guest handles the interrupt, and acknowledges it with an
+    ///    end-of-interrupt;
+    /// 3. the kernel deasserts the line, and this stream yields; and
+    /// 4. if the condition of the device is still active, the IrqFd is
+    ///    notified again.
+    ///
+    /// Several acknowledgements may be yielded as one, if the stream is
+    /// not polled in between.
+    pub fn resample_stream<'s>(
+        &'s mut self,
+    ) -> Result<impl Stream<Item = (), Error = Error> + 's, Error> {
+        match self.resample {
+            Some(ref mut resample) => Ok(resample.map(|_| ())),
+            None => Err(ErrorKind::ResampleIrqFdRequiredError.into()),
+        }
+    }
 }
 
 impl<'m> Drop for IrqFd<'m> {
     fn drop(&mut self) {
-        let _ =
-            self.machine
-                .irqfd_mod(self.gsi, self.flags | IrqFdFlag::DEASSIGN, self.as_raw_fd());
+        let _ = self.machine.irqfd_mod(
+            self.gsi,
+            self.flags | IrqFdFlag::DEASSIGN,
+            self.as_raw_fd(),
+            0,
+        );
     }
 }
 
@@ -0,0 +1,78 @@
+use core::Pause;
+use error::*;
+use kvm_sys as kvm;
+use tokio::prelude::*;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// A change in the lifecycle of the guest, as requested by the guest
+/// itself.
+pub enum Lifecycle {
+    /// The guest asked to be reset, or a core triple faulted (which on
+    /// x86 resets the processor).
+    Reboot,
+    /// The guest asked to be powered off.
+    Poweroff,
+    /// The guest crashed, and reported it through a paravirtualized
+    /// interface (e.g. the Hyper-V crash MSRs, or a PSCI call).
+    Crash,
+}
+
+impl Lifecycle {
+    /// The lifecycle change the given pause asks for, if any.  These
+    /// are the `KVM_EXIT_SYSTEM_EVENT` exits, and `KVM_EXIT_SHUTDOWN`.
+    pub fn from_pause(pause: &Pause) -> Option<Lifecycle> {
+        match *pause {
+            Pause::SystemEvent(ref event) => match event.type_ {
+                kvm::KVM_SYSTEM_EVENT_SHUTDOWN => Some(Lifecycle::Poweroff),
+                kvm::KVM_SYSTEM_EVENT_RESET => Some(Lifecycle::Reboot),
+                kvm::KVM_SYSTEM_EVENT_CRASH => Some(Lifecycle::Crash),
+                _ => None,
+            },
+            Pause::Shutdown => Some(Lifecycle::Reboot),
+            _ => None,
+        }
+    }
+}
+
+/// A stream of the lifecycle changes the guest asks for, fed by the
+/// [`CoreDriver`]s of every core.  This lets a supervising task handle
+/// shutdown and reboot apart from the run loops of the cores.  The
+/// stream ends once every [`LifecycleNotifier`] has been dropped.
+///
+/// The kernel only ever stops the core that asked for the change; the
+/// other cores keep running.  To reboot the guest, the supervisor must
+/// stop every core (e.g. by responding with [`Response::Stop`]), reset
+/// their state and the guest's memory, and then start them again; to
+/// power it off, it stops every core, and drops the machine.
+pub struct LifecycleWatcher(UnboundedReceiver<Lifecycle>);
+
+#[derive(Clone)]
+/// The sending half of a [`LifecycleWatcher`].  Each core driver is
+/// given a clone, through [`CoreDriver::watch_lifecycle`].
+pub struct LifecycleNotifier(UnboundedSender<Lifecycle>);
+
+impl LifecycleWatcher {
+    /// Creates a watcher, along with the notifier that feeds it.
+    pub fn new() -> (LifecycleWatcher, LifecycleNotifier) {
+        let (tx, rx) = unbounded_channel();
+        (LifecycleWatcher(rx), LifecycleNotifier(tx))
+    }
+}
+
+impl LifecycleNotifier {
+    /// Sends a lifecycle change to the watcher.  Nothing happens if the
+    /// watcher was dropped.
+    pub fn notify(&mut self, lifecycle: Lifecycle) {
+        let _ = self.0.try_send(lifecycle);
+    }
+}
+
+impl Stream for LifecycleWatcher {
+    type Item = Lifecycle;
+    type Error = Error;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>> {
+        self.0.poll().chain_err(|| ErrorKind::CoreThreadError)
+    }
+}
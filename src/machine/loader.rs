@@ -0,0 +1,552 @@
+//! Helpers for loading kernels and other boot-time data into guest
+//! memory.  All of these assume that the given [`Slab`] is mounted at
+//! guest address zero, so that offsets into the slab are guest physical
+//! addresses.
+
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use error::*;
+use memory::Slab;
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELF_CLASS_32: u8 = 1;
+const ELF_CLASS_64: u8 = 2;
+const ELF_DATA_LSB: u8 = 1;
+const ELF_DATA_MSB: u8 = 2;
+const PT_LOAD: u32 = 1;
+
+/// The guest address the zero page is written to.
+pub const BOOT_PARAMS_ADDRESS: u64 = 0x7000;
+/// The guest address the kernel command line is written to.
+pub const CMDLINE_ADDRESS: u64 = 0x20000;
+/// The guest address the protected-mode kernel is loaded at.
+pub const KERNEL_ADDRESS: u64 = 0x100000;
+
+const BOOT_PARAMS_SIZE: usize = 4096;
+const BOOT_FLAG: u16 = 0xaa55;
+const E820_ENTRIES_OFFSET: usize = 0x1e8;
+const E820_TABLE_OFFSET: usize = 0x2d0;
+const E820_ENTRY_SIZE: usize = 20;
+/// The most entries the e820 map in the zero page can hold.
+pub const E820_MAX_ENTRIES: usize = 128;
+const SETUP_HEADER_OFFSET: usize = 0x1f1;
+const SETUP_HEADER_MAGIC: &[u8] = b"HdrS";
+const MIN_BOOT_PROTOCOL: u16 = 0x0206;
+const LOADED_HIGH: u8 = 0x01;
+const CAN_USE_HEAP: u8 = 0x80;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The result of loading a Linux kernel.  The boot core should be set
+/// up with `RSI` pointing to [`BootParams::address`], and should start
+/// execution at either [`BootParams::kernel`] (32-bit protected mode)
+/// or [`BootParams::entry64`] (64-bit long mode).
+pub struct BootParams {
+    /// The guest address of the zero page (`struct boot_params`).
+    pub address: u64,
+    /// The guest address of the protected-mode kernel.  This is the
+    /// 32-bit entry point.
+    pub kernel: u64,
+    /// The guest address of the command line.
+    pub cmdline: u64,
+}
+
+impl BootParams {
+    /// The 64-bit entry point of the kernel.
+    pub fn entry64(&self) -> u64 {
+        self.kernel + 0x200
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+/// The type of a range of memory in the e820 map.  See [`E820Entry`].
+pub enum E820Kind {
+    /// The memory is usable RAM.
+    Ram = 1,
+    /// The memory is reserved, and must not be used by the kernel.
+    Reserved = 2,
+    /// The memory holds ACPI tables, and can be used once they are
+    /// read.
+    Acpi = 3,
+    /// The memory is ACPI non-volatile storage.
+    Nvs = 4,
+    /// The memory is unusable.
+    Unusable = 5,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// A range of guest physical memory in the e820 map, which tells the
+/// kernel where usable RAM is.  See [`build_boot_params`].
+///
+/// # Example
+/// ```rust
+/// # use kvm::machine::loader::{E820Entry, E820Kind};
+/// let low = E820Entry::ram(0, 0x9fc00);
+/// assert_eq!(low.kind, E820Kind::Ram);
+/// let bios = E820Entry::reserved(0xf0000, 0x10000);
+/// assert_eq!(bios.end(), 0x100000);
+/// ```
+pub struct E820Entry {
+    /// The guest physical address of the start of the range.
+    pub addr: u64,
+    /// The size of the range, in bytes.
+    pub size: u64,
+    /// The type of the range.
+    pub kind: E820Kind,
+}
+
+impl E820Entry {
+    /// Creates an entry for the given range, of the given type.
+    pub fn new(addr: u64, size: u64, kind: E820Kind) -> E820Entry {
+        E820Entry { addr, size, kind }
+    }
+
+    /// Creates an entry for usable RAM.
+    pub fn ram(addr: u64, size: u64) -> E820Entry {
+        E820Entry::new(addr, size, E820Kind::Ram)
+    }
+
+    /// Creates an entry for reserved memory.
+    pub fn reserved(addr: u64, size: u64) -> E820Entry {
+        E820Entry::new(addr, size, E820Kind::Reserved)
+    }
+
+    /// The guest physical address just past the end of the range.
+    pub fn end(&self) -> u64 {
+        self.addr + self.size
+    }
+}
+
+const PAGE_SIZE: usize = 4096;
+const PAGE_PRESENT: u64 = 1 << 0;
+const PAGE_WRITABLE: u64 = 1 << 1;
+const PAGE_HUGE: u64 = 1 << 7;
+
+/// The descriptors written by [`setup_flat_gdt`].  Each is flat; i.e.
+/// it has a base of zero, and covers the entire address space.
+const FLAT_GDT: [u64; 4] = [
+    // The null descriptor.
+    0,
+    // 64-bit code: present, ring 0, execute/read, long mode.
+    0x00af_9b00_0000_ffff,
+    // Data: present, ring 0, read/write, 32-bit, 4KiB granularity.
+    0x00cf_9300_0000_ffff,
+    // 32-bit code: present, ring 0, execute/read, 4KiB granularity.
+    0x00cf_9b00_0000_ffff,
+];
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The layout of the GDT written by [`setup_flat_gdt`].  The base and
+/// limit go into the `gdt` field of the special registers of the core,
+/// and the selectors into the segment registers.
+pub struct GdtLayout {
+    /// The guest address of the GDT.
+    pub base: u64,
+    /// The limit of the GDT; i.e. its size in bytes, minus one.
+    pub limit: u16,
+    /// The selector for 64-bit code, for `CS` in long mode.
+    pub code64: u16,
+    /// The selector for 32-bit code, for `CS` in protected mode.
+    pub code32: u16,
+    /// The selector for data, for `DS`, `ES`, `FS`, `GS`, and `SS`.
+    pub data: u16,
+}
+
+/// Writes a flat GDT into the slab at the given guest address.  This
+/// contains four descriptors, each with a base of zero and a limit
+/// covering the entire address space, all at ring 0:
+///
+/// | Selector | Descriptor                 |
+/// |----------|----------------------------|
+/// | `0x00`   | Null                       |
+/// | `0x08`   | 64-bit code (execute/read) |
+/// | `0x10`   | Data (read/write)          |
+/// | `0x18`   | 32-bit code (execute/read) |
+///
+/// The segment registers of the core must still be loaded with the
+/// hidden parts of these descriptors, as the core does not read the GDT
+/// until the guest reloads a segment register.  No IDT is written; the
+/// guest is expected to set up its own before enabling interrupts.
+/// This errors if the GDT does not fit within the slab.
+pub fn setup_flat_gdt(slab: &mut Slab, base: u64) -> Result<GdtLayout> {
+    let mut data = [0u8; 32];
+    for (index, descriptor) in FLAT_GDT.iter().enumerate() {
+        LittleEndian::write_u64(&mut data[index * 8..(index + 1) * 8], *descriptor);
+    }
+    slab.write(base, &data)?;
+
+    Ok(GdtLayout {
+        base,
+        limit: (data.len() - 1) as u16,
+        code64: 0x08,
+        code32: 0x18,
+        data: 0x10,
+    })
+}
+
+/// Writes identity-mapping page tables for long mode into the slab at
+/// the given guest address, mapping the first `gib` gigabytes of the
+/// address space with 2MiB pages.  This returns the guest address of
+/// the PML4, to be loaded into `CR3`.
+///
+/// The tables take up `2 + gib` pages, starting at `base`: the PML4,
+/// then a single PDPT, then one page directory per gigabyte.  Every
+/// page is present, writable, and accessible only from ring 0.  The
+/// base must be aligned to 4KiB, and must not overlap the memory that
+/// the guest uses otherwise.  This errors with
+/// [`ErrorKind::InvalidPageTablesError`] if the base is not aligned, if
+/// `gib` is zero or more than 512 (the most a single PDPT can map), or
+/// if the tables would run past the end of the address space, and
+/// errors if the tables do not fit within the slab.
+pub fn setup_identity_page_tables(slab: &mut Slab, base: u64, gib: usize) -> Result<u64> {
+    if base % PAGE_SIZE as u64 != 0 {
+        return Err(ErrorKind::InvalidPageTablesError("base must be aligned to 4KiB").into());
+    }
+
+    if gib == 0 || gib > 512 {
+        return Err(ErrorKind::InvalidPageTablesError("must map between 1 and 512 GiB").into());
+    }
+
+    let size = (2 + gib) * PAGE_SIZE;
+    if base.checked_add(size as u64).is_none() {
+        return Err(ErrorKind::InvalidPageTablesError("tables overflow the address space").into());
+    }
+
+    // The tables end within the address space, so the address of every
+    // table does as well.
+    let mut data = vec![0u8; size];
+    let pdpt = base + PAGE_SIZE as u64;
+    LittleEndian::write_u64(&mut data[0..8], pdpt | PAGE_PRESENT | PAGE_WRITABLE);
+    for directory in 0..gib {
+        let address = pdpt + ((1 + directory) * PAGE_SIZE) as u64;
+        let offset = PAGE_SIZE + directory * 8;
+        LittleEndian::write_u64(
+            &mut data[offset..offset + 8],
+            address | PAGE_PRESENT | PAGE_WRITABLE,
+        );
+
+        for entry in 0..512 {
+            let page = ((directory as u64) << 30) | ((entry as u64) << 21);
+            let offset = (2 + directory) * PAGE_SIZE + entry * 8;
+            LittleEndian::write_u64(
+                &mut data[offset..offset + 8],
+                page | PAGE_PRESENT | PAGE_WRITABLE | PAGE_HUGE,
+            );
+        }
+    }
+    slab.write(base, &data)?;
+
+    Ok(base)
+}
+
+/// A single program header from an ELF file.  This only contains the
+/// information needed to load the segment.
+struct ProgramHeader {
+    kind: u32,
+    offset: u64,
+    paddr: u64,
+    filesz: u64,
+    memsz: u64,
+}
+
+/// Loads an ELF file into the slab.  Each `PT_LOAD` segment is copied
+/// to its physical address, and any memory past the end of the file
+/// data in the segment (e.g. `.bss`) is zeroed.  This returns the entry
+/// point of the ELF file, which should be used as the initial
+/// instruction pointer of the boot core.
+///
+/// Both 32-bit and 64-bit ELF files are supported, in either byte
+/// order.  This errors if the file is malformed, or if any segment does
+/// not fit within the slab.
+///
+/// # Example
+/// ```rust
+/// # use kvm::ErrorKind;
+/// # use kvm::machine::loader;
+/// # use kvm::memory::Slab;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let mut slab = Slab::from_anon(0x1000)?;
+/// // A 64-bit, little-endian header whose program headers lie at the
+/// // very end of the address space.
+/// let mut elf = [0u8; 64];
+/// elf[0..4].copy_from_slice(b"\x7fELF");
+/// elf[4] = 2;
+/// elf[5] = 1;
+/// elf[32..40].copy_from_slice(&[0xff; 8]);
+/// elf[54] = 56;
+/// elf[56] = 2;
+/// match *loader::load_elf(&mut slab, &elf).unwrap_err().kind() {
+///     ErrorKind::InvalidElfError(_) => {}
+///     _ => panic!("expected an invalid ELF error"),
+/// }
+///
+/// // The same header, cut short.
+/// match *loader::load_elf(&mut slab, &elf[..40]).unwrap_err().kind() {
+///     ErrorKind::InvalidElfError(_) => {}
+///     _ => panic!("expected an invalid ELF error"),
+/// }
+/// #     Ok(())
+/// # }
+/// ```
+pub fn load_elf(slab: &mut Slab, elf: &[u8]) -> Result<u64> {
+    if elf.len() < 16 || &elf[0..4] != ELF_MAGIC {
+        return Err(ErrorKind::InvalidElfError("missing ELF magic").into());
+    }
+
+    match elf[5] {
+        ELF_DATA_LSB => load_elf_with::<LittleEndian>(slab, elf),
+        ELF_DATA_MSB => load_elf_with::<BigEndian>(slab, elf),
+        _ => Err(ErrorKind::InvalidElfError("unknown byte order").into()),
+    }
+}
+
+fn load_elf_with<B: ByteOrder>(slab: &mut Slab, elf: &[u8]) -> Result<u64> {
+    let class = elf[4];
+    let (entry, phoff, phentsize, phnum) = match class {
+        ELF_CLASS_64 => {
+            let header = slice_elf(elf, 0, 64)?;
+            (
+                B::read_u64(&header[24..32]),
+                B::read_u64(&header[32..40]),
+                B::read_u16(&header[54..56]) as u64,
+                B::read_u16(&header[56..58]) as u64,
+            )
+        }
+        ELF_CLASS_32 => {
+            let header = slice_elf(elf, 0, 52)?;
+            (
+                B::read_u32(&header[24..28]) as u64,
+                B::read_u32(&header[28..32]) as u64,
+                B::read_u16(&header[42..44]) as u64,
+                B::read_u16(&header[44..46]) as u64,
+            )
+        }
+        _ => return Err(ErrorKind::InvalidElfError("unknown class").into()),
+    };
+
+    for index in 0..phnum {
+        let offset = index
+            .checked_mul(phentsize)
+            .and_then(|offset| offset.checked_add(phoff))
+            .ok_or_else(|| ErrorKind::InvalidElfError("program header out of bounds"))?;
+        let entry = slice_elf(elf, offset, phentsize)?;
+        let header = program_header::<B>(class, entry)?;
+        if header.kind != PT_LOAD {
+            continue;
+        }
+
+        if header.filesz > header.memsz {
+            return Err(ErrorKind::InvalidElfError("segment file size exceeds memory size").into());
+        }
+
+        let end = header.paddr.checked_add(header.memsz);
+        if end.map(|end| end > slab.len() as u64).unwrap_or(true) {
+            return Err(ErrorKind::InvalidElfError("segment does not fit in memory").into());
+        }
+
+        let data = slice_elf(elf, header.offset, header.filesz)?;
+        slab.write(header.paddr, data)?;
+        slab.zero(
+            header.paddr + header.filesz,
+            (header.memsz - header.filesz) as usize,
+        )?;
+    }
+
+    Ok(entry)
+}
+
+fn program_header<B: ByteOrder>(class: u8, entry: &[u8]) -> Result<ProgramHeader> {
+    if class == ELF_CLASS_64 {
+        if entry.len() < 48 {
+            return Err(ErrorKind::InvalidElfError("program header too small").into());
+        }
+
+        Ok(ProgramHeader {
+            kind: B::read_u32(&entry[0..4]),
+            offset: B::read_u64(&entry[8..16]),
+            paddr: B::read_u64(&entry[24..32]),
+            filesz: B::read_u64(&entry[32..40]),
+            memsz: B::read_u64(&entry[40..48]),
+        })
+    } else {
+        if entry.len() < 24 {
+            return Err(ErrorKind::InvalidElfError("program header too small").into());
+        }
+
+        Ok(ProgramHeader {
+            kind: B::read_u32(&entry[0..4]),
+            offset: B::read_u32(&entry[4..8]) as u64,
+            paddr: B::read_u32(&entry[12..16]) as u64,
+            filesz: B::read_u32(&entry[16..20]) as u64,
+            memsz: B::read_u32(&entry[20..24]) as u64,
+        })
+    }
+}
+
+/// Retrieves a subslice of the given data, returning `None` if it's out
+/// of bounds.
+fn slice(data: &[u8], offset: u64, len: u64) -> Option<&[u8]> {
+    offset
+        .checked_add(len)
+        .filter(|end| *end <= data.len() as u64)
+        .map(|end| &data[offset as usize..end as usize])
+}
+
+fn slice_elf(data: &[u8], offset: u64, len: u64) -> Result<&[u8]> {
+    slice(data, offset, len).ok_or_else(|| ErrorKind::InvalidElfError("truncated file").into())
+}
+
+fn slice_bzimage(data: &[u8], offset: u64, len: u64) -> Result<&[u8]> {
+    slice(data, offset, len)
+        .ok_or_else(|| ErrorKind::InvalidBzImageError("truncated image").into())
+}
+
+/// Loads a Linux bzImage into the slab, following the Linux x86 boot
+/// protocol.  The setup header of the kernel is copied into a zero page
+/// at [`BOOT_PARAMS_ADDRESS`], the command line is written to
+/// [`CMDLINE_ADDRESS`], and the protected-mode kernel is copied to
+/// [`KERNEL_ADDRESS`].  The real-mode setup code is not loaded, as the
+/// guest is expected to start in protected or long mode.
+///
+/// This requires boot protocol version 2.06 or later, which every
+/// kernel since 2.6.22 supports.  This errors if the image is not a
+/// bzImage, if the command line is too long for the kernel, or if the
+/// kernel does not fit within the slab.
+pub fn load_bzimage(slab: &mut Slab, kernel: &[u8], cmdline: &str) -> Result<BootParams> {
+    let header = slice_bzimage(kernel, 0, 0x264)?;
+    if &header[0x202..0x206] != SETUP_HEADER_MAGIC {
+        return Err(ErrorKind::InvalidBzImageError("missing setup header magic").into());
+    }
+
+    let version = LittleEndian::read_u16(&header[0x206..0x208]);
+    if version < MIN_BOOT_PROTOCOL {
+        return Err(ErrorKind::InvalidBzImageError("boot protocol version too old").into());
+    }
+
+    if header[0x211] & LOADED_HIGH == 0 {
+        return Err(ErrorKind::InvalidBzImageError("kernel is not a bzImage").into());
+    }
+
+    let cmdline_size = LittleEndian::read_u32(&header[0x238..0x23c]) as usize;
+    if cmdline.len() > cmdline_size {
+        return Err(ErrorKind::InvalidBzImageError("command line too long").into());
+    }
+
+    let setup_sects = match header[0x1f1] {
+        0 => 4,
+        value => value as u64,
+    };
+
+    // The setup header ends at the jump instruction at 0x200, plus the
+    // offset encoded in the jump.
+    let header_end = 0x202 + header[0x201] as usize;
+    let setup = slice_bzimage(
+        kernel,
+        SETUP_HEADER_OFFSET as u64,
+        (header_end - SETUP_HEADER_OFFSET) as u64,
+    )?;
+    let mut params = vec![0u8; BOOT_PARAMS_SIZE];
+    params[SETUP_HEADER_OFFSET..header_end].copy_from_slice(setup);
+    // type_of_loader: undefined.
+    params[0x210] = 0xff;
+    params[0x211] |= CAN_USE_HEAP;
+    LittleEndian::write_u16(&mut params[0x224..0x226], 0xde00);
+    LittleEndian::write_u32(&mut params[0x228..0x22c], CMDLINE_ADDRESS as u32);
+
+    let offset = (setup_sects + 1) * 512;
+    if offset > kernel.len() as u64 {
+        return Err(ErrorKind::InvalidBzImageError("truncated image").into());
+    }
+
+    let image = &kernel[offset as usize..];
+    slab.write(KERNEL_ADDRESS, image)?;
+    slab.write(CMDLINE_ADDRESS, cmdline.as_bytes())?;
+    slab.write(CMDLINE_ADDRESS + cmdline.len() as u64, &[0])?;
+    slab.write(BOOT_PARAMS_ADDRESS, &params)?;
+
+    Ok(BootParams {
+        address: BOOT_PARAMS_ADDRESS,
+        kernel: KERNEL_ADDRESS,
+        cmdline: CMDLINE_ADDRESS,
+    })
+}
+
+/// Fills in the zero page (`struct boot_params`) at
+/// [`BOOT_PARAMS_ADDRESS`] with the e820 map, the guest address of the
+/// command line, and the guest address and size of the initrd, if
+/// there is one.  This returns the address of the zero page, which the
+/// boot core should have in `RSI`.
+///
+/// The setup header already in the zero page is kept, so this is meant
+/// to be called after [`load_bzimage`], which copies it from the
+/// kernel.  If there is none, a minimal header is written instead, for
+/// a kernel loaded some other way (e.g. as an ELF file through
+/// [`load_elf`]).  The initrd must already be in guest memory, below
+/// 4GiB unless the kernel supports boot protocol 2.12.
+///
+/// This errors if there are more entries than [`E820_MAX_ENTRIES`], or
+/// if the zero page does not fit within the slab.
+///
+/// # Example
+/// ```rust
+/// # use kvm::machine::loader::{self, E820Entry};
+/// # use kvm::memory::Slab;
+/// # fn main() -> Result<(), Box<::std::error::Error>> {
+/// let mut slab = Slab::from_anon(0x10_0000)?;
+/// let e820 = [
+///     E820Entry::ram(0, 0x9fc00),
+///     E820Entry::reserved(0xf0000, 0x10000),
+///     E820Entry::ram(0x10_0000, 0xf0_0000),
+/// ];
+/// let address = loader::build_boot_params(&mut slab, &e820, loader::CMDLINE_ADDRESS, None)?;
+/// assert_eq!(address, loader::BOOT_PARAMS_ADDRESS);
+///
+/// let mut count = [0u8];
+/// slab.read(address + 0x1e8, &mut count)?;
+/// assert_eq!(count[0], 3);
+/// #     Ok(())
+/// # }
+/// ```
+pub fn build_boot_params(
+    slab: &mut Slab,
+    e820: &[E820Entry],
+    cmdline_addr: u64,
+    initrd: Option<(u64, u64)>,
+) -> Result<u64> {
+    if e820.len() > E820_MAX_ENTRIES {
+        return Err(ErrorKind::InvalidBootParamsError("too many e820 entries").into());
+    }
+
+    let mut params = vec![0u8; BOOT_PARAMS_SIZE];
+    slab.read(BOOT_PARAMS_ADDRESS, &mut params)?;
+    if &params[0x202..0x206] != SETUP_HEADER_MAGIC {
+        LittleEndian::write_u16(&mut params[0x1fe..0x200], BOOT_FLAG);
+        params[0x202..0x206].copy_from_slice(SETUP_HEADER_MAGIC);
+        LittleEndian::write_u16(&mut params[0x206..0x208], MIN_BOOT_PROTOCOL);
+        // type_of_loader: undefined.
+        params[0x210] = 0xff;
+        params[0x211] = LOADED_HIGH | CAN_USE_HEAP;
+        LittleEndian::write_u16(&mut params[0x224..0x226], 0xde00);
+    }
+
+    // The addresses are split, with the upper halves in the `ext_`
+    // fields outside of the setup header.
+    LittleEndian::write_u32(&mut params[0x228..0x22c], cmdline_addr as u32);
+    LittleEndian::write_u32(&mut params[0x0c8..0x0cc], (cmdline_addr >> 32) as u32);
+    let (initrd_addr, initrd_size) = initrd.unwrap_or((0, 0));
+    LittleEndian::write_u32(&mut params[0x218..0x21c], initrd_addr as u32);
+    LittleEndian::write_u32(&mut params[0x21c..0x220], initrd_size as u32);
+    LittleEndian::write_u32(&mut params[0x0c0..0x0c4], (initrd_addr >> 32) as u32);
+    LittleEndian::write_u32(&mut params[0x0c4..0x0c8], (initrd_size >> 32) as u32);
+
+    params[E820_ENTRIES_OFFSET] = e820.len() as u8;
+    for (index, entry) in e820.iter().enumerate() {
+        let offset = E820_TABLE_OFFSET + index * E820_ENTRY_SIZE;
+        let data = &mut params[offset..offset + E820_ENTRY_SIZE];
+        LittleEndian::write_u64(&mut data[0..8], entry.addr);
+        LittleEndian::write_u64(&mut data[8..16], entry.size);
+        LittleEndian::write_u32(&mut data[16..20], entry.kind as u32);
+    }
+    slab.write(BOOT_PARAMS_ADDRESS, &params)?;
+
+    Ok(BOOT_PARAMS_ADDRESS)
+}
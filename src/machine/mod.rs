@@ -1,16 +1,40 @@
-use super::core::Core;
+use super::core::{Core, IoAddress, ParkState};
 use super::error::*;
+use super::eventfd::EventFd;
+use super::memory::Slab;
+use super::system::System;
 use kvm_sys as kvm;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::File;
 use std::num::NonZeroU32;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr;
+use std::sync::{Arc, Mutex, PoisonError};
 
+#[cfg(target_arch = "aarch64")]
+mod arm;
+mod dirty;
+mod driver;
 mod ioeventfd;
 mod irqfd;
+mod lifecycle;
+pub mod loader;
+mod pit;
 mod region;
-pub use self::ioeventfd::{IoEventFd, IoEventFdFlag};
+mod routing;
+#[cfg(target_arch = "s390x")]
+mod s390;
+#[cfg(target_arch = "aarch64")]
+pub use self::arm::{ArmFeature, ArmVcpuInit};
+pub use self::dirty::DirtyPages;
+pub use self::driver::{CoreDriver, DriverEvent};
+pub use self::ioeventfd::{Endian, IoEventFd, IoEventFdFlag};
 pub use self::irqfd::{IrqFd, IrqFdFlag};
+pub use self::lifecycle::{Lifecycle, LifecycleNotifier, LifecycleWatcher};
+pub use self::pit::Pit;
 pub use self::region::*;
+pub use self::routing::{GsiRoute, GsiRouteKind};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[repr(u32)]
@@ -23,6 +47,20 @@ pub enum IrqLevel {
     Active = 1,
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[repr(u32)]
+/// The parts of the in-kernel irqchip of an x86 machine, whose state
+/// can be retrieved and set through [`Machine::irqchip`] and
+/// [`Machine::set_irqchip`].
+pub enum IrqChipId {
+    /// The master PIC, for IRQs 0-7.
+    PicMaster = kvm::KVM_IRQCHIP_PIC_MASTER,
+    /// The slave PIC, for IRQs 8-15.
+    PicSlave = kvm::KVM_IRQCHIP_PIC_SLAVE,
+    /// The IOAPIC, for IRQs 0-23.
+    Ioapic = kvm::KVM_IRQCHIP_IOAPIC,
+}
+
 bitflags! {
     /// The clock flags for setting and retrieval of the CPU clock.
     pub struct ClockFlag: u32 {
@@ -39,6 +77,25 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// The exits that can be disabled through
+    /// [`Machine::disable_exits`].  Disabling an exit lets the guest
+    /// execute the instruction natively, instead of trapping to the
+    /// host.
+    pub struct DisableExits: u64 {
+        /// `MWAIT` and `MONITOR` are executed natively.
+        const MWAIT = kvm::KVM_X86_DISABLE_EXITS_MWAIT;
+        /// `HLT` is executed natively.  A halted core then keeps the
+        /// host CPU it runs on, instead of yielding it; this is only
+        /// useful if each core has a host CPU to itself.
+        const HLT = kvm::KVM_X86_DISABLE_EXITS_HLT;
+        /// `PAUSE` is executed natively, disabling pause-loop exiting.
+        const PAUSE = kvm::KVM_X86_DISABLE_EXITS_PAUSE;
+        /// C-state transitions are executed natively.
+        const CSTATE = kvm::KVM_X86_DISABLE_EXITS_CSTATE;
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
 #[repr(i32)]
 /// Capability information.  This is used to ensure, check, or enable
@@ -56,14 +113,196 @@ pub enum Capability {
     IoEventFd = kvm::KVM_CAP_IOEVENTFD,
     IoEventFdAnyLength = kvm::KVM_CAP_IOEVENTFD_ANY_LENGTH,
     IoEventFdNoLength = kvm::KVM_CAP_IOEVENTFD_NO_LENGTH,
+    NestedState = kvm::KVM_CAP_NESTED_STATE,
+    SyncRegs = kvm::KVM_CAP_SYNC_REGS,
+    ArmVmIpaSize = kvm::KVM_CAP_ARM_VM_IPA_SIZE,
+    Vapic = kvm::KVM_CAP_VAPIC,
+    EnableCap = kvm::KVM_CAP_ENABLE_CAP,
+    EnableCapVm = kvm::KVM_CAP_ENABLE_CAP_VM,
+    SplitIrqChip = kvm::KVM_CAP_SPLIT_IRQCHIP,
+    X86DisableExits = kvm::KVM_CAP_X86_DISABLE_EXITS,
+    HypervSynic = kvm::KVM_CAP_HYPERV_SYNIC,
+    Hyperv = kvm::KVM_CAP_HYPERV,
+    HypervTime = kvm::KVM_CAP_HYPERV_TIME,
+    HypervVpIndex = kvm::KVM_CAP_HYPERV_VP_INDEX,
+    MmuShadowCacheControl = kvm::KVM_CAP_MMU_SHADOW_CACHE_CONTROL,
+    SetGuestDebug = kvm::KVM_CAP_SET_GUEST_DEBUG,
+    S390Skeys = kvm::KVM_CAP_S390_SKEYS,
+    S390VcpuResets = kvm::KVM_CAP_S390_VCPU_RESETS,
+    CoalescedPio = kvm::KVM_CAP_COALESCED_PIO,
+    CoalescedMmio = kvm::KVM_CAP_COALESCED_MMIO,
+    KvmclockCtrl = kvm::KVM_CAP_KVMCLOCK_CTRL,
+    DebugRegs = kvm::KVM_CAP_DEBUGREGS,
+    IrqRouting = kvm::KVM_CAP_IRQ_ROUTING,
+    VcpuEvents = kvm::KVM_CAP_VCPU_EVENTS,
+    TscControl = kvm::KVM_CAP_TSC_CONTROL,
+    GetTscKhz = kvm::KVM_CAP_GET_TSC_KHZ,
+    ExitOnEmulationFailure = kvm::KVM_CAP_EXIT_ON_EMULATION_FAILURE,
+    ReadOnlyMem = kvm::KVM_CAP_READONLY_MEM,
+    SetGuestDebug2 = kvm::KVM_CAP_SET_GUEST_DEBUG2,
+    Pit2 = kvm::KVM_CAP_PIT2,
+    IrqFdResample = kvm::KVM_CAP_IRQFD_RESAMPLE,
+    GetMsrFeatures = kvm::KVM_CAP_GET_MSR_FEATURES,
+    ReinjectControl = kvm::KVM_CAP_REINJECT_CONTROL,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
-#[repr(i32)]
-/// The virtual machine type to create.  For now, there is only one
-/// value, the default.
+/// Where a capability can be enabled.  Some capabilities affect the
+/// entire machine, and must be enabled on it; others only affect a
+/// single core, and must be enabled on each core.
+pub enum CapabilityScope {
+    /// The capability is enabled through [`Machine::enable_capability`].
+    Machine,
+    /// The capability is enabled through [`Core::enable_capability`].
+    Core,
+}
+
+impl Capability {
+    /// Where this capability can be enabled.  If this is `None`, then
+    /// the capability can only be queried, not enabled.
+    ///
+    /// - [`Capability::SplitIrqChip`] and [`Capability::X86DisableExits`]
+    ///   are enabled on the machine, before any cores are created.
+    /// - [`Capability::ExitOnEmulationFailure`] is enabled on the
+    ///   machine.
+    /// - [`Capability::HypervSynic`] is enabled on each core.
+    pub fn scope(self) -> Option<CapabilityScope> {
+        match self {
+            Capability::SplitIrqChip
+            | Capability::X86DisableExits
+            | Capability::ExitOnEmulationFailure => Some(CapabilityScope::Machine),
+            Capability::HypervSynic => Some(CapabilityScope::Core),
+            _ => None,
+        }
+    }
+
+    /// Every capability known to this library.
+    pub fn all() -> &'static [Capability] {
+        &[
+            Capability::NumberCores,
+            Capability::NumberMemorySlots,
+            Capability::MaxCores,
+            Capability::MaxCoreId,
+            Capability::MultiAddressSpace,
+            Capability::IrqChip,
+            Capability::SyncMmu,
+            Capability::SetTssAddress,
+            Capability::SetIdentityMapAddress,
+            Capability::IoEventFd,
+            Capability::IoEventFdAnyLength,
+            Capability::IoEventFdNoLength,
+            Capability::NestedState,
+            Capability::SyncRegs,
+            Capability::ArmVmIpaSize,
+            Capability::Vapic,
+            Capability::EnableCap,
+            Capability::EnableCapVm,
+            Capability::SplitIrqChip,
+            Capability::X86DisableExits,
+            Capability::HypervSynic,
+            Capability::Hyperv,
+            Capability::HypervTime,
+            Capability::HypervVpIndex,
+            Capability::MmuShadowCacheControl,
+            Capability::SetGuestDebug,
+            Capability::S390Skeys,
+            Capability::S390VcpuResets,
+            Capability::CoalescedPio,
+            Capability::CoalescedMmio,
+            Capability::KvmclockCtrl,
+            Capability::DebugRegs,
+            Capability::IrqRouting,
+            Capability::VcpuEvents,
+            Capability::TscControl,
+            Capability::GetTscKhz,
+            Capability::ExitOnEmulationFailure,
+            Capability::ReadOnlyMem,
+            Capability::SetGuestDebug2,
+            Capability::Pit2,
+            Capability::IrqFdResample,
+            Capability::GetMsrFeatures,
+            Capability::ReinjectControl,
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// The values of every known capability, as reported by the system or
+/// a machine.  This is mostly useful for diagnostics.  Capabilities
+/// that are unsupported are reported as zero, as are capabilities that
+/// could not be checked, so that one failing check does not keep the
+/// rest from being reported.  The capabilities are kept in the order of
+/// [`Capability::all`].
+pub struct CapabilityReport(Vec<(Capability, i32)>);
+
+impl CapabilityReport {
+    pub(crate) fn build<F>(mut check: F) -> CapabilityReport
+    where
+        F: FnMut(Capability) -> Result<i32>,
+    {
+        let values = Capability::all()
+            .iter()
+            .map(|cap| (*cap, check(*cap).unwrap_or(0)))
+            .collect();
+        CapabilityReport(values)
+    }
+
+    /// The value of the given capability.  The meaning of the value is
+    /// dependent on the capability; see [`Machine::extension`].
+    pub fn get(&self, cap: Capability) -> i32 {
+        self.0
+            .iter()
+            .find(|&&(other, _)| other == cap)
+            .map_or(0, |&(_, value)| value)
+    }
+
+    /// Whether or not the given capability is supported, i.e. whether
+    /// or not its value is non-zero.
+    pub fn supported(&self, cap: Capability) -> bool {
+        self.get(cap) != 0
+    }
+
+    /// Iterates over all of the capabilities and their values, in the
+    /// order of [`Capability::all`].
+    pub fn iter<'r>(&'r self) -> impl Iterator<Item = (Capability, i32)> + 'r {
+        self.0.iter().cloned()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The virtual machine type to create.  Most architectures only support
+/// the default; the others are architecture-specific.
 pub enum MachineKind {
-    Default = 0,
+    /// The default machine type.
+    Default,
+    /// A user-controlled s390 machine, where the address space of the
+    /// guest is managed by userspace.  Only supported on s390.
+    S390Ucontrol,
+    /// An ARM machine with the given guest physical address space size,
+    /// in bits.  This must be at least 32, and must not exceed
+    /// [`Machine::arm_max_ipa_size`]; [`System::create_machine`] rejects
+    /// any other size.  A size of zero is the same as the default, 40
+    /// bits.  Only supported on ARM.
+    ArmIpaSize(u32),
+}
+
+impl MachineKind {
+    /// The machine type, as given to the kernel when creating the
+    /// machine.  This does not check the IPA size of an
+    /// [`MachineKind::ArmIpaSize`]; only its low bits are kept.
+    pub fn raw(self) -> i32 {
+        match self {
+            MachineKind::Default => 0,
+            MachineKind::S390Ucontrol => kvm::KVM_VM_S390_UCONTROL as i32,
+            MachineKind::ArmIpaSize(bits) => (bits & kvm::KVM_VM_TYPE_ARM_IPA_SIZE_MASK) as i32,
+        }
+    }
+}
+
+impl Default for MachineKind {
+    fn default() -> MachineKind {
+        MachineKind::Default
+    }
 }
 
 #[derive(Debug)]
@@ -74,14 +313,69 @@ pub enum MachineKind {
 /// # Safety
 /// This is not thread-safe.  If you must interact with it across
 /// threads, consider using a mutex.
-pub struct Machine(pub(crate) File);
+pub struct Machine(pub(crate) File, usize, Bookkeeping);
+
+/// The mappings owned by the regions of a machine, by slot.  These are
+/// shared with the cores of the machine, as each core keeps the machine
+/// open, and the guest may keep using the mappings through it after
+/// the machine itself is dropped.
+pub(crate) type Backings = Arc<Mutex<HashMap<u32, Slab>>>;
+
+#[derive(Debug, Default)]
+/// What this library knows about the setup of the machine, so that
+/// setup done in the wrong order can be caught with a clear error
+/// instead of an opaque one from the kernel.  For machines created from
+/// a raw file descriptor, the setup done before is unknown, and so
+/// these checks are skipped.
+struct Bookkeeping {
+    adopted: bool,
+    irqchip: Cell<bool>,
+    pit: Cell<bool>,
+    phys_bits: Cell<Option<u32>>,
+    cores: Cell<bool>,
+    routes: RefCell<Vec<GsiRoute>>,
+    backings: Backings,
+    regions: RefCell<BTreeMap<u32, RegionEntry>>,
+    mmio: RefCell<Vec<(u64, u64)>>,
+    generation: Cell<u64>,
+    parking: Arc<ParkState>,
+}
 
 impl Machine {
+    /// Creates the machine, with the size of the mapping of the run
+    /// structure of its cores, as reported by the system.
+    pub(crate) unsafe fn with_core_mmap_size(fd: RawFd, size: usize) -> Machine {
+        Machine(File::from_raw_fd(fd), size, Bookkeeping::default())
+    }
+
+    /// Adopts the machine with the given file descriptor, e.g. one
+    /// passed from another process, with the size of the mapping of the
+    /// run structure of its cores, as reported by
+    /// [`System::core_mmap_size`].  As the setup done before is not
+    /// known, the ordering checks of the machine are skipped.
+    ///
+    /// # Safety
+    /// The file descriptor must be an open machine, and is owned by the
+    /// machine from then on.
+    pub unsafe fn from_raw_fd_with_size(fd: RawFd, size: usize) -> Machine {
+        let bookkeeping = Bookkeeping {
+            adopted: true,
+            ..Bookkeeping::default()
+        };
+        Machine(File::from_raw_fd(fd), size, bookkeeping)
+    }
+
     /// Returns information about a specified extension/capability.
     /// The significance of the return value is dependent on the
     /// capability being requested; however, for most, a zero value
     /// indicates an absense of that capability, and a one value
     /// indicates a presence of that capability.
+    ///
+    /// The underlying API call takes no argument beyond the capability
+    /// itself.  Capabilities that describe a range of values, such as
+    /// [`Capability::ArmVmIpaSize`], report the maximum supported
+    /// value; the value to use is then given elsewhere, e.g. as part of
+    /// the [`MachineKind`] when creating the machine.
     pub fn extension(&self, cap: Capability) -> Result<i32> {
         unsafe { kvm::kvm_check_extension(self.as_raw_fd(), cap as i32) }
             .chain_err(|| ErrorKind::MachineApiError("kvm_check_extension"))
@@ -103,6 +397,64 @@ impl Machine {
             })
     }
 
+    /// Queries every known capability of this machine.  See
+    /// [`CapabilityReport`] for more information.
+    pub fn capability_report(&self) -> CapabilityReport {
+        CapabilityReport::build(|cap| self.extension(cap))
+    }
+
+    /// Enables a capability on the machine.  The flags and arguments are
+    /// dependent on the capability being enabled.  This errors if the
+    /// capability cannot be enabled on the machine (see
+    /// [`Capability::scope`]), and requires the
+    /// [`Capability::EnableCapVm`] capability.
+    pub fn enable_capability(&self, cap: Capability, flags: u32, args: [u64; 4]) -> Result<()> {
+        if cap.scope() != Some(CapabilityScope::Machine) {
+            return Err(ErrorKind::CapabilityScopeError(cap, CapabilityScope::Machine).into());
+        }
+
+        self.assert_extension(Capability::EnableCapVm)?;
+        let enable = kvm::EnableCap {
+            cap: cap as u32,
+            flags,
+            args,
+            _pad: [0; 64],
+        };
+
+        unsafe { kvm::kvm_enable_cap(self.as_raw_fd(), &enable as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_enable_cap"))
+            .map(|_| ())
+    }
+
+    /// Disables the given exits for every core of the machine.  This can
+    /// greatly reduce latency for guests that poll, at the cost of the
+    /// host CPUs running the cores: with `HLT` exits disabled, an idle
+    /// core burns its host CPU instead of sleeping.  This must be done
+    /// before any cores are created, and requires that every given exit
+    /// be supported by the [`Capability::X86DisableExits`] capability.
+    pub fn disable_exits(&self, exits: DisableExits) -> Result<()> {
+        let supported = self.assert_extension(Capability::X86DisableExits)?;
+        if !DisableExits::from_bits_truncate(supported.get() as u64).contains(exits) {
+            return Err(ErrorKind::MissingExtensionError(Capability::X86DisableExits).into());
+        }
+
+        self.enable_capability(Capability::X86DisableExits, 0, [exits.bits(), 0, 0, 0])
+    }
+
+    /// Makes every core of the machine exit to userspace when the
+    /// kernel fails to emulate an instruction, instead of only for the
+    /// instructions the kernel knows it cannot emulate.  The exit is an
+    /// internal error with the `KVM_INTERNAL_ERROR_EMULATION` suberror,
+    /// and its data carries the bytes of the instruction, available
+    /// through [`Data::insn_bytes`]: after the flags (the first word)
+    /// comes the length of the instruction as a single byte, followed
+    /// by up to fifteen bytes of the instruction.  This requires the
+    /// [`Capability::ExitOnEmulationFailure`] capability.
+    pub fn enable_exit_on_emulation_failure(&self) -> Result<()> {
+        self.assert_extension(Capability::ExitOnEmulationFailure)?;
+        self.enable_capability(Capability::ExitOnEmulationFailure, 0, [1, 0, 0, 0])
+    }
+
     /// Determines the max number of cores available for this machine.
     /// This determines the _absolute_ maximum number of cores; the
     /// internal API has a concept of a "recommended" number of cores,
@@ -143,14 +495,216 @@ impl Machine {
         self.extension(Capability::MultiAddressSpace)
     }
 
+    /// The maximum size of the guest physical address space, in bits,
+    /// that the host supports for ARM guests.  If this is zero, then
+    /// the host only supports the default of 40 bits, and does not allow
+    /// it to be configured.
+    pub fn arm_max_ipa_size(&self) -> Result<i32> {
+        self.extension(Capability::ArmVmIpaSize)
+    }
+
+    /// The number of bits of guest physical address space that the host
+    /// supports.  Guest memory and MMIO regions must be placed below
+    /// `1 << bits`.  On x86, this is taken from the physical address
+    /// size reported in the supported CPUID leaf `0x80000008`; on ARM,
+    /// this is [`Machine::arm_max_ipa_size`].  On s390, guests always
+    /// have the full 64 bits, and the memory they can use is limited
+    /// through the attributes of the machine instead.
+    ///
+    /// The supported CPUID leaves are only reported by the system, and
+    /// so on x86, the system is opened to query them the first time
+    /// this is called; the result is kept for later calls.  If the host
+    /// does not report a size, this returns the architectural default:
+    /// 36 bits on x86, and 40 bits on ARM.  Errors from the queries are
+    /// returned; see [`Machine::guest_phys_bits_or_default`] to fall
+    /// back to the default instead.
+    pub fn guest_phys_bits(&self) -> Result<u32> {
+        if cfg!(target_arch = "s390x") {
+            return Ok(64);
+        }
+
+        if cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+            return self
+                .arm_max_ipa_size()
+                .map(|bits| if bits > 0 { bits as u32 } else { 40 });
+        }
+
+        if let Some(bits) = self.2.phys_bits.get() {
+            return Ok(bits);
+        }
+
+        let bits = System::new()?
+            .supported_cpuid()?
+            .iter()
+            .find(|entry| entry.function == 0x8000_0008)
+            .map(|entry| entry.eax & 0xff)
+            .filter(|bits| *bits > 0)
+            .unwrap_or(36);
+        self.2.phys_bits.set(Some(bits));
+        Ok(bits)
+    }
+
+    /// The number of bits of guest physical address space that the host
+    /// supports, as [`Machine::guest_phys_bits`], or the architectural
+    /// default if it cannot be queried.  The default is 36 bits on x86,
+    /// 40 bits on ARM, and 64 bits on s390.
+    pub fn guest_phys_bits_or_default(&self) -> u32 {
+        self.guest_phys_bits().unwrap_or_else(|_| {
+            if cfg!(any(target_arch = "arm", target_arch = "aarch64")) {
+                40
+            } else {
+                36
+            }
+        })
+    }
+
     /// Creates a single core on the machine with the given ID.  This
     /// core is a "vCPU" in KVM terminology.  Note that errors may arise
     /// for using the same id for multiple cores, exceeding the max
     /// core count, or exceeding the max core ID.
     pub fn create_core(&self, id: i32) -> Result<Core> {
-        unsafe { kvm::kvm_create_vcpu(self.as_raw_fd(), id) }
-            .map(|v| unsafe { Core::from_raw_fd(v) })
+        let machine = self
+            .0
+            .try_clone()
+            .chain_err(|| ErrorKind::MachineApiError("kvm_create_vcpu"))?;
+        let core = unsafe { kvm::kvm_create_vcpu(self.as_raw_fd(), id) }
             .chain_err(|| ErrorKind::MachineApiError("kvm_create_vcpu"))
+            .and_then(|v| {
+                Core::with_machine(
+                    v,
+                    machine,
+                    self.1,
+                    self.has_irqchip(),
+                    &self.2.parking,
+                    &self.2.backings,
+                )
+            })?;
+        self.2.cores.set(true);
+        Ok(core)
+    }
+
+    /// Whether or not a core has been created through this machine.
+    /// Some setup, such as [`Machine::create_irqchip`] and
+    /// [`Machine::set_identity_address`], must be done before the first
+    /// core is created; those methods error with
+    /// [`ErrorKind::CoresCreatedError`] once this is true.
+    ///
+    /// For a machine created from a raw file descriptor, cores created
+    /// before are not known, and so this only covers cores created
+    /// since; the ordering checks are skipped for such a machine.
+    pub fn cores_created(&self) -> bool {
+        self.2.cores.get()
+    }
+
+    /// Stops every core of the machine, and waits until all of them are
+    /// out of the guest.  This is meant for taking a consistent snapshot
+    /// of the machine, e.g. its memory and the state of every core.
+    /// Call [`Machine::resume_all`] to let the cores run again.
+    ///
+    /// This relies on the run loop of every core created through this
+    /// machine, each on its own thread, following the protocol:
+    ///
+    /// 1. before every run, the loop calls
+    ///    [`Core::park_if_requested`], which blocks while a pause is in
+    ///    effect;
+    /// 2. this sets `immediate_exit` for every core in the protocol, so
+    ///    that a core about to enter the guest does not, and sends
+    ///    [`KICK_SIGNAL`] to the thread of each such core that is in
+    ///    [`Core::run`] (or a similar method), so that a core in the
+    ///    guest leaves it;
+    /// 3. the interrupted run fails with `EINTR`, and the loop goes
+    ///    back to the first step, where the core parks; and
+    /// 4. once every core in the protocol is parked, this returns.
+    ///
+    /// A core joins the protocol the first time its loop calls
+    /// [`Core::park_if_requested`]; this neither stops nor waits on the
+    /// cores that have not, e.g. those that were not run yet, or that
+    /// are run without the protocol (see [`Core::is_parked`]).  A core
+    /// that has not run yet parks as soon as its loop starts.  A core
+    /// whose loop is stuck elsewhere, e.g. waiting on a device, keeps
+    /// this from returning until it reaches its next call to
+    /// [`Core::park_if_requested`].  Dropping a core, or turning it into
+    /// a raw file descriptor, stops the machine from waiting on it.
+    /// Pausing an already paused machine only waits for the cores to
+    /// park.
+    pub fn pause_all(&self) -> Result<()> {
+        self.2.parking.pause()
+    }
+
+    /// Lets the cores parked by [`Machine::pause_all`] run again.
+    pub fn resume_all(&self) {
+        self.2.parking.resume()
+    }
+
+    /// Resets the machine for a reboot of the guest, e.g. once a core
+    /// exits with [`Lifecycle::Reboot`].  Every core must be stopped
+    /// first; see [`LifecycleWatcher`].  This:
+    ///
+    /// - resets each of the given cores to its power-on state, through
+    ///   [`Core::reset`];
+    /// - zeroes each of the given slabs;
+    /// - resets the PIT, if it was created through this machine, and
+    ///   sets the clock of the machine back to zero;
+    /// - and then calls `reload`, if given, with the slabs, to load the
+    ///   firmware or kernel into them again.
+    ///
+    /// Only the slabs given are zeroed, and so only the slabs backing
+    /// the guest's RAM should be given; memory the guest expects to
+    /// keep across a reboot, e.g. a region backed by a file standing in
+    /// for a disk or flash, should not be.  The regions themselves, the
+    /// routes, the IoEventFds and IrqFds, and the state of the irqchip
+    /// are left as they are; the guest sets the irqchip up again as it
+    /// boots.  The state of each core is reset as well, so that only
+    /// the bootstrap processor runs; see [`Core::reset`].  This is only
+    /// supported on x86.
+    pub fn reset(
+        &self,
+        cores: &mut [&mut Core],
+        slabs: &mut [&mut Slab],
+        reload: Option<&mut dyn FnMut(&mut [&mut Slab]) -> Result<()>>,
+    ) -> Result<()> {
+        for core in cores.iter_mut() {
+            core.reset()?;
+        }
+
+        for slab in slabs.iter_mut() {
+            let len = slab.len();
+            slab.zero(0, len)?;
+        }
+
+        if self.2.pit.get() {
+            self.reset_pit()?;
+        }
+        self.set_clock(0, ClockFlag::empty())?;
+
+        match reload {
+            Some(reload) => reload(slabs),
+            None => Ok(()),
+        }
+    }
+
+    /// Resets the PIT to the state the kernel creates it with: each
+    /// channel counts from 65536, in no mode, and only the gates of
+    /// the first two channels are open.
+    fn reset_pit(&self) -> Result<()> {
+        let mut state: kvm::PitState2 = unsafe { ::std::mem::zeroed() };
+        for (index, channel) in state.channels.iter_mut().enumerate() {
+            channel.mode = 0xff;
+            channel.gate = (index != 2) as u8;
+        }
+        Pit { machine: self }.set_state(&state)
+    }
+
+    fn has_irqchip(&self) -> bool {
+        !self.2.adopted && self.2.irqchip.get()
+    }
+
+    fn assert_no_cores(&self, req: &'static str) -> Result<()> {
+        if !self.2.adopted && self.2.cores.get() {
+            Err(ErrorKind::CoresCreatedError(req).into())
+        } else {
+            Ok(())
+        }
     }
 
     /// Retrieves the dirty log for the given slot.  The size here is
@@ -187,14 +741,28 @@ impl Machine {
         Ok(vec)
     }
 
+    /// Retrieves the dirty log for the given slot, as an iterator over
+    /// the indices of the dirty pages.  See [`Machine::dirty_log`] and
+    /// [`DirtyPages`].
+    pub fn dirty_pages(&self, slot: u32, size: usize) -> Result<DirtyPages> {
+        self.dirty_log(slot, size).map(DirtyPages::new)
+    }
+
     /// Creates a virtual IoApic, a virtual Pic, and causes all future
     /// cores to be created with Apics.  This is likely desirable
     /// behavior, unless you wish to implement the IRQs.  This only
-    /// supports x86 platforms.
+    /// supports x86 platforms, and must be done before any cores are
+    /// created.
+    ///
+    /// The kernel installs a default GSI routing table along with the
+    /// irqchip, which is then what [`Machine::gsi_routes`] returns.
     pub fn create_irqchip(&self) -> Result<()> {
+        self.assert_no_cores("kvm_create_irqchip")?;
         self.assert_extension(Capability::IrqChip).and_then(|_| {
             unsafe { kvm::kvm_create_irqchip(self.as_raw_fd()) }
                 .chain_err(|| ErrorKind::MachineApiError("kvm_create_irqchip"))?;
+            self.2.irqchip.set(true);
+            *self.2.routes.borrow_mut() = self::routing::default_routes();
             Ok(())
         })
     }
@@ -222,6 +790,82 @@ impl Machine {
             .map(|_| irqlevel.irq)
     }
 
+    /// Retrieves the state of the given part of the in-kernel irqchip.
+    /// This requires an irqchip created through
+    /// [`Machine::create_irqchip`]; with a split irqchip, the PIC and
+    /// IOAPIC are emulated in userspace, and the kernel has no state
+    /// for them.
+    pub fn irqchip(&self, id: IrqChipId) -> Result<kvm::IrqChip> {
+        if !self.2.adopted && !self.2.irqchip.get() {
+            return Err(ErrorKind::IrqChipRequiredError.into());
+        }
+
+        let mut chip: kvm::IrqChip = unsafe { ::std::mem::zeroed() };
+        chip.chip_id = id as u32;
+        unsafe { kvm::kvm_get_irqchip(self.as_raw_fd(), &mut chip as *mut _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_get_irqchip"))?;
+        Ok(chip)
+    }
+
+    /// Sets the state of a part of the in-kernel irqchip; which part is
+    /// given by its `chip_id`.  See [`Machine::irqchip`].
+    pub fn set_irqchip(&self, chip: &kvm::IrqChip) -> Result<()> {
+        if !self.2.adopted && !self.2.irqchip.get() {
+            return Err(ErrorKind::IrqChipRequiredError.into());
+        }
+
+        unsafe { kvm::kvm_set_irqchip(self.as_raw_fd(), chip as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_set_irqchip"))?;
+        Ok(())
+    }
+
+    /// Masks or unmasks the given IRQ line, as the guest would by
+    /// writing to the interrupt controllers.  IRQs 0-15 are masked in
+    /// the PIC, in its interrupt mask register, and IRQs 0-23 in the
+    /// IOAPIC, in the mask bit of their redirection entry; a masked
+    /// line is not delivered by [`Machine::set_irq_level`].
+    ///
+    /// This changes state the guest owns, by reading and writing it
+    /// back through [`Machine::irqchip`] and [`Machine::set_irqchip`],
+    /// and so the guest may unmask the line again itself.  This
+    /// requires an irqchip created through [`Machine::create_irqchip`],
+    /// and errors with [`ErrorKind::InvalidIrqError`] for IRQs past 23.
+    pub fn set_irq_mask(&self, irq: u32, masked: bool) -> Result<()> {
+        if irq >= 24 {
+            return Err(ErrorKind::InvalidIrqError(irq).into());
+        }
+
+        if irq < 16 {
+            let id = if irq < 8 {
+                IrqChipId::PicMaster
+            } else {
+                IrqChipId::PicSlave
+            };
+            let mut chip = self.irqchip(id)?;
+            let bit = 1u8 << (irq % 8);
+            unsafe {
+                if masked {
+                    chip.chip.pic.imr |= bit;
+                } else {
+                    chip.chip.pic.imr &= !bit;
+                }
+            }
+            self.set_irqchip(&chip)?;
+        }
+
+        let mut chip = self.irqchip(IrqChipId::Ioapic)?;
+        let bit = 1u64 << 16;
+        unsafe {
+            let entry = &mut chip.chip.ioapic.redirtbl[irq as usize].bits;
+            if masked {
+                *entry |= bit;
+            } else {
+                *entry &= !bit;
+            }
+        }
+        self.set_irqchip(&chip)
+    }
+
     /// Retrieves the clock of the machine.  The flag here can specify
     /// how the clock should be retrieved.  Right now, the only flag
     /// available is the [`ClockFlag::STABLE`] flag, which denotes that
@@ -255,17 +899,302 @@ impl Machine {
             .map(|_| ())
     }
 
+    /// Advances the clock by the given number of nanoseconds.  This is
+    /// useful after restoring a snapshot, so that the guest sees the
+    /// time that passed while it was stopped.  The clock is read and
+    /// then set again.
+    ///
+    /// The flags the kernel reports when reading the clock, e.g.
+    /// [`ClockFlag::STABLE`] on a host with a stable master clock, are
+    /// not passed back when setting it: they describe the clock, rather
+    /// than how to set it, and kernels before 5.16 reject any flag when
+    /// setting the clock.
+    ///
+    /// The clock keeps running between the read and the set, and so
+    /// the time that passes between the two is lost to the guest; a
+    /// core running in between may also see the clock jump.  The cores
+    /// should be paused first.
+    pub fn advance_clock(&self, delta_ns: u64) -> Result<()> {
+        let clock = self.clock(ClockFlag::empty())?;
+        self.set_clock(clock.wrapping_add(delta_ns), ClockFlag::empty())
+    }
+
     /// Sets a memory region for the machine.  If a region is provided
     /// with the same slot as an already existing region, that region
     /// will be updated.  Regions that overlap will be prioritised based
     /// on the higher slot number.  See [`Region`] for more information.
+    ///
+    /// The region must be aligned to pages; see [`Region::validate`].
+    /// Read-only regions require the [`Capability::ReadOnlyMem`]
+    /// capability.
     pub fn set_region<'s>(&self, region: impl Into<Region<'s>>) -> Result<()> {
         let region: Region = region.into();
-        let umr: kvm::UserspaceMemoryRegion = region.into();
+        region.validate()?;
+        let (umr, backing) = region.into_raw();
+        if RegionFlags::from_bits_truncate(umr.flags).contains(RegionFlags::READ_ONLY) {
+            self.assert_extension(Capability::ReadOnlyMem)?;
+        }
 
         unsafe { kvm::kvm_set_user_memory_region(self.as_raw_fd(), &umr as *const _) }
-            .chain_err(|| ErrorKind::MachineApiError("kvm_set_user_memory_region"))
-            .map(|_| ())
+            .chain_err(|| ErrorKind::MachineApiError("kvm_set_user_memory_region"))?;
+        // The kernel no longer uses the mapping of the region previously
+        // in the slot, if it had one, so it can be dropped.
+        let mut backings = self.2.backings.lock().unwrap_or_else(PoisonError::into_inner);
+        match backing {
+            Some(slab) => backings.insert(umr.slot, slab),
+            None => backings.remove(&umr.slot),
+        };
+        let mut regions = self.2.regions.borrow_mut();
+        if umr.memory_size == 0 {
+            regions.remove(&umr.slot);
+        } else {
+            regions.insert(umr.slot, RegionEntry::from_raw(&umr));
+        }
+        self.2.generation.set(self.2.generation.get().wrapping_add(1));
+        Ok(())
+    }
+
+    /// A counter of the changes to the regions of the machine.  This is
+    /// incremented every time a region is set or removed through this
+    /// machine, and starts at zero.
+    ///
+    /// This is advisory: a host pointer from [`Machine::translate`] may
+    /// no longer point to guest memory once the regions change, and so
+    /// a cached pointer should be dropped once the generation differs
+    /// from the one it was translated in.  It does not synchronize with
+    /// running cores, which see the new regions as soon as the kernel
+    /// installs them.
+    pub fn region_generation(&self) -> u64 {
+        self.2.generation.get()
+    }
+
+    /// Translates a guest physical address into a pointer to the host
+    /// memory backing it, through the regions set on the machine.  If
+    /// several regions cover the address, the one with the highest slot
+    /// is used.  This returns `None` if no region covers the address,
+    /// or if it was reserved through [`Machine::reserve_mmio`]; the
+    /// guest's accesses to it then cause MMIO exits.
+    ///
+    /// Only regions set through this machine are known; for a machine
+    /// created from a raw file descriptor, regions set before are not.
+    pub fn translate(&self, gpa: u64) -> Option<*mut u8> {
+        self.region_at(gpa).map(|region| (region.host + (gpa - region.addr)) as *mut u8)
+    }
+
+    /// Reads guest memory at the given guest physical address, filling
+    /// the buffer.  The memory is read through the host mapping of the
+    /// region that covers the address; see [`Machine::translate`].
+    ///
+    /// This errors with [`ErrorKind::UnmappedGuestAddressError`] if no
+    /// region covers the address, and with
+    /// [`ErrorKind::GuestRegionBoundaryError`] if the read would cross
+    /// the end of that region, even if another region follows it.
+    ///
+    /// # Safety
+    /// The memory is accessed through the host address the region was
+    /// set with.  The machine only borrows the source of a region while
+    /// it is being set (see [`RegionOptions::source`]), and so the
+    /// source, e.g. a [`Slab`], must still be mapped at that address;
+    /// if it was dropped or unmapped since, this reads freed memory.
+    /// Regions created through [`RegionOptions::from_memfd`] are owned
+    /// by the machine, and are always mapped.
+    pub unsafe fn read_guest(&self, gpa: u64, buf: &mut [u8]) -> Result<()> {
+        let host = self.guest_range(gpa, buf.len())?.0;
+        ptr::copy_nonoverlapping(host, buf.as_mut_ptr(), buf.len());
+        Ok(())
+    }
+
+    /// Writes the data to guest memory at the given guest physical
+    /// address.  See [`Machine::read_guest`].  Writes to a read-only
+    /// region error with [`ErrorKind::ReadOnlyGuestAddressError`], as
+    /// its host mapping may not be writable.
+    ///
+    /// The write is not recorded in the dirty log of the region, as it
+    /// does not go through the guest; see
+    /// [`Machine::write_guest_tracked`] to record it separately.
+    ///
+    /// # Safety
+    /// As for [`Machine::read_guest`], the source of the region must
+    /// still be mapped; it must also be writable, which a read-only
+    /// [`Slab`] given to a region without [`RegionOptions::read_only`]
+    /// is not.
+    pub unsafe fn write_guest(&self, gpa: u64, data: &[u8]) -> Result<()> {
+        let (host, flags) = self.guest_range(gpa, data.len())?;
+        if flags.contains(RegionFlags::READ_ONLY) {
+            return Err(ErrorKind::ReadOnlyGuestAddressError(gpa).into());
+        }
+
+        ptr::copy_nonoverlapping(data.as_ptr(), host, data.len());
+        Ok(())
+    }
+
+    /// Writes the data to guest memory, as [`Machine::write_guest`],
+    /// and records the pages it touched in the given dirty set.  Unlike
+    /// writes by the guest, a write through the host mapping is never
+    /// recorded in the dirty log of the region; a migration that only
+    /// copies the pages in [`Machine::dirty_pages`] would then miss it.
+    ///
+    /// The pages are only recorded if the region logs dirty pages.
+    /// Each is recorded as its slot and its index in the slot; the index
+    /// is the same as [`Machine::dirty_pages`] yields for that slot, so
+    /// that the pages of a slot in the set can be merged with its dirty
+    /// log when copying pages.  The set is only added to; it is up to
+    /// the caller to clear it once the pages are copied.
+    ///
+    /// # Safety
+    /// See [`Machine::write_guest`].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::machine::{MachineKind, RegionOptions};
+    /// # use kvm::memory::Slab;
+    /// # use kvm::system::System;
+    /// # use std::collections::BTreeSet;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let system = match System::new() {
+    ///     Ok(system) => system,
+    ///     Err(_) => return Ok(()),
+    /// };
+    ///
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// let mut slab = Slab::from_anon(0x4000)?;
+    /// let mut region = RegionOptions::new(1);
+    /// region
+    ///     .addr(0x10_0000)
+    ///     .source(slab.as_mut_slice()?)
+    ///     .log_dirty_pages();
+    /// machine.set_region(region)?;
+    ///
+    /// let mut dirty = BTreeSet::new();
+    /// // The slab is still mapped, and writable.
+    /// unsafe { machine.write_guest_tracked(0x10_0ffe, &[1, 2, 3, 4], &mut dirty)? };
+    /// assert_eq!(dirty.into_iter().collect::<Vec<_>>(), vec![(1, 0), (1, 1)]);
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub unsafe fn write_guest_tracked(
+        &self,
+        gpa: u64,
+        data: &[u8],
+        dirty: &mut BTreeSet<(u32, u64)>,
+    ) -> Result<()> {
+        self.write_guest(gpa, data)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        let regions = self.2.regions.borrow();
+        let found = regions.iter().rev().find(|&(_, region)| region.contains(gpa));
+        if let Some((&slot, region)) = found {
+            if region.flags.contains(RegionFlags::LOG_DIRTY_PAGES) {
+                let first = (gpa - region.addr) / PAGE_SIZE;
+                let last = (gpa - region.addr + data.len() as u64 - 1) / PAGE_SIZE;
+                dirty.extend((first..=last).map(|page| (slot, page)));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The region with the highest slot that covers the given guest
+    /// address, unless the address is reserved for MMIO.
+    fn region_at(&self, gpa: u64) -> Option<RegionEntry> {
+        if self.is_mmio(gpa) {
+            return None;
+        }
+
+        self.2
+            .regions
+            .borrow()
+            .values()
+            .rev()
+            .find(|region| region.contains(gpa))
+            .cloned()
+    }
+
+    /// Resolves `len` bytes of guest memory at the given address to host
+    /// memory, along with the flags of the region they are in.
+    fn guest_range(&self, gpa: u64, len: usize) -> Result<(*mut u8, RegionFlags)> {
+        let region = self
+            .region_at(gpa)
+            .ok_or_else(|| Error::from(ErrorKind::UnmappedGuestAddressError(gpa)))?;
+        let offset = gpa - region.addr;
+        if len as u64 > region.size - offset {
+            return Err(ErrorKind::GuestRegionBoundaryError(gpa, len).into());
+        }
+
+        Ok(((region.host + offset) as *mut u8, region.flags))
+    }
+
+    /// Reserves a range of guest physical addresses for MMIO.  The
+    /// kernel has no notion of this: any guest access to an address
+    /// that no region covers causes an MMIO exit, and so a device's
+    /// registers need no region at all.  Setting a read-only region
+    /// there instead would serve reads from memory, without an exit.
+    ///
+    /// This only records the range, so that [`Machine::translate`] and
+    /// [`Machine::is_mmio`] know that the guest's accesses to it are
+    /// handled by a device; the exits are then dispatched through e.g.
+    /// a [`DeviceBus`](::devices::DeviceBus), with
+    /// [`IoAddress::Memory`](::core::IoAddress::Memory) addresses.
+    /// This errors with [`ErrorKind::MmioOverlapError`] if the range
+    /// overlaps a region set on the machine.
+    pub fn reserve_mmio(&self, addr: u64, len: u64) -> Result<()> {
+        let end = addr.saturating_add(len);
+        let overlaps = self
+            .2
+            .regions
+            .borrow()
+            .values()
+            .any(|region| addr < region.addr.saturating_add(region.size) && region.addr < end);
+        if overlaps {
+            return Err(ErrorKind::MmioOverlapError(addr, len).into());
+        }
+
+        self.2.mmio.borrow_mut().push((addr, len));
+        Ok(())
+    }
+
+    /// Whether or not the given guest physical address was reserved
+    /// for MMIO, through [`Machine::reserve_mmio`].
+    pub fn is_mmio(&self, gpa: u64) -> bool {
+        self.2
+            .mmio
+            .borrow()
+            .iter()
+            .any(|&(addr, len)| gpa >= addr && gpa - addr < len)
+    }
+
+    /// Removes the region in the given slot.  This is done by setting a
+    /// region with no memory in the slot.
+    pub fn remove_region(&self, slot: u32) -> Result<()> {
+        self.set_region(RegionOptions::new(slot))
+    }
+
+    /// Sets several regions at once, in slot order.  If setting any of
+    /// them fails, the regions that were already set are removed again
+    /// before the error is returned, so that the machine is not left
+    /// with only part of its memory map.
+    ///
+    /// The rollback is best-effort: if removing a region fails, the
+    /// remaining regions are still removed, but that region is left in
+    /// place.  Regions that replaced an existing region in the same
+    /// slot are removed, not restored.
+    pub fn set_regions(&self, mut regions: Vec<Region>) -> Result<()> {
+        regions.sort_by_key(|region| region.slot());
+        let mut installed = Vec::with_capacity(regions.len());
+        for region in regions {
+            let slot = region.slot();
+            if let Err(err) = self.set_region(region) {
+                for slot in installed.into_iter().rev() {
+                    let _ = self.remove_region(slot);
+                }
+                return Err(err);
+            }
+            installed.push(slot);
+        }
+
+        Ok(())
     }
 
     /// This sets a region in memory that must be at least three pages
@@ -275,8 +1204,17 @@ impl Machine {
     ///
     /// This is _required_ on Intel-based machines, due to a quirk in
     /// the implementation detail.  A good choice for this may be
-    /// `0xfffbd000`.
+    /// `0xfffbd000`.  AMD-based machines do not need it; on those, if
+    /// the [`Capability::SetTssAddress`] capability is missing, this
+    /// does nothing instead of erroring.
     pub fn set_tss_address(&self, address: u32) -> Result<()> {
+        // VMX uses the TSS to run real mode code, as it cannot run it
+        // natively on older processors; SVM runs it directly, and so has
+        // no use for the TSS.
+        if host_is_amd() && self.extension(Capability::SetTssAddress)? == 0 {
+            return Ok(());
+        }
+
         self.assert_extension(Capability::SetTssAddress)
             .and_then(|_| {
                 unsafe { kvm::kvm_set_tss_addr(self.as_raw_fd(), address) }
@@ -296,6 +1234,7 @@ impl Machine {
     /// the implementation detail.  A good choice for this may be
     /// `0xfffbc000`.
     pub fn set_identity_address(&self, address: u64) -> Result<()> {
+        self.assert_no_cores("kvm_set_identity_map_addr")?;
         self.assert_extension(Capability::SetIdentityMapAddress)
             .and_then(|_| {
                 unsafe { kvm::kvm_set_identity_map_addr(self.as_raw_fd(), &address as *const _) }
@@ -304,18 +1243,122 @@ impl Machine {
             })
     }
 
+    /// Sets the maximum number of pages the kernel may use for the
+    /// shadow (or EPT/NPT) page tables of the machine.  This can be
+    /// used to limit the memory used by each machine on hosts running
+    /// many guests.  Setting this too low forces the kernel to
+    /// constantly evict and rebuild page table entries, which can
+    /// severely hurt guest performance.  This requires the
+    /// [`Capability::MmuShadowCacheControl`] capability.
+    pub fn set_mmu_page_limit(&self, pages: u64) -> Result<()> {
+        self.assert_extension(Capability::MmuShadowCacheControl)
+            .and_then(|_| {
+                unsafe { kvm::kvm_set_nr_mmu_pages(self.as_raw_fd(), pages) }
+                    .chain_err(|| ErrorKind::MachineApiError("kvm_set_nr_mmu_pages"))
+                    .map(|_| ())
+            })
+    }
+
+    /// Retrieves the maximum number of pages the kernel may use for the
+    /// page tables of the machine.  See [`Machine::set_mmu_page_limit`]
+    /// for more information.
+    pub fn mmu_page_limit(&self) -> Result<u64> {
+        self.assert_extension(Capability::MmuShadowCacheControl)
+            .and_then(|_| {
+                unsafe { kvm::kvm_get_nr_mmu_pages(self.as_raw_fd()) }
+                    .chain_err(|| ErrorKind::MachineApiError("kvm_get_nr_mmu_pages"))
+                    .map(|v| v as u64)
+            })
+    }
+
+    /// Registers a range of ports for coalesced port IO.  Writes by the
+    /// guest to these ports do not cause an exit; instead, they are
+    /// queued in a ring buffer shared with userspace, which is processed
+    /// on the next exit; see [`Core::drain_coalesced`].  Reads still
+    /// cause an exit.  This is useful for
+    /// devices with write-heavy ports whose writes have no immediate
+    /// side effects.  This requires the [`Capability::CoalescedPio`]
+    /// capability.
+    pub fn register_coalesced_pio(&self, port: u64, size: u32) -> Result<()> {
+        self.assert_extension(Capability::CoalescedPio)
+            .and_then(|_| {
+                let zone = coalesced_pio_zone(port, size);
+                unsafe { kvm::kvm_register_coalesced_mmio(self.as_raw_fd(), &zone as *const _) }
+                    .chain_err(|| ErrorKind::MachineApiError("kvm_register_coalesced_mmio"))
+                    .map(|_| ())
+            })
+    }
+
+    /// Unregisters a range of ports previously registered through
+    /// [`Machine::register_coalesced_pio`].
+    pub fn unregister_coalesced_pio(&self, port: u64, size: u32) -> Result<()> {
+        self.assert_extension(Capability::CoalescedPio)
+            .and_then(|_| {
+                let zone = coalesced_pio_zone(port, size);
+                unsafe { kvm::kvm_unregister_coalesced_mmio(self.as_raw_fd(), &zone as *const _) }
+                    .chain_err(|| ErrorKind::MachineApiError("kvm_unregister_coalesced_mmio"))
+                    .map(|_| ())
+            })
+    }
+
     /// Creates a Programmable Interrupt Timer used by the machine.
     /// This is powered by the kernel itself.  This operation is only
-    /// valid *after* we've already created an IRQ chip.
+    /// valid *after* we've already created an IRQ chip, through
+    /// [`Machine::create_irqchip`]; otherwise, this errors with
+    /// [`ErrorKind::IrqChipRequiredError`].
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::machine::MachineKind;
+    /// # use kvm::system::System;
+    /// # use kvm::ErrorKind;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// # let system = match System::new() {
+    /// #     Ok(system) => system,
+    /// #     Err(_) => return Ok(()),
+    /// # };
+    /// let machine = system.create_machine(MachineKind::Default)?;
+    /// match machine.create_default_pit() {
+    ///     Err(ref err) => match *err.kind() {
+    ///         ErrorKind::IrqChipRequiredError => {}
+    ///         _ => panic!("unexpected error: {}", err),
+    ///     },
+    ///     Ok(_) => panic!("created a pit without an irqchip"),
+    /// }
+    ///
+    /// machine.create_irqchip()?;
+    /// machine.create_default_pit()?;
+    /// #     Ok(())
+    /// # }
+    /// ```
     pub fn create_pit(&self, flags: PitFlag) -> Result<()> {
+        if !self.2.adopted && !self.2.irqchip.get() {
+            return Err(ErrorKind::IrqChipRequiredError.into());
+        }
+
         let config = kvm::PitConfig {
             flags: flags.bits(),
             _pad: [0; 15],
         };
 
         unsafe { kvm::kvm_create_pit2(self.as_raw_fd(), &config as *const _) }
-            .chain_err(|| ErrorKind::MachineApiError("kvm_create_pit2"))
-            .map(|_| ())
+            .chain_err(|| ErrorKind::MachineApiError("kvm_create_pit2"))?;
+        self.2.pit.set(true);
+        Ok(())
+    }
+
+    /// Creates a Programmable Interrupt Timer with the given flags,
+    /// returning a handle to it.  This is the same as
+    /// [`Machine::create_pit`], but the handle keeps the operations on
+    /// the PIT together; see [`Pit`].
+    pub fn create_pit_handle<'m>(&'m self, flags: PitFlag) -> Result<Pit<'m>> {
+        self.create_pit(flags).map(|_| Pit { machine: self })
+    }
+
+    /// Creates a Programmable Interrupt Timer with no flags.  See
+    /// [`Machine::create_pit`].
+    pub fn create_default_pit(&self) -> Result<()> {
+        self.create_pit(PitFlag::empty())
     }
 
     /// This creates an IoEventFd.  An IoEventFd is an eventfd that
@@ -346,20 +1389,113 @@ impl Machine {
             })
     }
 
+    /// This creates an IoEventFd that only triggers when the guest
+    /// writes the given value.  The value is given as the guest sees
+    /// it, and is converted using the given byte order; see [`Endian`]
+    /// for which guests need which ordering.  This is otherwise the same
+    /// as [`Machine::create_ioeventfd`] with [`IoEventFdFlag::DATAMATCH`]
+    /// set.
+    pub fn create_ioeventfd_match<'m>(
+        &'m self,
+        address: u64,
+        length: u32,
+        data: u64,
+        endian: Endian,
+        flags: IoEventFdFlag,
+    ) -> Result<IoEventFd<'m>> {
+        self.create_ioeventfd(
+            address,
+            length,
+            endian.datamatch(data, length),
+            flags | IoEventFdFlag::DATAMATCH,
+        )
+    }
+
     /// This creates an IrqFd.  This allows userspace to send an Irq
     /// to the CPU without forcing the CPU to halt.  This is tied to a
     /// specific GSI line, given at creation.
     pub fn create_irqfd<'m>(&'m self, gsi: u32, flags: IrqFdFlag) -> Result<IrqFd<'m>> {
         let irqfd = IrqFd::build()?;
-        self.irqfd_mod(gsi, flags, irqfd.as_raw_fd())
+        self.irqfd_mod(gsi, flags, irqfd.as_raw_fd(), 0)
+            .map(|_| IrqFd {
+                machine: self,
+                file: irqfd,
+                gsi,
+                flags,
+                resample: None,
+            })
+    }
+
+    /// Creates an IrqFd for a level-triggered interrupt.  Notifying it
+    /// asserts the GSI line, which then stays asserted until the guest
+    /// acknowledges the interrupt; the kernel then deasserts the line,
+    /// and signals the resample eventfd of the IrqFd.  See
+    /// [`IrqFd::resample_stream`].
+    ///
+    /// This requires an in-kernel irqchip, through
+    /// [`Machine::create_irqchip`], and the
+    /// [`Capability::IrqFdResample`] capability.
+    pub fn create_resample_irqfd<'m>(&'m self, gsi: u32) -> Result<IrqFd<'m>> {
+        if !self.2.adopted && !self.2.irqchip.get() {
+            return Err(ErrorKind::IrqChipRequiredError.into());
+        }
+        self.assert_extension(Capability::IrqFdResample)?;
+
+        let irqfd = IrqFd::build()?;
+        let resample = EventFd::new()?;
+        let flags = IrqFdFlag::RESAMPLE;
+        self.irqfd_mod(gsi, flags, irqfd.as_raw_fd(), resample.as_raw_fd())
             .map(|_| IrqFd {
                 machine: self,
                 file: irqfd,
                 gsi,
                 flags,
+                resample: Some(resample),
             })
     }
 
+    /// Creates the pair of file descriptors a virtio device needs: an
+    /// IoEventFd on the queue notification register, which the guest
+    /// writes to when it has added buffers to a queue, and an IrqFd on
+    /// the given GSI, which raises the interrupt of the device once
+    /// buffers are used.  Neither goes through an exit of the core.
+    ///
+    /// The IoEventFd triggers on a write of any value, and so for
+    /// every queue of the device; the backend checks each queue once
+    /// it is notified.  The width of the write depends on the
+    /// transport:
+    ///
+    /// | Transport         | Register                                   | Width   |
+    /// |-------------------|--------------------------------------------|---------|
+    /// | virtio-mmio       | `QueueNotify`, at `0x050` of the device    | 4 bytes |
+    /// | virtio-pci legacy | `QUEUE_NOTIFY`, at `0x10` of the IO BAR    | 2 bytes |
+    /// | virtio-pci modern | the notification capability, in MMIO space | 2 bytes |
+    ///
+    /// An IoEventFd only matches writes of the width it is registered
+    /// with, unless it is registered with no width, which is only
+    /// possible for MMIO.  An [`IoAddress::Memory`] address is thus
+    /// registered with no width, so that it covers both virtio-mmio and
+    /// modern virtio-pci; this requires the
+    /// [`Capability::IoEventFdNoLength`] capability.  An
+    /// [`IoAddress::Port`] address is registered for a 2-byte write.
+    pub fn create_virtio_doorbell<'m>(
+        &'m self,
+        notify_addr: IoAddress,
+        gsi: u32,
+    ) -> Result<(IoEventFd<'m>, IrqFd<'m>)> {
+        let (address, length, flags) = match notify_addr {
+            IoAddress::Memory(address) => {
+                self.assert_extension(Capability::IoEventFdNoLength)?;
+                (address, 0, IoEventFdFlag::empty())
+            }
+            IoAddress::Port(port) => (port as u64, 2, IoEventFdFlag::PIO),
+        };
+
+        let ioeventfd = self.create_ioeventfd(address, length, 0, flags)?;
+        let irqfd = self.create_irqfd(gsi, IrqFdFlag::empty())?;
+        Ok((ioeventfd, irqfd))
+    }
+
     /// Internal call to modify already existing IoEventFds.  This is
     /// mostly used to delete an IoEventFd that already exists.
     pub(crate) fn ioeventfd_mod(
@@ -384,12 +1520,18 @@ impl Machine {
             .map(|_| ())
     }
 
-    pub(crate) fn irqfd_mod(&self, gsi: u32, flags: IrqFdFlag, fd: RawFd) -> Result<()> {
+    pub(crate) fn irqfd_mod(
+        &self,
+        gsi: u32,
+        flags: IrqFdFlag,
+        fd: RawFd,
+        resample: RawFd,
+    ) -> Result<()> {
         let irqfd = kvm::IrqFd {
             fd,
             gsi,
             flags: flags.bits(),
-            resampled: 0,
+            resampled: resample,
             _pad: [0u8; 16],
         };
 
@@ -399,6 +1541,29 @@ impl Machine {
     }
 }
 
+/// Whether or not the host processor is made by AMD, from the vendor
+/// string in CPUID leaf zero.
+#[cfg(target_arch = "x86_64")]
+fn host_is_amd() -> bool {
+    let leaf = unsafe { ::std::arch::x86_64::__cpuid(0) };
+    // The vendor string is "AuthenticAMD", split across EBX, EDX, and
+    // ECX, in that order.
+    (leaf.ebx, leaf.edx, leaf.ecx) == (0x6874_7541, 0x6974_6e65, 0x444d_4163)
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn host_is_amd() -> bool {
+    false
+}
+
+fn coalesced_pio_zone(port: u64, size: u32) -> kvm::CoalescedMmioZone {
+    kvm::CoalescedMmioZone {
+        addr: port,
+        size,
+        pio: 1,
+    }
+}
+
 impl AsRawFd for Machine {
     fn as_raw_fd(&self) -> RawFd {
         self.0.as_raw_fd()
@@ -406,14 +1571,25 @@ impl AsRawFd for Machine {
 }
 
 impl FromRawFd for Machine {
+    /// Adopts the machine with the given file descriptor.  The size of
+    /// the mapping of the run structure of its cores is not known from
+    /// the machine itself, and so this opens the system to query it;
+    /// this panics if that fails.  See [`Machine::from_raw_fd_with_size`]
+    /// to give the size instead.
     unsafe fn from_raw_fd(fd: RawFd) -> Machine {
-        Machine(File::from_raw_fd(fd))
+        let size = super::system::core_mmap_size().unwrap();
+        Machine::from_raw_fd_with_size(fd, size)
     }
 }
 
 impl IntoRawFd for Machine {
     fn into_raw_fd(self) -> RawFd {
-        self.0.into_raw_fd()
+        // The machine stays open, and so the guest may keep using the
+        // mappings owned by its regions; they are leaked, rather than
+        // unmapped from under it.
+        let Machine(file, _, bookkeeping) = self;
+        ::std::mem::forget(bookkeeping.backings);
+        file.into_raw_fd()
     }
 }
 
@@ -0,0 +1,58 @@
+use super::{Capability, Machine};
+use error::*;
+use kvm_sys as kvm;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug)]
+/// The in-kernel Programmable Interrupt Timer of a machine, as created
+/// by [`Machine::create_pit_handle`].  This groups the operations on
+/// the PIT, for saving and restoring it, and for controlling how its
+/// missed ticks are handled.
+///
+/// The kernel has no way to destroy the PIT, and so it stays with the
+/// machine once this is dropped; the handle only borrows the machine,
+/// like an [`IoEventFd`](super::IoEventFd) does.
+pub struct Pit<'m> {
+    pub(super) machine: &'m Machine,
+}
+
+impl<'m> Pit<'m> {
+    /// The machine the PIT belongs to.
+    pub fn machine(&self) -> &'m Machine {
+        self.machine
+    }
+
+    /// Retrieves the state of the PIT: the state of each of its three
+    /// channels, and its flags.  This must be saved when migrating or
+    /// snapshotting the guest.
+    pub fn state(&self) -> Result<kvm::PitState2> {
+        let mut state: kvm::PitState2 = unsafe { ::std::mem::zeroed() };
+        unsafe { kvm::kvm_get_pit2(self.machine.as_raw_fd(), &mut state as *mut _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_get_pit2"))?;
+        Ok(state)
+    }
+
+    /// Sets the state of the PIT.  See [`Pit::state`].
+    pub fn set_state(&self, state: &kvm::PitState2) -> Result<()> {
+        unsafe { kvm::kvm_set_pit2(self.machine.as_raw_fd(), state as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_set_pit2"))?;
+        Ok(())
+    }
+
+    /// Sets whether or not ticks the guest missed are reinjected.  By
+    /// default, the kernel delivers every tick, even if the guest was
+    /// not running when it was due, which keeps guests that count
+    /// ticks on time; guests that read the time from elsewhere are
+    /// better off without the catch-up.  This requires the
+    /// [`Capability::ReinjectControl`] capability.
+    pub fn set_reinject(&self, reinject: bool) -> Result<()> {
+        self.machine.assert_extension(Capability::ReinjectControl)?;
+        let control = kvm::ReinjectControl {
+            pit_reinject: reinject as u8,
+            _pad: [0; 31],
+        };
+        unsafe { kvm::kvm_reinject_control(self.machine.as_raw_fd(), &control as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_reinject_control"))?;
+        Ok(())
+    }
+}
@@ -1,4 +1,9 @@
+use error::*;
 use kvm_sys as kvm;
+use memory::Slab;
+use std::cmp::Ordering;
+use std::hash::{Hash, Hasher};
+use std::os::unix::io::RawFd;
 
 /// A builder for a region.  This is used to create a [`Region`], which
 /// is then passed to the machine to set the region information.
@@ -7,11 +12,39 @@ pub struct RegionOptions<'s> {
     flags: RegionFlags,
     source: Option<&'s mut [u8]>,
     addr: u64,
+    backing: Option<Backing>,
 }
 
+#[derive(Debug)]
+/// A mapping owned by a region, which the machine keeps for as long as
+/// the region is set.  Backings are compared by the memory they map.
+struct Backing(Slab);
+
+impl PartialEq for Backing {
+    fn eq(&self, other: &Backing) -> bool {
+        self.0.as_slice().as_ptr() == other.0.as_slice().as_ptr() && self.0.len() == other.0.len()
+    }
+}
+
+impl Eq for Backing {}
+
+impl Hash for Backing {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.as_slice().as_ptr().hash(state);
+        self.0.len().hash(state);
+    }
+}
+
+/// The size of a page, to which regions must be aligned.
+pub(super) const PAGE_SIZE: u64 = 4096;
+
 bitflags! {
-    struct RegionFlags: u32 {
+    /// The flags for a region.  See [`RegionOptions::log_dirty_pages`]
+    /// and [`RegionOptions::read_only`] for more information.
+    pub struct RegionFlags: u32 {
+        /// The kernel logs the pages the guest writes to.
         const LOG_DIRTY_PAGES = kvm::KVM_MEM_LOG_DIRTY_PAGES;
+        /// Writes from the guest are handled as MMIO exits.
         const READ_ONLY = kvm::KVM_MEM_READONLY;
     }
 }
@@ -33,9 +66,37 @@ impl<'s> RegionOptions<'s> {
             flags: RegionFlags::empty(),
             source: None,
             addr: 0,
+            backing: None,
         }
     }
 
+    /// Creates a region builder with the given slot number, mounted at
+    /// the given guest address, and backed by a shared mapping of `len`
+    /// bytes of the given file, starting at `offset`.  This is meant for
+    /// a memfd (or other shared memory file) whose descriptor is also
+    /// passed to another process, such as a vhost-user backend, so that
+    /// both can access the memory of the guest.
+    ///
+    /// The mapping is owned by the region, and once the region is set,
+    /// by the machine; it is unmapped when the slot is replaced or
+    /// removed, or once the machine and all of its cores are dropped.
+    /// It is never unmapped if the machine or one of its cores is
+    /// turned into a raw file descriptor.  The file descriptor
+    /// itself is not owned, and can be closed once this returns.
+    pub fn from_memfd(
+        slot: u32,
+        guest_addr: u64,
+        fd: RawFd,
+        offset: u64,
+        len: u64,
+    ) -> Result<RegionOptions<'static>> {
+        let slab = Slab::from_file(fd, offset, len as usize)?;
+        let mut options = RegionOptions::new(slot);
+        options.addr(guest_addr);
+        options.backing = Some(Backing(slab));
+        Ok(options)
+    }
+
     /// Sets whether or not the virtual machine should log dirty pages.
     /// This means that whenever the guest writes to a page, the kernel
     /// will log it, which can later be retrieved through the machine
@@ -60,6 +121,13 @@ impl<'s> RegionOptions<'s> {
     /// writes from the guest are not passed to the backing memory.
     /// Instead, writes are handled as an MMIO exit for the core that
     /// performed the write.
+    ///
+    /// These exits must be handled like any other MMIO write: the core
+    /// returns [`Exit::Mmio`] with `is_write` set, and the data the
+    /// guest wrote.  Reads are served from the backing memory as usual.
+    /// Setting a read-only region requires the
+    /// [`Capability::ReadOnlyMem`] capability; see
+    /// [`Machine::set_region`].
     pub fn read_only(&mut self) -> &mut Self {
         self.flags |= RegionFlags::READ_ONLY;
         self
@@ -82,8 +150,12 @@ impl<'s> RegionOptions<'s> {
     /// Please note that this slice *must* be valid for the lifetime of
     /// the machine, or when the region is destroyed, whichever comes
     /// first.
+    ///
+    /// This replaces the mapping created by [`RegionOptions::from_memfd`],
+    /// if there was one.
     pub fn source(&mut self, source: &'s mut [u8]) -> &mut Self {
         self.source = Some(source);
+        self.backing = None;
         self
     }
 
@@ -104,20 +176,185 @@ impl<'s> RegionOptions<'s> {
         self.addr = addr;
         self
     }
+
+    /// The flags currently set for the region.
+    pub fn flags(&self) -> RegionFlags {
+        self.flags
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Debug, PartialEq, Eq, Hash)]
 /// A single region in memory for the machine.  This contains a
 /// reference to the userspace memory set for the region.  It is valid
 /// for this region to be null.  However, it is not valid for this
 /// reference to be invalid, and so the data contained within this
 /// region must be valid for at least the lifetime of the region.
 /// Unfortunately, expressing such is a difficult task.
-pub struct Region<'s>(u32, RegionFlags, Option<&'s mut [u8]>, u64);
+///
+/// Regions are ordered by their priority, highest first; see
+/// [`Region::priority`].  Regions with the same priority are then
+/// ordered by address.
+///
+/// # Example
+/// ```rust
+/// # use kvm::machine::{Region, RegionOptions};
+/// let mut low = RegionOptions::new(1);
+/// low.addr(0x1000);
+/// let mut high = RegionOptions::new(2);
+/// high.addr(0x0);
+/// let mut regions: Vec<Region> = vec![low.into(), high.into()];
+/// regions.sort();
+/// assert_eq!(regions[0].priority(), 2);
+/// assert_eq!(regions[1].priority(), 1);
+/// ```
+pub struct Region<'s>(u32, RegionFlags, Option<&'s mut [u8]>, u64, Option<Backing>);
+
+impl<'s> Region<'s> {
+    /// The slot of the region.
+    pub fn slot(&self) -> u32 {
+        self.0
+    }
+
+    /// The flags for the region.
+    pub fn flags(&self) -> RegionFlags {
+        self.1
+    }
+
+    /// The priority of the region, when deciding which region owns an
+    /// address that multiple regions cover.  This is the slot of the
+    /// region; higher slots win.
+    pub fn priority(&self) -> u32 {
+        self.0
+    }
+
+    /// Checks that the region is aligned to pages, as the kernel
+    /// requires: its guest address, its size, and the address of the
+    /// memory backing it.  This errors with
+    /// [`ErrorKind::UnalignedRegionError`], naming the field that is
+    /// not aligned.  [`Machine::set_region`] checks this before setting
+    /// the region.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::machine::{Region, RegionOptions};
+    /// # use kvm::memory::Slab;
+    /// # use kvm::{Error, ErrorKind};
+    /// fn validate(addr: u64, source: &mut [u8]) -> Result<(), Error> {
+    ///     let mut options = RegionOptions::new(0);
+    ///     options.addr(addr).source(source);
+    ///     let region: Region = options.into();
+    ///     region.validate()
+    /// }
+    ///
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// let mut slab = Slab::from_anon(0x2000)?;
+    /// validate(0x1000, slab.as_mut_slice()?)?;
+    /// validate(0x0, &mut slab.as_mut_slice()?[..0x1000])?;
+    ///
+    /// let misaligned = [
+    ///     (0x1800, 0x0, 0x2000, "guest_phys_addr"),
+    ///     (0x1000, 0x0, 0x1800, "memory_size"),
+    ///     (0x1000, 0x800, 0x1800, "userspace_addr"),
+    /// ];
+    /// for &(addr, start, end, field) in &misaligned {
+    ///     match validate(addr, &mut slab.as_mut_slice()?[start..end]) {
+    ///         Err(ref err) => match *err.kind() {
+    ///             ErrorKind::UnalignedRegionError(name, _) => assert_eq!(name, field),
+    ///             _ => panic!("unexpected error: {}", err),
+    ///         },
+    ///         Ok(_) => panic!("accepted a misaligned {}", field),
+    ///     }
+    /// }
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn validate(&self) -> Result<()> {
+        let (memory_size, userspace_addr) = self.host();
+        let fields = [
+            ("guest_phys_addr", self.3),
+            ("memory_size", memory_size),
+            ("userspace_addr", userspace_addr),
+        ];
+        match fields.iter().find(|&&(_, value)| value % PAGE_SIZE != 0) {
+            Some(&(field, value)) => Err(ErrorKind::UnalignedRegionError(field, value).into()),
+            None => Ok(()),
+        }
+    }
+
+    /// The size and address of the memory backing the region.
+    fn host(&self) -> (u64, u64) {
+        match self.4 {
+            Some(ref backing) => (backing.0.len() as u64, backing.0.as_slice().as_ptr() as u64),
+            None => match self.2 {
+                Some(ref source) => (source.len() as u64, source.as_ptr() as u64),
+                None => (0, 0),
+            },
+        }
+    }
+
+    /// Converts the region into the structure the kernel takes, along
+    /// with the mapping backing it, if the region owns one.  The
+    /// mapping must be kept for as long as the region is set.
+    pub(super) fn into_raw(mut self) -> (kvm::UserspaceMemoryRegion, Option<Slab>) {
+        let (memory_size, userspace_addr) = self.host();
+        let backing = self.4.take().map(|backing| backing.0);
+        let umr = kvm::UserspaceMemoryRegion {
+            slot: self.0,
+            flags: self.1.bits(),
+            guest_phys_addr: self.3,
+            memory_size,
+            userspace_addr,
+        };
+        (umr, backing)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What the machine knows about a region that is set, so that guest
+/// physical addresses can be resolved without asking the kernel.
+pub(super) struct RegionEntry {
+    pub(super) addr: u64,
+    pub(super) size: u64,
+    pub(super) host: u64,
+    pub(super) flags: RegionFlags,
+}
+
+impl RegionEntry {
+    pub(super) fn from_raw(umr: &kvm::UserspaceMemoryRegion) -> RegionEntry {
+        RegionEntry {
+            addr: umr.guest_phys_addr,
+            size: umr.memory_size,
+            host: umr.userspace_addr,
+            flags: RegionFlags::from_bits_truncate(umr.flags),
+        }
+    }
+
+    /// Whether or not the region covers the given guest address.
+    pub(super) fn contains(&self, gpa: u64) -> bool {
+        gpa >= self.addr && gpa - self.addr < self.size
+    }
+}
+
+impl<'s> PartialOrd for Region<'s> {
+    fn partial_cmp(&self, other: &Region<'s>) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<'s> Ord for Region<'s> {
+    fn cmp(&self, other: &Region<'s>) -> Ordering {
+        other
+            .0
+            .cmp(&self.0)
+            .then(self.3.cmp(&other.3))
+            .then(self.1.cmp(&other.1))
+            .then(self.2.cmp(&other.2))
+    }
+}
 
 impl<'s> Into<Region<'s>> for RegionOptions<'s> {
     fn into(self) -> Region<'s> {
-        Region(self.slot, self.flags, self.source, self.addr)
+        Region(self.slot, self.flags, self.source, self.addr, self.backing)
     }
 }
 
@@ -128,22 +365,20 @@ impl<'s> Into<RegionOptions<'s>> for Region<'s> {
             flags: self.1,
             source: self.2,
             addr: self.3,
+            backing: self.4,
         }
     }
 }
 
 #[doc(hidden)]
+/// For a region created through [`RegionOptions::from_memfd`], the
+/// mapping is leaked by this conversion, so that the result never
+/// dangles; [`Machine::set_region`] keeps the mapping instead, and
+/// unmaps it once the region is removed.
 impl<'s> Into<kvm::UserspaceMemoryRegion> for Region<'s> {
-    fn into(mut self) -> kvm::UserspaceMemoryRegion {
-        let memory_size = { self.2.as_ref().map(|v| v.len()) }.unwrap_or(0) as u64;
-        let userspace_addr =
-            { self.2.as_mut().map(|v| v.as_mut_ptr()) }.unwrap_or(0 as *mut _) as u64;
-        kvm::UserspaceMemoryRegion {
-            slot: self.0,
-            flags: self.1.bits(),
-            guest_phys_addr: self.3,
-            memory_size,
-            userspace_addr,
-        }
+    fn into(self) -> kvm::UserspaceMemoryRegion {
+        let (umr, backing) = self.into_raw();
+        ::std::mem::forget(backing);
+        umr
     }
 }
@@ -0,0 +1,143 @@
+use super::{Capability, Machine};
+use error::*;
+use kvm_sys as kvm;
+use std::cell::Ref;
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// Where the interrupts of a GSI are delivered.
+pub enum GsiRouteKind {
+    /// The interrupt is delivered to a pin of one of the in-kernel
+    /// interrupt controllers.  On x86, chip 0 and 1 are the master and
+    /// slave PIC, and chip 2 is the IOAPIC.
+    IrqChip { chip: u32, pin: u32 },
+    /// The interrupt is delivered as a message signaled interrupt, with
+    /// the given address and data.
+    Msi { address: u64, data: u32 },
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// A single route in the GSI routing table of the machine.  See
+/// [`Machine::set_gsi_routing`].
+pub struct GsiRoute {
+    /// The GSI this route is for.  A GSI can have more than one route,
+    /// in which case the interrupt is delivered to each of them.
+    pub gsi: u32,
+    /// Where the interrupt is delivered.
+    pub kind: GsiRouteKind,
+}
+
+impl GsiRoute {
+    fn raw(&self) -> kvm::IrqRoutingEntry {
+        let mut entry: kvm::IrqRoutingEntry = unsafe { ::std::mem::zeroed() };
+        entry.gsi = self.gsi;
+        match self.kind {
+            GsiRouteKind::IrqChip { chip, pin } => {
+                entry.type_ = kvm::KVM_IRQ_ROUTING_IRQCHIP;
+                entry.u.irqchip.irqchip = chip;
+                entry.u.irqchip.pin = pin;
+            }
+            GsiRouteKind::Msi { address, data } => {
+                entry.type_ = kvm::KVM_IRQ_ROUTING_MSI;
+                entry.u.msi.address_lo = address as u32;
+                entry.u.msi.address_hi = (address >> 32) as u32;
+                entry.u.msi.data = data;
+            }
+        }
+        entry
+    }
+}
+
+/// The routing table the kernel installs when the irqchip is created
+/// on x86: GSIs 0-15 are routed to both the IOAPIC pin and the PIC pin
+/// of the same number, and GSIs 16-23 to the IOAPIC alone.
+pub(super) fn default_routes() -> Vec<GsiRoute> {
+    let mut routes = Vec::with_capacity(40);
+    for gsi in 0..24 {
+        routes.push(GsiRoute {
+            gsi,
+            kind: GsiRouteKind::IrqChip { chip: 2, pin: gsi },
+        });
+        if gsi < 16 {
+            routes.push(GsiRoute {
+                gsi,
+                kind: GsiRouteKind::IrqChip {
+                    chip: gsi / 8,
+                    pin: gsi % 8,
+                },
+            });
+        }
+    }
+    routes
+}
+
+impl Machine {
+    /// Sets the GSI routing table of the machine.  This replaces the
+    /// entire table; any GSI that is not given has no route.  Creating
+    /// an irqchip installs a default table, routing the GSIs to the PIC
+    /// and IOAPIC pins, which is lost once this is called.  This
+    /// requires the [`Capability::IrqRouting`] capability.
+    ///
+    /// The kernel provides no way to read the table back, so the table
+    /// given here is kept, and is available through
+    /// [`Machine::gsi_routes`].  The change is all-or-nothing: if the
+    /// kernel rejects the table, both the kernel's table and the kept
+    /// table are left as they were.
+    pub fn set_gsi_routing(&self, routes: Vec<GsiRoute>) -> Result<()> {
+        self.assert_extension(Capability::IrqRouting)?;
+        // The structure is variable-length, so we allocate enough words
+        // for the header and all of the entries.
+        let size = size_of::<kvm::IrqRouting>() + routes.len() * size_of::<kvm::IrqRoutingEntry>();
+        let mut buf = vec![0u64; (size + 7) / 8];
+        let routing = buf.as_mut_ptr() as *mut kvm::IrqRouting;
+        unsafe {
+            (*routing).nr = routes.len() as u32;
+            let entries = (*routing).entries.as_mut_ptr();
+            for (index, route) in routes.iter().enumerate() {
+                *entries.add(index) = route.raw();
+            }
+        }
+
+        unsafe { kvm::kvm_set_gsi_routing(self.as_raw_fd(), routing) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_set_gsi_routing"))?;
+        *self.2.routes.borrow_mut() = routes;
+        Ok(())
+    }
+
+    /// The GSI routing table last set through
+    /// [`Machine::set_gsi_routing`].  Once the irqchip is created
+    /// through [`Machine::create_irqchip`], and until a table is set,
+    /// this is the default table the kernel installs with it.  This is
+    /// otherwise empty if no table has been set, even though the kernel
+    /// may have a default one, e.g. for a machine created from a raw
+    /// file descriptor.
+    pub fn gsi_routes(&self) -> Ref<[GsiRoute]> {
+        Ref::map(self.2.routes.borrow(), |routes| &routes[..])
+    }
+
+    /// Adds a route to the GSI routing table, and sets the new table.
+    /// If the route is already in the table, the table is set again
+    /// unchanged.  See [`Machine::set_gsi_routing`].
+    ///
+    /// The table is built from [`Machine::gsi_routes`], and so, after
+    /// [`Machine::create_irqchip`], the default routes to the PIC and
+    /// IOAPIC are kept.  Without an irqchip created through this
+    /// machine, or a table set through it, the new table only has the
+    /// given route.
+    pub fn add_gsi_route(&self, route: GsiRoute) -> Result<()> {
+        let mut routes = self.gsi_routes().to_vec();
+        if !routes.contains(&route) {
+            routes.push(route);
+        }
+        self.set_gsi_routing(routes)
+    }
+
+    /// Removes every route for the given GSI from the GSI routing table,
+    /// and sets the new table.  See [`Machine::set_gsi_routing`].
+    pub fn remove_gsi_route(&self, gsi: u32) -> Result<()> {
+        let mut routes = self.gsi_routes().to_vec();
+        routes.retain(|route| route.gsi != gsi);
+        self.set_gsi_routing(routes)
+    }
+}
@@ -0,0 +1,48 @@
+use super::{Capability, Machine};
+use error::*;
+use kvm_sys as kvm;
+use std::os::unix::io::AsRawFd;
+
+impl Machine {
+    /// Retrieves the storage keys for `count` guest pages, starting at
+    /// the given guest frame number.  Each key is one byte.  This
+    /// returns `None` if the guest is not using storage keys, in which
+    /// case they do not need to be migrated.  This requires the
+    /// [`Capability::S390Skeys`] capability.
+    pub fn s390_get_skeys(&self, start_gfn: u64, count: u64) -> Result<Option<Vec<u8>>> {
+        self.assert_extension(Capability::S390Skeys)?;
+        let mut keys = vec![0u8; count as usize];
+        let skeys = skeys(start_gfn, &mut keys);
+        let result = unsafe { kvm::kvm_s390_get_skeys(self.as_raw_fd(), &skeys as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_s390_get_skeys"))?;
+
+        if result == kvm::KVM_S390_GET_SKEYS_NONE as i32 {
+            Ok(None)
+        } else {
+            Ok(Some(keys))
+        }
+    }
+
+    /// Sets the storage keys for guest pages, starting at the given
+    /// guest frame number; one key for each byte given.  This enables
+    /// storage keys for the guest, if they weren't already.  This
+    /// requires the [`Capability::S390Skeys`] capability.
+    pub fn s390_set_skeys(&self, start_gfn: u64, keys: &[u8]) -> Result<()> {
+        self.assert_extension(Capability::S390Skeys)?;
+        let mut keys = keys.to_vec();
+        let skeys = skeys(start_gfn, &mut keys);
+        unsafe { kvm::kvm_s390_set_skeys(self.as_raw_fd(), &skeys as *const _) }
+            .chain_err(|| ErrorKind::MachineApiError("kvm_s390_set_skeys"))
+            .map(|_| ())
+    }
+}
+
+fn skeys(start_gfn: u64, keys: &mut [u8]) -> kvm::S390Skeys {
+    kvm::S390Skeys {
+        start_gfn,
+        count: keys.len() as u64,
+        skeydata_addr: keys.as_mut_ptr() as u64,
+        flags: 0,
+        _pad: [0; 9],
+    }
+}
@@ -0,0 +1,188 @@
+use super::error::*;
+use nix::libc::c_void;
+use nix::sys::mman::*;
+use std::ops::Drop;
+use std::os::unix::io::RawFd;
+
+#[derive(Debug)]
+/// A slab of memory mapped into this process, to be used as the backing
+/// memory for a region of the guest.  The slab owns the mapping, and
+/// unmaps it when dropped; because of this, the slab must outlive any
+/// region that uses it as a source.
+///
+/// Offsets into the slab are relative to the start of the slab.  The
+/// loaders in [`machine::loader`] assume that the slab is mounted at
+/// guest address zero, so that offsets are guest physical addresses.
+pub struct Slab(*mut u8, usize, bool);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+/// The protection of the memory of a slab.
+pub enum Protection {
+    /// The slab can only be read from.  Any attempt to write to it from
+    /// this process fails.
+    ReadOnly,
+    /// The slab can be both read from and written to.
+    ReadWrite,
+}
+
+impl From<Protection> for ProtFlags {
+    fn from(protection: Protection) -> ProtFlags {
+        match protection {
+            Protection::ReadOnly => ProtFlags::PROT_READ,
+            Protection::ReadWrite => ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+        }
+    }
+}
+
+impl Slab {
+    /// Creates a slab backed by anonymous memory.  The memory is zeroed,
+    /// and is only allocated by the host as the guest touches it.
+    pub fn from_anon(size: usize) -> Result<Slab> {
+        unsafe {
+            mmap(
+                0 as *mut c_void,
+                size,
+                ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                MapFlags::MAP_PRIVATE | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        }.map(|point| Slab(point as *mut u8, size, true))
+        .chain_err(|| ErrorKind::MapSlabError)
+    }
+
+    /// Creates a slab backed by the given file, starting at the given
+    /// offset.  The mapping is shared, so writes by the guest are
+    /// visible to other mappings of the file.
+    pub fn from_file(fd: RawFd, offset: u64, size: usize) -> Result<Slab> {
+        Slab::from_file_prot(fd, offset, size, Protection::ReadWrite)
+    }
+
+    /// Creates a slab backed by the given file, starting at the given
+    /// offset, with the given protection.  See [`Slab::from_file`].
+    ///
+    /// A read-only mapping is useful for ROM images, as the file does
+    /// not need to be opened for writing, and the host can share the
+    /// pages between guests.  A region using a read-only slab as its
+    /// source must be marked with [`RegionOptions::read_only`]; guest
+    /// writes to it are then handled as MMIO exits.  Otherwise, the
+    /// kernel fails to run the core once the guest writes to it.
+    pub fn from_file_prot(
+        fd: RawFd,
+        offset: u64,
+        size: usize,
+        prot: impl Into<ProtFlags>,
+    ) -> Result<Slab> {
+        let prot = prot.into();
+        unsafe {
+            mmap(
+                0 as *mut c_void,
+                size,
+                prot,
+                MapFlags::MAP_SHARED,
+                fd,
+                offset as _,
+            )
+        }.map(|point| Slab(point as *mut u8, size, prot.contains(ProtFlags::PROT_WRITE)))
+        .chain_err(|| ErrorKind::MapSlabError)
+    }
+
+    /// The size of the slab, in bytes.
+    pub fn len(&self) -> usize {
+        self.1
+    }
+
+    /// Whether or not the slab is empty.
+    pub fn is_empty(&self) -> bool {
+        self.1 == 0
+    }
+
+    /// Whether or not the slab was mapped writable.
+    pub fn is_writable(&self) -> bool {
+        self.2
+    }
+
+    /// The memory of the slab.
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.0, self.1) }
+    }
+
+    /// The memory of the slab, mutably.  This is what should be passed
+    /// to [`RegionOptions::source`].  This errors with
+    /// [`ErrorKind::ReadOnlySlabError`] if the slab is not writable; use
+    /// [`Slab::as_mut_slice_unchecked`] to give a read-only slab to a
+    /// read-only region.
+    pub fn as_mut_slice(&mut self) -> Result<&mut [u8]> {
+        self.assert_writable()?;
+        Ok(unsafe { self.as_mut_slice_unchecked() })
+    }
+
+    /// The memory of the slab, mutably, whether or not the slab is
+    /// writable.  This is how a read-only slab is passed to
+    /// [`RegionOptions::source`], for a region marked with
+    /// [`RegionOptions::read_only`].
+    ///
+    /// # Safety
+    /// If the slab is not writable, the memory must not be written
+    /// through the returned slice; doing so faults.
+    pub unsafe fn as_mut_slice_unchecked(&mut self) -> &mut [u8] {
+        ::std::slice::from_raw_parts_mut(self.0, self.1)
+    }
+
+    /// Reads from the slab at the given offset, filling the buffer.
+    /// This errors if the read would go past the end of the slab.
+    pub fn read(&self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        let range = self.range(offset, buf.len())?;
+        buf.copy_from_slice(&self.as_slice()[range]);
+        Ok(())
+    }
+
+    /// Writes the data to the slab at the given offset.  This errors if
+    /// the write would go past the end of the slab, or if the slab is
+    /// not writable.
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<()> {
+        self.assert_writable()?;
+        let range = self.range(offset, data.len())?;
+        unsafe { self.as_mut_slice_unchecked() }[range].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Zeroes the given number of bytes of the slab, starting at the
+    /// given offset.  This errors if it would go past the end of the
+    /// slab, or if the slab is not writable.
+    pub fn zero(&mut self, offset: u64, len: usize) -> Result<()> {
+        self.assert_writable()?;
+        let range = self.range(offset, len)?;
+        for byte in &mut unsafe { self.as_mut_slice_unchecked() }[range] {
+            *byte = 0;
+        }
+        Ok(())
+    }
+
+    fn assert_writable(&self) -> Result<()> {
+        if self.2 {
+            Ok(())
+        } else {
+            Err(ErrorKind::ReadOnlySlabError.into())
+        }
+    }
+
+    fn range(&self, offset: u64, len: usize) -> Result<::std::ops::Range<usize>> {
+        let start = offset as usize;
+        match start.checked_add(len) {
+            Some(end) if offset <= self.1 as u64 && end <= self.1 => Ok(start..end),
+            _ => Err(ErrorKind::SlabBoundsError(offset, len).into()),
+        }
+    }
+}
+
+impl Drop for Slab {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.0 as *mut c_void, self.1) };
+    }
+}
+
+// The slab owns its mapping, and nothing ties the mapping to the thread
+// that created it; the memory is only accessed through the slab, which
+// needs a mutable reference to write to it.
+unsafe impl Send for Slab {}
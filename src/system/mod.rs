@@ -1,12 +1,14 @@
 use super::error::*;
-use super::machine::{Machine, MachineKind};
+use super::machine::{Capability, CapabilityReport, Machine, MachineKind};
 use kvm_sys as kvm;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
+use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::slice;
 
 mod msr;
 
-pub use self::msr::MsrIndex;
+pub use self::msr::{MsrIndex, WellKnownMsr};
 
 #[derive(Debug)]
 /// A KVM System.  This represents the host machine, most likely.
@@ -109,11 +111,119 @@ impl System {
     /// #     Ok(())
     /// # }
     /// # Errors
-    /// This will error if the API call fails.
+    /// This will error if the API call fails.  For the common causes,
+    /// the error is [`ErrorKind::CreateMachineError`], which names the
+    /// kind of machine and the cause:
+    ///
+    /// - `EINVAL`: the kind of machine is not supported by the host.
+    /// - `ENOMEM`: the host is out of memory.
+    /// - `EBUSY`: the host is busy, e.g. hardware virtualization is in
+    ///   use by another hypervisor.
+    /// - `EMFILE` or `ENFILE`: there are too many open files.
+    /// - `EPERM` or `EACCES`: the process is not allowed to create
+    ///   machines.
+    ///
+    /// Any other failure is a [`ErrorKind::SystemApiError`].  An
+    /// [`MachineKind::ArmIpaSize`] is checked before the machine is
+    /// created; a size that is not zero, and is below 32 bits or above
+    /// [`Machine::arm_max_ipa_size`] (which is zero if the host does not
+    /// allow the size to be configured), errors with
+    /// [`ErrorKind::CreateMachineError`] as well.
     pub fn create_machine(&self, kind: MachineKind) -> Result<Machine> {
-        unsafe { kvm::kvm_create_vm(self.as_raw_fd(), kind as i32) }
-            .map(|v| unsafe { Machine::from_raw_fd(v) })
-            .chain_err(|| ErrorKind::SystemApiError("kvm_create_vm"))
+        use nix::errno::Errno;
+
+        if let MachineKind::ArmIpaSize(bits) = kind {
+            let max = self.extension(Capability::ArmVmIpaSize)?;
+            if bits != 0 && (bits < 32 || i64::from(bits) > i64::from(max)) {
+                return Err(ErrorKind::CreateMachineError(kind, "IPA size out of range").into());
+            }
+        }
+
+        let size = self.core_mmap_size()?;
+        match unsafe { kvm::kvm_create_vm(self.as_raw_fd(), kind.raw()) } {
+            Ok(fd) => Ok(unsafe { Machine::with_core_mmap_size(fd, size) }),
+            Err(err) => {
+                let reason = match err {
+                    ::nix::Error::Sys(Errno::EINVAL) => "unsupported machine type",
+                    ::nix::Error::Sys(Errno::ENOMEM) => "out of memory",
+                    ::nix::Error::Sys(Errno::EBUSY) => "virtualization is busy",
+                    ::nix::Error::Sys(Errno::EMFILE) | ::nix::Error::Sys(Errno::ENFILE) => {
+                        "too many open files"
+                    }
+                    ::nix::Error::Sys(Errno::EPERM) | ::nix::Error::Sys(Errno::EACCES) => {
+                        "permission denied"
+                    }
+                    _ => return Err(err).chain_err(|| ErrorKind::SystemApiError("kvm_create_vm")),
+                };
+                Err(err).chain_err(|| ErrorKind::CreateMachineError(kind, reason))
+            }
+        }
+    }
+
+    /// Creates a machine with the usual x86 setup done, ready for
+    /// regions and cores.  In order, this:
+    ///
+    /// 1. creates a [`MachineKind::Default`] machine;
+    /// 2. sets the TSS address to `0xfffbd000`, through
+    ///    [`Machine::set_tss_address`];
+    /// 3. sets the identity map address to `0xfffbc000`, through
+    ///    [`Machine::set_identity_address`];
+    /// 4. creates the in-kernel irqchip, through
+    ///    [`Machine::create_irqchip`]; and
+    /// 5. creates a PIT, through [`Machine::create_default_pit`].
+    ///
+    /// The TSS and identity map together take the four pages below
+    /// `0xfffc0000`, which must then not be used by any region.  Each
+    /// step is skipped if the machine lacks the capability for it
+    /// ([`Capability::SetTssAddress`],
+    /// [`Capability::SetIdentityMapAddress`], [`Capability::IrqChip`],
+    /// and [`Capability::Pit2`]); the PIT is also skipped without an
+    /// irqchip.  As no cores exist yet, the ordering requirements of
+    /// these calls are met.
+    ///
+    /// # Example
+    /// ```rust
+    /// # use kvm::system::System;
+    /// # fn main() -> Result<(), Box<::std::error::Error>> {
+    /// # let system = match System::new() {
+    /// #     Ok(system) => system,
+    /// #     Err(_) => return Ok(()),
+    /// # };
+    /// let machine = system.create_x86_machine()?;
+    /// machine.create_core(0)?;
+    /// #     Ok(())
+    /// # }
+    /// ```
+    pub fn create_x86_machine(&self) -> Result<Machine> {
+        let machine = self.create_machine(MachineKind::Default)?;
+        if machine.extension(Capability::SetTssAddress)? > 0 {
+            machine.set_tss_address(0xfffb_d000)?;
+        }
+        if machine.extension(Capability::SetIdentityMapAddress)? > 0 {
+            machine.set_identity_address(0xfffb_c000)?;
+        }
+        if machine.extension(Capability::IrqChip)? > 0 {
+            machine.create_irqchip()?;
+            if machine.extension(Capability::Pit2)? > 0 {
+                machine.create_default_pit()?;
+            }
+        }
+        Ok(machine)
+    }
+
+    /// Returns information about a specified extension/capability of
+    /// the host.  A machine may report different values for the same
+    /// capability; prefer [`Machine::extension`] when a machine is
+    /// available.
+    pub fn extension(&self, cap: Capability) -> Result<i32> {
+        unsafe { kvm::kvm_check_extension(self.as_raw_fd(), cap as i32) }
+            .chain_err(|| ErrorKind::SystemApiError("kvm_check_extension"))
+    }
+
+    /// Queries every known capability of the host.  See
+    /// [`CapabilityReport`] for more information.
+    pub fn capability_report(&self) -> CapabilityReport {
+        CapabilityReport::build(|cap| self.extension(cap))
     }
 
     pub fn msr_index_list(&self) -> Result<Vec<MsrIndex>> {
@@ -149,6 +259,91 @@ impl System {
         Ok(self::msr::condense_list(pointer))
     }
 
+    /// Retrieves the values of the feature MSRs of the host, i.e. the
+    /// MSRs in [`System::msr_feature_index_list`].  These describe the
+    /// features the host supports for guests, such as the VMX
+    /// capabilities, and are the MSR counterpart of
+    /// [`System::supported_cpuid`]: the guest's values should be set
+    /// to a subset of them, e.g. when checking that a guest can be
+    /// migrated to this host.
+    ///
+    /// This is read from the system, not from a core, and so it
+    /// reflects what the host can offer rather than what any one guest
+    /// was given; see [`Core::msr`] for the latter.  All of the values
+    /// are read in one call.  This requires the
+    /// [`Capability::GetMsrFeatures`] capability.
+    pub fn msr_feature_values(&self) -> Result<Vec<(MsrIndex, u64)>> {
+        if self.extension(Capability::GetMsrFeatures)? == 0 {
+            return Err(ErrorKind::MissingExtensionError(Capability::GetMsrFeatures).into());
+        }
+
+        let indices = self.msr_feature_index_list()?;
+        let pointer = self::msr::alloc_msrs(&indices);
+        // The kernel returns the number of MSRs it read, stopping at the
+        // first one it fails to read.  The list is condensed even if the
+        // call failed, so that it is freed.
+        let result = unsafe { kvm::kvm_get_msrs(self.as_raw_fd(), pointer) };
+        let read = result.as_ref().map(|read| *read as usize).unwrap_or(0);
+        let values = self::msr::condense_msrs(pointer, read.min(indices.len()));
+        result.chain_err(|| ErrorKind::SystemApiError("kvm_get_msrs"))?;
+        Ok(values)
+    }
+
+    /// Determines whether or not the host allows guests to use nested
+    /// virtualization; i.e. to run their own hypervisors.  This reads
+    /// the `nested` parameter of the vendor module: `kvm_intel` for
+    /// Intel hosts (VMX), which reports `Y` or `N`, and `kvm_amd` for
+    /// AMD hosts (SVM), which reports `1` or `0`.  On both, nested
+    /// virtualization is enabled by default on recent kernels.
+    ///
+    /// If neither module is loaded, or the parameters cannot be read,
+    /// this returns `false`.  Even if this returns `true`, the guest
+    /// still needs to be given the VMX or SVM CPUID bit.
+    pub fn supports_nested(&self) -> Result<bool> {
+        const PARAMETERS: &[&str] = &[
+            "/sys/module/kvm_intel/parameters/nested",
+            "/sys/module/kvm_amd/parameters/nested",
+        ];
+
+        Ok(PARAMETERS
+            .iter()
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .any(|value| match value.trim() {
+                "Y" | "y" | "1" => true,
+                _ => false,
+            }))
+    }
+
+    /// Retrieves the CPUID leaves that the host and KVM support.  This
+    /// can be used as the basis for the CPUID table given to
+    /// [`Core::set_cpuid`].  This is only supported on x86.
+    pub fn supported_cpuid(&self) -> Result<Vec<kvm::CpuidEntry2>> {
+        // The kernel doesn't tell us how many entries there are; it
+        // returns E2BIG if there isn't enough room, so we keep growing
+        // the buffer until it fits.
+        let mut count = 64;
+        loop {
+            let size = size_of::<kvm::Cpuid2>() + count * size_of::<kvm::CpuidEntry2>();
+            let mut buf = vec![0u32; (size + 3) / 4];
+            let cpuid = buf.as_mut_ptr() as *mut kvm::Cpuid2;
+            unsafe { (*cpuid).nent = count as u32 };
+
+            match unsafe { kvm::kvm_get_supported_cpuid(self.as_raw_fd(), cpuid) } {
+                Ok(_) => unsafe {
+                    let entries = (*cpuid).entries.as_ptr();
+                    return Ok(slice::from_raw_parts(entries, (*cpuid).nent as usize).to_vec());
+                },
+                Err(::nix::Error::Sys(::nix::errno::Errno::E2BIG)) if count < 4096 => {
+                    count *= 2
+                }
+                Err(err) => {
+                    return Err(err)
+                        .chain_err(|| ErrorKind::SystemApiError("kvm_get_supported_cpuid"))
+                }
+            }
+        }
+    }
+
     /// Returns the size required for the mmap of the vCPU file
     /// descriptor, in bytes.  This is needed for processing the
     /// structure located at that address.
@@ -175,6 +370,13 @@ impl System {
     }
 }
 
+/// The size of the mapping of the run structure of a core.  This is only
+/// used when the system that created the machine isn't known, and so
+/// opens the system to query it.
+pub(crate) fn core_mmap_size() -> Result<usize> {
+    System::new().and_then(|system| system.core_mmap_size())
+}
+
 impl AsRawFd for System {
     fn as_raw_fd(&self) -> RawFd {
         self.0.as_raw_fd()
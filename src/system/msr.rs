@@ -12,6 +12,54 @@ impl MsrIndex {
     }
 }
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[repr(u32)]
+/// Commonly used x86 MSRs.  These can be converted into an
+/// [`MsrIndex`]; for any other MSR, use [`MsrIndex::new`].
+pub enum WellKnownMsr {
+    /// The time stamp counter.
+    Tsc = 0x10,
+    /// The base address of the local APIC, and whether or not it is
+    /// enabled.
+    ApicBase = 0x1b,
+    /// The code segment for `SYSENTER`.
+    SysenterCs = 0x174,
+    /// The stack pointer for `SYSENTER`.
+    SysenterEsp = 0x175,
+    /// The instruction pointer for `SYSENTER`.
+    SysenterEip = 0x176,
+    /// Miscellaneous processor features.
+    MiscEnable = 0x1a0,
+    /// The page attribute table.
+    Pat = 0x277,
+    /// The deadline for the local APIC timer in TSC-deadline mode.
+    TscDeadline = 0x6e0,
+    /// Extended features, including long mode and the NX bit.
+    Efer = 0xc000_0080,
+    /// The segments for `SYSCALL` and `SYSRET`.
+    Star = 0xc000_0081,
+    /// The instruction pointer for `SYSCALL` in long mode.
+    LStar = 0xc000_0082,
+    /// The instruction pointer for `SYSCALL` in compatibility mode.
+    Cstar = 0xc000_0083,
+    /// The flags mask for `SYSCALL`.
+    SyscallMask = 0xc000_0084,
+    /// The base address of the FS segment.
+    FsBase = 0xc000_0100,
+    /// The base address of the GS segment.
+    GsBase = 0xc000_0101,
+    /// The value swapped into the GS base by `SWAPGS`.
+    KernelGsBase = 0xc000_0102,
+    /// The auxiliary value returned by `RDTSCP`.
+    TscAux = 0xc000_0103,
+}
+
+impl From<WellKnownMsr> for MsrIndex {
+    fn from(msr: WellKnownMsr) -> MsrIndex {
+        MsrIndex(msr as u32)
+    }
+}
+
 impl Into<u32> for MsrIndex {
     fn into(self) -> u32 {
         self.0
@@ -34,3 +82,31 @@ pub(super) fn condense_list(pointer: *mut kvm::MsrList) -> Vec<MsrIndex> {
 
     result
 }
+
+pub(super) fn alloc_msrs(indices: &[MsrIndex]) -> *mut kvm::Msrs {
+    use nix::libc::calloc;
+    use std::mem::size_of;
+    let size = size_of::<kvm::Msrs>() + indices.len() * size_of::<kvm::MsrEntry>();
+    let pointer = unsafe { calloc(1, size) as *mut kvm::Msrs };
+    unsafe {
+        (*pointer).nmsrs = indices.len() as u32;
+        let entries = (*pointer).entries.as_mut_ptr();
+        for (i, index) in indices.iter().enumerate() {
+            (*entries.add(i)).index = index.0;
+        }
+    }
+    pointer
+}
+
+pub(super) fn condense_msrs(pointer: *mut kvm::Msrs, count: usize) -> Vec<(MsrIndex, u64)> {
+    let slice = unsafe { ::std::slice::from_raw_parts((*pointer).entries.as_ptr(), count) };
+    let result = slice
+        .iter()
+        .map(|entry| (MsrIndex(entry.index), entry.data))
+        .collect();
+    unsafe {
+        nix::libc::free(pointer as *mut nix::libc::c_void);
+    }
+
+    result
+}